@@ -0,0 +1,287 @@
+//! Administrative commands. They work directly on the configured database,
+//! so they can be used while the server is running.
+
+use std::error::Error;
+use std::io::BufRead;
+use chrono::TimeZone;
+use structopt::StructOpt;
+
+use crate::config::Config;
+use crate::model;
+use crate::password;
+use crate::repository::Repo;
+use crate::util;
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// Manage users
+    User(UserCommand),
+    /// Manage invites people sign up with
+    Invite(InviteCommand),
+    /// Manage retention policies of channels
+    Channel(ChannelCommand),
+    /// Maintain the database
+    Db(DbCommand)
+}
+
+#[derive(StructOpt, Debug)]
+pub enum UserCommand {
+    /// Add a user with a password read from standard input
+    Add {
+        username: String,
+        /// Shown name, its capital letters are the acronym
+        name: String,
+        /// CSS color name or hex code the name is shown in
+        #[structopt(long, default_value = "gray")]
+        color: String
+    },
+    /// List users
+    List,
+    /// Set a password read from standard input and revoke sessions of the user
+    ResetPassword {
+        username: String
+    },
+    /// Change the color the name of a user is shown in
+    SetColor {
+        username: String,
+        /// CSS color name or hex code
+        color: String
+    },
+    /// Delete a user with the messages and files they sent
+    Delete {
+        username: String,
+        /// Delete without asking for confirmation
+        #[structopt(long)]
+        yes: bool
+    },
+    /// Invalidate authorization tokens, so that users have to log in again
+    RevokeSessions {
+        /// User whose sessions are revoked
+        #[structopt(required_unless = "all")]
+        username: Option<String>,
+        /// Revoke sessions of every user
+        #[structopt(long, conflicts_with = "username")]
+        all: bool
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub enum InviteCommand {
+    /// Create an invite code
+    Create {
+        /// How many people can sign up with it
+        #[structopt(long, default_value = "1")]
+        uses: u32,
+        /// How long it can be used
+        #[structopt(long, default_value = "7")]
+        days: u32
+    },
+    /// List invites that can still be used
+    List,
+    /// Make an invite unusable
+    Revoke {
+        code: String
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub enum ChannelCommand {
+    /// List channels with how long their messages are kept
+    List,
+    /// Set how long messages of a channel are kept instead of the global policy
+    Retention {
+        name: String,
+        /// Delete messages older than that many days, 0 keeps them forever
+        #[structopt(long)]
+        days: Option<u32>,
+        /// Keep only that many latest messages, 0 keeps all of them
+        #[structopt(long)]
+        keep_last: Option<u32>,
+        /// Follow the global policy again
+        #[structopt(long, conflicts_with_all = &["days", "keep-last"])]
+        default: bool
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub enum DbCommand {
+    /// Compact the database file and the search index
+    Vacuum,
+    /// Show what the database holds
+    Stats
+}
+
+fn find_user(repo: &Repo, username: &str) -> Result<model::UserCredentials, Box<dyn Error>> {
+    return Ok(repo.select_user_credentials(username)?
+        .ok_or_else(|| format!("User {} does not exist", username))?);
+}
+
+/// Reads a password from the first line of standard input and hashes it
+fn read_password_hash(config: &Config) -> Result<String, Box<dyn Error>> {
+    eprintln!("Password:");
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    let password = line.trim_end_matches(&['\r', '\n'][..]);
+    util::check_password(password)?;
+    let hasher = password::Hasher::new(config.argon2_memory_kib, config.argon2_iterations, config.argon2_parallelism)
+        .map_err(|e| e.to_string())?;
+    return Ok(hasher.hash(password).map_err(|e| e.to_string())?);
+}
+
+/// Describes a retention policy, unset values are taken from the configuration
+fn format_retention(channel: &model::ChannelRetention, config: &Config) -> String {
+    let days = match channel.days.unwrap_or(config.retention_days) {
+        0 => { "forever".to_owned() }
+        days => { format!("{} days", days) }
+    };
+    let keep_last = match channel.keep_last.unwrap_or(config.retention_keep_last) {
+        0 => { "all messages".to_owned() }
+        count => { format!("last {} messages", count) }
+    };
+    let default = |value: Option<u32>| if value.is_none() { " (default)" } else { "" };
+    return format!("{}{}, {}{}", days, default(channel.days), keep_last, default(channel.keep_last));
+}
+
+fn format_time(timestamp: i64) -> String {
+    return chrono::offset::Local.timestamp(timestamp, 0).format("%Y-%m-%d %H:%M").to_string();
+}
+
+fn kib(bytes: i64) -> String {
+    return format!("{} KiB", (bytes + 1023) / 1024);
+}
+
+pub fn run(command: Command, config: &Config) -> Result<(), Box<dyn Error>> {
+    let repo = Repo::new(&config.database)?;
+
+    match command {
+        Command::User(UserCommand::Add { username, name, color }) => {
+            util::check_username(&username)?;
+            util::check_display_name(&name)?;
+            util::check_color(&color)?;
+            if repo.select_user_credentials(&username)?.is_some() {
+                return Err(format!("User {} already exists", username).into());
+            }
+            let password_hash = read_password_hash(config)?;
+            let id = repo.insert_user(&username, name.trim(), &color, &password_hash)?;
+            println!("Added user {} with id {}", username, id);
+        }
+        Command::User(UserCommand::List) => {
+            let users = repo.select_user_summaries()?;
+            let rows: Vec<[String; 6]> = users.into_iter()
+                .map(|u| [u.id.to_string(), u.username, u.name, u.color,
+                    (if u.registered { "yes" } else { "no" }).to_owned(), u.message_count.to_string()])
+                .collect();
+            let header = ["ID", "USERNAME", "NAME", "COLOR", "PASSWORD", "MESSAGES"].map(str::to_owned);
+            let mut widths = [0; 6];
+            for row in Some(&header).into_iter().chain(rows.iter()) {
+                for (width, cell) in widths.iter_mut().zip(row.iter()) {
+                    *width = (*width).max(cell.chars().count());
+                }
+            }
+            for row in Some(&header).into_iter().chain(rows.iter()) {
+                let cells: Vec<String> = row.iter().zip(widths.iter())
+                    .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                    .collect();
+                println!("{}", cells.join("  ").trim_end());
+            }
+        }
+        Command::User(UserCommand::ResetPassword { username }) => {
+            let user = find_user(&repo, &username)?;
+            let password_hash = read_password_hash(config)?;
+            repo.set_user_password(user.id, &password_hash, true)?;
+            println!("Changed password of {} and revoked their sessions", user.username);
+        }
+        Command::User(UserCommand::SetColor { username, color }) => {
+            let user = find_user(&repo, &username)?;
+            util::check_color(&color)?;
+            repo.update_profile(user.id, None, Some(&color))?;
+            println!("Changed color of {} to {}", user.username, color);
+        }
+        Command::User(UserCommand::Delete { username, yes }) => {
+            let user = find_user(&repo, &username)?;
+            if !yes {
+                return Err(format!("This deletes {} with all messages and files they sent. \
+                    Run again with --yes to do it.", user.username).into());
+            }
+            let stored_names = repo.delete_user(user.id)?;
+            for stored_name in stored_names.iter() {
+                if let Err(e) = std::fs::remove_file(config.files_dir.join(stored_name)) {
+                    eprintln!("Could not remove file {}: {}", stored_name, e);
+                }
+            }
+            println!("Deleted {} and {} files", user.username, stored_names.len());
+        }
+        Command::User(UserCommand::RevokeSessions { username: Some(username), all: false }) => {
+            let user = find_user(&repo, &username)?;
+            repo.revoke_sessions(user.id)?;
+            println!("Revoked sessions of {}", user.username);
+        }
+        Command::User(UserCommand::RevokeSessions { all: true, .. }) => {
+            let count = repo.revoke_all_sessions()?;
+            println!("Revoked sessions of {} users", count);
+        }
+        Command::User(UserCommand::RevokeSessions { username: None, all: false }) => {
+            return Err("Either username or --all is required".into());
+        }
+        Command::Invite(InviteCommand::Create { uses, days }) => {
+            if uses == 0 || days == 0 {
+                return Err("An invite needs at least one use and one day".into());
+            }
+            let now = chrono::Utc::now().timestamp();
+            let invite = repo.create_invite(uses, now + i64::from(days) * 24 * 60 * 60)?;
+            println!("Invite code: {}", invite.code);
+            println!("Valid for {} sign-ups until {}, at /register?invite={}",
+                invite.max_uses, format_time(invite.expires_at), invite.code);
+        }
+        Command::Invite(InviteCommand::List) => {
+            for invite in repo.select_valid_invites()? {
+                println!("{}  used {} of {}  created {}  expires {}", invite.code, invite.uses,
+                    invite.max_uses, format_time(invite.created_at), format_time(invite.expires_at));
+            }
+        }
+        Command::Invite(InviteCommand::Revoke { code }) => {
+            if !repo.revoke_invite(&code)? {
+                return Err(format!("Invite {} does not exist or has expired", code).into());
+            }
+            println!("Revoked invite {}", code);
+        }
+        Command::Channel(ChannelCommand::List) => {
+            for channel in repo.select_channel_retentions()? {
+                println!("#{}  {}", channel.name, format_retention(&channel, config));
+            }
+        }
+        Command::Channel(ChannelCommand::Retention { name, days, keep_last, default }) => {
+            let name = name.trim_start_matches('#');
+            let mut channel = repo.select_channel_retentions()?
+                .into_iter()
+                .find(|c| c.name.to_lowercase() == name.to_lowercase())
+                .ok_or_else(|| format!("Channel #{} does not exist", name))?;
+            if default {
+                channel.days = None;
+                channel.keep_last = None;
+            } else if days.is_none() && keep_last.is_none() {
+                return Err("Either --days, --keep-last or --default is required".into());
+            }
+            channel.days = days.or(channel.days);
+            channel.keep_last = keep_last.or(channel.keep_last);
+            repo.set_channel_retention(channel.id, channel.days, channel.keep_last)?;
+            println!("Messages of #{} are kept {}", channel.name, format_retention(&channel, config));
+        }
+        Command::Db(DbCommand::Vacuum) => {
+            let before = repo.select_stats()?.size_bytes;
+            repo.vacuum()?;
+            let after = repo.select_stats()?.size_bytes;
+            println!("Compacted the database from {} to {}", kib(before), kib(after));
+        }
+        Command::Db(DbCommand::Stats) => {
+            let stats = repo.select_stats()?;
+            println!("schema version  {}", stats.schema_version);
+            println!("users           {}", stats.users);
+            println!("messages        {} ({} deleted)", stats.messages, stats.deleted_messages);
+            println!("channels        {}", stats.channels);
+            println!("files           {}", stats.files);
+            println!("size            {}", kib(stats.size_bytes));
+        }
+    }
+    return Ok(());
+}
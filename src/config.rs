@@ -0,0 +1,400 @@
+//! Server configuration.
+//!
+//! Values are resolved in the following order, later ones win: built-in
+//! defaults, TOML configuration file, `LOCALPOST_*` environment variables,
+//! command line flags.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::admin;
+use crate::password;
+use crate::util;
+
+pub const DEFAULT_CONFIG_FILE: &str = "localpost.toml";
+const MIN_SECRET_LENGTH: usize = 16;
+
+#[derive(StructOpt, Debug, Default)]
+#[structopt(name = "localpost-server", about = "Simplest chatting web application")]
+pub struct Args {
+    /// Configuration file [env: LOCALPOST_CONFIG] [default: localpost.toml]
+    #[structopt(short, long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+    /// Address to listen on, e.g. 0.0.0.0:8080
+    #[structopt(short, long)]
+    pub listen: Option<SocketAddr>,
+    /// SQLite database file
+    #[structopt(long, parse(from_os_str))]
+    pub database: Option<PathBuf>,
+    /// Glob matching page templates
+    #[structopt(long)]
+    pub templates: Option<String>,
+    /// Directory served under /static
+    #[structopt(long, parse(from_os_str))]
+    pub static_dir: Option<PathBuf>,
+    /// Directory uploaded files are stored in
+    #[structopt(long, parse(from_os_str))]
+    pub files_dir: Option<PathBuf>,
+    /// Lifetime of authorization tokens in seconds
+    #[structopt(long)]
+    pub token_expiration: Option<u64>,
+    /// Run an administrative command instead of the server
+    #[structopt(subcommand)]
+    pub command: Option<admin::Command>
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: SocketAddr,
+    pub database: PathBuf,
+    pub templates: String,
+    pub static_dir: PathBuf,
+    pub files_dir: PathBuf,
+    /// Seconds
+    pub token_expiration: u64,
+    /// Bytes, whole form
+    pub max_upload_size: u64,
+    /// Bytes, single file
+    pub max_file_size: u64,
+    /// Argon2id memory cost in KiB
+    pub argon2_memory_kib: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+    /// Messages older than that many days are deleted, zero keeps them forever
+    pub retention_days: u32,
+    /// Number of latest messages kept in each channel, zero keeps all of them.
+    /// Direct messages are only limited by `retention_days`.
+    pub retention_keep_last: u32,
+    /// Seconds between checks for messages past retention
+    pub purge_interval: u64,
+    /// Key for signing authorization tokens. Generated on first start.
+    pub secret: String
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        return Self {
+            listen: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            database: PathBuf::from("messages.db"),
+            templates: "templates/*.html".to_owned(),
+            static_dir: PathBuf::from("templates/static"),
+            files_dir: PathBuf::from("files"),
+            token_expiration: 86400,
+            max_upload_size: 32 * 1024 * 1024,
+            max_file_size: 16 * 1024 * 1024,
+            argon2_memory_kib: 19 * 1024,
+            argon2_iterations: 2,
+            argon2_parallelism: 1,
+            retention_days: 0,
+            retention_keep_last: 0,
+            purge_interval: 3600,
+            secret: String::new()
+        };
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Write(PathBuf, io::Error),
+    Invalid { key: String, reason: String }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "Could not read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "Could not parse {}: {}", path.display(), e),
+            ConfigError::Write(path, e) => write!(f, "Could not write {}: {}", path.display(), e),
+            ConfigError::Invalid { key, reason } => write!(f, "Invalid value of {}: {}", key, reason)
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn invalid(key: &str, reason: impl ToString) -> ConfigError {
+    return ConfigError::Invalid { key: key.to_owned(), reason: reason.to_string() };
+}
+
+/// Parses an environment variable if it is set
+fn parse_var<T>(env: &impl Fn(&str) -> Option<String>, name: &str) -> Result<Option<T>, ConfigError>
+    where T: FromStr, T::Err: fmt::Display
+{
+    return env(name)
+        .map(|value| value.parse::<T>().map_err(|e| invalid(name, e)))
+        .transpose();
+}
+
+/// Path of the configuration file given by flag or environment variable
+pub fn file_path(args: &Args) -> PathBuf {
+    return args.config.clone()
+        .or_else(|| std::env::var_os("LOCALPOST_CONFIG").map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_FILE));
+}
+
+impl Config {
+    /// Loads configuration of the running process. Nothing is validated or written.
+    pub fn load(path: &Path, args: &Args) -> Result<Self, ConfigError> {
+        let mut config = Self::from_file(path)?;
+        config.apply_env(|name: &str| std::env::var(name).ok())?;
+        config.apply_args(args);
+        return Ok(config);
+    }
+
+    /// Reads configuration file. Missing file is the same as an empty one.
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        match fs::read_to_string(path) {
+            Ok(text) => { return toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_owned(), e)); }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => { return Ok(Self::default()); }
+            Err(e) => { return Err(ConfigError::Read(path.to_owned(), e)); }
+        }
+    }
+
+    pub fn apply_env(&mut self, env: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        if let Some(v) = parse_var(&env, "LOCALPOST_LISTEN")? { self.listen = v; }
+        if let Some(v) = parse_var(&env, "LOCALPOST_DATABASE")? { self.database = v; }
+        if let Some(v) = parse_var(&env, "LOCALPOST_TEMPLATES")? { self.templates = v; }
+        if let Some(v) = parse_var(&env, "LOCALPOST_STATIC_DIR")? { self.static_dir = v; }
+        if let Some(v) = parse_var(&env, "LOCALPOST_FILES_DIR")? { self.files_dir = v; }
+        if let Some(v) = parse_var(&env, "LOCALPOST_TOKEN_EXPIRATION")? { self.token_expiration = v; }
+        if let Some(v) = parse_var(&env, "LOCALPOST_MAX_UPLOAD_SIZE")? { self.max_upload_size = v; }
+        if let Some(v) = parse_var(&env, "LOCALPOST_MAX_FILE_SIZE")? { self.max_file_size = v; }
+        if let Some(v) = parse_var(&env, "LOCALPOST_ARGON2_MEMORY_KIB")? { self.argon2_memory_kib = v; }
+        if let Some(v) = parse_var(&env, "LOCALPOST_ARGON2_ITERATIONS")? { self.argon2_iterations = v; }
+        if let Some(v) = parse_var(&env, "LOCALPOST_ARGON2_PARALLELISM")? { self.argon2_parallelism = v; }
+        if let Some(v) = parse_var(&env, "LOCALPOST_RETENTION_DAYS")? { self.retention_days = v; }
+        if let Some(v) = parse_var(&env, "LOCALPOST_RETENTION_KEEP_LAST")? { self.retention_keep_last = v; }
+        if let Some(v) = parse_var(&env, "LOCALPOST_PURGE_INTERVAL")? { self.purge_interval = v; }
+        if let Some(v) = parse_var(&env, "LOCALPOST_SECRET")? { self.secret = v; }
+        return Ok(());
+    }
+
+    pub fn apply_args(&mut self, args: &Args) {
+        if let Some(v) = args.listen { self.listen = v; }
+        if let Some(v) = &args.database { self.database = v.clone(); }
+        if let Some(v) = &args.templates { self.templates = v.clone(); }
+        if let Some(v) = &args.static_dir { self.static_dir = v.clone(); }
+        if let Some(v) = &args.files_dir { self.files_dir = v.clone(); }
+        if let Some(v) = args.token_expiration { self.token_expiration = v; }
+    }
+
+    /// Checks settings of the server, except for the secret
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.token_expiration == 0 {
+            return Err(invalid("token_expiration", "must be greater than zero"));
+        }
+        if self.max_file_size == 0 {
+            return Err(invalid("max_file_size", "must be greater than zero"));
+        }
+        if self.max_upload_size < self.max_file_size {
+            return Err(invalid("max_upload_size", "must not be less than max_file_size"));
+        }
+        if let Err(e) = password::Hasher::new(self.argon2_memory_kib, self.argon2_iterations, self.argon2_parallelism) {
+            return Err(invalid("argon2_memory_kib/argon2_iterations/argon2_parallelism", e));
+        }
+        if self.purge_interval == 0 {
+            return Err(invalid("purge_interval", "must be greater than zero"));
+        }
+        if self.templates.is_empty() {
+            return Err(invalid("templates", "must not be empty"));
+        }
+        if !self.static_dir.is_dir() {
+            return Err(invalid("static_dir", format!("{} is not a directory", self.static_dir.display())));
+        }
+        if self.files_dir.exists() && !self.files_dir.is_dir() {
+            return Err(invalid("files_dir", format!("{} is not a directory", self.files_dir.display())));
+        }
+        return Ok(());
+    }
+
+    /// Generates a secret and saves it to the configuration file, if none is configured
+    pub fn ensure_secret(&mut self, path: &Path) -> Result<(), ConfigError> {
+        if self.secret.is_empty() {
+            let secret = util::generate_secret();
+            save_secret(path, &Self::from_file(path)?, &secret)?;
+            tide::log::info!("Generated server secret and saved it to {}", path.display());
+            self.secret = secret;
+        }
+        if self.secret.chars().count() < MIN_SECRET_LENGTH {
+            return Err(invalid("secret", format!("must be at least {} characters long", MIN_SECRET_LENGTH)));
+        }
+        return Ok(());
+    }
+}
+
+/// Appends secret to the configuration file or creates the file if it is missing
+fn save_secret(path: &Path, file_config: &Config, secret: &str) -> Result<(), ConfigError> {
+    let write_error = |e| ConfigError::Write(path.to_owned(), e);
+    if path.exists() {
+        // appending would duplicate the key
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_owned(), e))?;
+        let sets_secret = text.parse::<toml::Value>()
+            .map_err(|e| ConfigError::Parse(path.to_owned(), e))?
+            .get("secret")
+            .is_some();
+        if sets_secret {
+            return Err(invalid("secret", format!("is empty in {}. Set it or remove the line to generate one", 
+                path.display())));
+        }
+        let mut file = fs::OpenOptions::new().append(true).open(path).map_err(write_error)?;
+        writeln!(file, "\n# Generated on first start\nsecret = {:?}", secret).map_err(write_error)?;
+        warn_if_readable_by_others(path);
+    } else {
+        let config = Config { secret: secret.to_owned(), ..file_config.clone() };
+        let text = toml::to_string(&config)
+            .map_err(|e| write_error(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+        create_private(path).and_then(|mut file| file.write_all(text.as_bytes())).map_err(write_error)?;
+    }
+    return Ok(());
+}
+
+/// Creates a file only its owner can read, as it holds the secret
+fn create_private(path: &Path) -> io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    return options.open(path);
+}
+
+#[cfg(unix)]
+fn warn_if_readable_by_others(path: &Path) {
+    if let Ok(metadata) = fs::metadata(path) {
+        if metadata.permissions().mode() & 0o077 != 0 {
+            tide::log::warn!("{} holds the server secret but can be read by other users. \
+                Consider restricting it with chmod 600.", path.display());
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_if_readable_by_others(_path: &Path) {}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn valid() -> Config {
+        return Config { secret: "0123456789abcdef".to_owned(), static_dir: PathBuf::from("."), ..Config::default() };
+    }
+
+    #[test]
+    fn parses_toml() {
+        let config: Config = toml::from_str("
+            listen = \"127.0.0.1:9000\"
+            database = \"/var/lib/localpost/messages.db\"
+            token_expiration = 3600
+        ").unwrap();
+        assert_eq!(config.listen, "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(config.database, PathBuf::from("/var/lib/localpost/messages.db"));
+        assert_eq!(config.token_expiration, 3600);
+        assert_eq!(config.templates, "templates/*.html");
+
+        assert!(toml::from_str::<Config>("listen = \"nowhere\"").is_err());
+        assert!(toml::from_str::<Config>("lisetn = \"0.0.0.0:80\"").is_err());
+    }
+
+    #[test]
+    fn env_and_args_override_file() {
+        let vars: HashMap<&str, &str> = [
+            ("LOCALPOST_LISTEN", "127.0.0.1:1"),
+            ("LOCALPOST_DATABASE", "env.db"),
+            ("LOCALPOST_SECRET", "from-environment-variable"),
+            ("LOCALPOST_RETENTION_DAYS", "90")
+        ].iter().cloned().collect();
+
+        let mut config = Config::default();
+        config.apply_env(|name| vars.get(name).map(|v| v.to_string())).unwrap();
+        config.apply_args(&Args { database: Some(PathBuf::from("args.db")), ..Args::default() });
+
+        assert_eq!(config.listen, "127.0.0.1:1".parse().unwrap());
+        assert_eq!(config.database, PathBuf::from("args.db"));
+        assert_eq!(config.secret, "from-environment-variable");
+        assert_eq!(config.retention_days, 90);
+    }
+
+    #[test]
+    fn rejects_invalid_env() {
+        let mut config = Config::default();
+        let result = config.apply_env(|name| {
+            if name == "LOCALPOST_TOKEN_EXPIRATION" { Some("one day".to_owned()) } else { None }
+        });
+        match result {
+            Err(ConfigError::Invalid { key, .. }) => assert_eq!(key, "LOCALPOST_TOKEN_EXPIRATION"),
+            other => panic!("Unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn validates_values() {
+        assert!(valid().validate().is_ok());
+        assert!(Config { secret: String::new(), ..valid() }.validate().is_ok());
+        assert!(Config { token_expiration: 0, ..valid() }.validate().is_err());
+        assert!(Config { max_upload_size: 1, max_file_size: 2, ..valid() }.validate().is_err());
+        assert!(Config { argon2_iterations: 0, ..valid() }.validate().is_err());
+        assert!(Config { purge_interval: 0, ..valid() }.validate().is_err());
+        assert!(Config { static_dir: PathBuf::from("does/not/exist"), ..valid() }.validate().is_err());
+    }
+
+    #[test]
+    fn saves_generated_secret() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let path = dir.path().join("new.toml");
+        save_secret(&path, &Config::default(), "generated-secret-value").unwrap();
+        let config = Config::from_file(&path).unwrap();
+        assert_eq!(config.secret, "generated-secret-value");
+        assert_eq!(config.listen, Config::default().listen);
+        #[cfg(unix)]
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let path = dir.path().join("existing.toml");
+        fs::write(&path, "# my settings\ntoken_expiration = 60\n").unwrap();
+        save_secret(&path, &Config::default(), "generated-secret-value").unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("# my settings\n"));
+        let config = Config::from_file(&path).unwrap();
+        assert_eq!(config.token_expiration, 60);
+        assert_eq!(config.secret, "generated-secret-value");
+    }
+
+    #[test]
+    fn generates_secret_only_if_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("localpost.toml");
+
+        let mut config = Config::default();
+        config.ensure_secret(&path).unwrap();
+        assert!(config.secret.len() >= MIN_SECRET_LENGTH);
+        let mut reloaded = Config::from_file(&path).unwrap();
+        assert_eq!(reloaded.secret, config.secret);
+        reloaded.ensure_secret(&path).unwrap();
+        assert_eq!(Config::from_file(&path).unwrap().secret, config.secret);
+
+        assert!(Config { secret: "short".to_owned(), ..Config::default() }.ensure_secret(&path).is_err());
+    }
+
+    #[test]
+    fn does_not_duplicate_empty_secret() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("localpost.toml");
+        fs::write(&path, "secret = \"\"\n").unwrap();
+
+        let mut config = Config::from_file(&path).unwrap();
+        assert!(matches!(config.ensure_secret(&path), Err(ConfigError::Invalid { .. })));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "secret = \"\"\n");
+    }
+}
@@ -0,0 +1,217 @@
+//! Delivery of live events to connected clients.
+//!
+//! Every connection subscribes with the id of its user and gets a bounded
+//! queue. Publishing never waits: a subscriber whose queue is full is too slow
+//! to keep up and gets disconnected, it has to reconnect and catch up from the
+//! database. Subscriptions are removed as soon as they are dropped.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use async_std::channel::{self, Receiver, Sender, TrySendError};
+
+pub struct Hub<T> {
+    capacity: usize,
+    subscribers: Mutex<Subscribers<T>>
+}
+
+struct Subscribers<T> {
+    next_id: u64,
+    by_user: HashMap<u32, Vec<(u64, Sender<T>)>>
+}
+
+/// Receiving end of a connection. Unsubscribes when dropped.
+pub struct Subscription<T> {
+    hub: Arc<Hub<T>>,
+    user_id: u32,
+    id: u64,
+    receiver: Receiver<T>
+}
+
+impl<T> Hub<T> {
+    /// `capacity` is the number of events queued for a connection before it counts as slow
+    pub fn new(capacity: usize) -> Self {
+        return Self { capacity, subscribers: Mutex::new(Subscribers { next_id: 0, by_user: HashMap::new() }) };
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Subscribers<T>> {
+        // nothing can panic while the lock is held, the data is consistent anyway
+        return self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+    }
+
+    pub fn subscribe(self: &Arc<Self>, user_id: u32) -> Subscription<T> {
+        let (sender, receiver) = channel::bounded(self.capacity);
+        let mut subscribers = self.lock();
+        let id = subscribers.next_id;
+        subscribers.next_id += 1;
+        subscribers.by_user.entry(user_id).or_default().push((id, sender));
+        tide::log::debug!("Subscribed connection {} of user {}, {} connections in total",
+            id, user_id, count(&subscribers));
+        return Subscription { hub: self.clone(), user_id, id, receiver };
+    }
+
+    /// Closes every connection of the user. Queued events are still delivered.
+    pub fn disconnect_user(&self, user_id: u32) {
+        if let Some(connections) = self.lock().by_user.remove(&user_id) {
+            for (_, sender) in connections {
+                sender.close();
+            }
+        }
+    }
+
+    fn unsubscribe(&self, user_id: u32, id: u64) {
+        let mut subscribers = self.lock();
+        if let Some(connections) = subscribers.by_user.get_mut(&user_id) {
+            connections.retain(|(other, _)| *other != id);
+            if connections.is_empty() {
+                subscribers.by_user.remove(&user_id);
+            }
+        }
+        tide::log::debug!("Unsubscribed connection {} of user {}, {} connections in total",
+            id, user_id, count(&subscribers));
+    }
+
+    /// Number of open connections
+    pub fn subscriber_count(&self) -> usize {
+        return count(&self.lock());
+    }
+
+    /// Number of open connections of the user
+    pub fn user_subscriber_count(&self, user_id: u32) -> usize {
+        return self.lock().by_user.get(&user_id).map(Vec::len).unwrap_or(0);
+    }
+}
+
+impl<T: Clone> Hub<T> {
+    /// Queues the event for every connection of the given users. Each user gets it once.
+    pub fn publish(&self, user_ids: impl IntoIterator<Item = u32>, event: &T) {
+        let mut user_ids: Vec<u32> = user_ids.into_iter().collect();
+        user_ids.sort_unstable();
+        user_ids.dedup();
+        deliver(&mut self.lock(), user_ids, event);
+    }
+
+    /// Queues the event for every open connection
+    pub fn publish_all(&self, event: &T) {
+        let mut subscribers = self.lock();
+        let user_ids: Vec<u32> = subscribers.by_user.keys().copied().collect();
+        deliver(&mut subscribers, user_ids, event);
+    }
+}
+
+fn deliver<T: Clone>(subscribers: &mut Subscribers<T>, user_ids: Vec<u32>, event: &T) {
+    for user_id in user_ids {
+        let connections = match subscribers.by_user.get_mut(&user_id) {
+            Some(connections) => { connections }
+            None => { continue; }
+        };
+        connections.retain(|(id, sender)| {
+            match sender.try_send(event.clone()) {
+                Ok(()) => { return true; }
+                Err(TrySendError::Full(_)) => {
+                    tide::log::warn!("Disconnecting connection {} of user {}: too many undelivered events",
+                        id, user_id);
+                    sender.close();
+                    return false;
+                }
+                Err(TrySendError::Closed(_)) => { return false; }
+            }
+        });
+        if connections.is_empty() {
+            subscribers.by_user.remove(&user_id);
+        }
+    }
+}
+
+fn count<T>(subscribers: &Subscribers<T>) -> usize {
+    return subscribers.by_user.values().map(Vec::len).sum();
+}
+
+impl<T> Subscription<T> {
+    /// Waits for the next event. `None` means the connection was closed by the hub.
+    pub async fn recv(&self) -> Option<T> {
+        return self.receiver.recv().await.ok();
+    }
+}
+
+impl<T> Drop for Subscription<T> {
+    fn drop(&mut self) {
+        self.hub.unsubscribe(self.user_id, self.id);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task::block_on;
+
+    #[test]
+    fn delivers_to_every_connection_of_a_user_once() {
+        let hub = Arc::new(Hub::new(4));
+        let alice_tab = hub.subscribe(1);
+        let alice_phone = hub.subscribe(1);
+        let bob = hub.subscribe(2);
+        assert_eq!(hub.subscriber_count(), 3);
+        assert_eq!(hub.user_subscriber_count(1), 2);
+
+        hub.publish(vec![1, 1, 3], &"hi");
+        assert_eq!(block_on(alice_tab.recv()), Some("hi"));
+        assert_eq!(block_on(alice_phone.recv()), Some("hi"));
+        assert!(alice_tab.receiver.is_empty());
+        assert!(bob.receiver.is_empty());
+    }
+
+    #[test]
+    fn removes_dropped_subscriptions() {
+        let hub = Arc::new(Hub::new(4));
+        let first = hub.subscribe(1);
+        let second = hub.subscribe(1);
+        drop(first);
+        assert_eq!(hub.user_subscriber_count(1), 1);
+        drop(second);
+        assert_eq!(hub.subscriber_count(), 0);
+        hub.publish(vec![1], &"nobody listens");
+    }
+
+    #[test]
+    fn disconnects_slow_subscribers() {
+        let hub = Arc::new(Hub::new(2));
+        let slow = hub.subscribe(1);
+        let fast = hub.subscribe(1);
+        for event in ["a", "b"].iter() {
+            hub.publish(vec![1], event);
+            assert_eq!(block_on(fast.recv()), Some(*event));
+        }
+        hub.publish(vec![1], &"c");
+        assert_eq!(hub.user_subscriber_count(1), 1);
+        assert_eq!(block_on(fast.recv()), Some("c"));
+
+        assert_eq!(block_on(slow.recv()), Some("a"));
+        assert_eq!(block_on(slow.recv()), Some("b"));
+        assert_eq!(block_on(slow.recv()), None);
+    }
+
+    #[test]
+    fn publishes_to_everyone() {
+        let hub = Arc::new(Hub::new(4));
+        let alice = hub.subscribe(1);
+        let bob = hub.subscribe(2);
+        hub.publish_all(&"all");
+        assert_eq!(block_on(alice.recv()), Some("all"));
+        assert_eq!(block_on(bob.recv()), Some("all"));
+    }
+
+    #[test]
+    fn disconnects_users() {
+        let hub = Arc::new(Hub::new(2));
+        let alice = hub.subscribe(1);
+        let bob = hub.subscribe(2);
+        hub.publish(vec![1], &"last");
+        hub.disconnect_user(1);
+        assert_eq!(hub.subscriber_count(), 1);
+        assert_eq!(block_on(alice.recv()), Some("last"));
+        assert_eq!(block_on(alice.recv()), None);
+        drop(bob);
+        assert_eq!(hub.subscriber_count(), 0);
+    }
+}
@@ -1,81 +1,299 @@
-use std::sync::{Arc, Mutex, MutexGuard, mpsc};
+#![allow(clippy::needless_return)]
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::collections::{hash_map, HashMap};
+use std::path::{Path, PathBuf};
 use std::time;
 use tide_websockets::WebSocket;
-use futures::io::AsyncBufReadExt;
 use async_std::stream::StreamExt;
 use async_std::fs::File;
 use async_std::prelude::*;
 use tide::prelude::json;
 use tide::Request;
+use structopt::StructOpt;
 use std::iter::Iterator;
 
 
-use blake3;
-use tera;
-use base64;
-
 mod view;
 mod util;
+mod multipart;
 mod model;
 mod repository;
+mod migrations;
+mod config;
+mod password;
+mod admin;
+mod hub;
+mod protocol;
+mod presence;
+mod markdown;
+
+/// Messages shown on the page and returned by `GET /messages` by default
+const PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 200;
+
+/// Events queued for a websocket before it is disconnected as too slow
+const SUBSCRIBER_QUEUE_SIZE: usize = 64;
+
+/// How often idle websockets check that their session was not revoked by another process
+const SESSION_CHECK_INTERVAL: time::Duration = time::Duration::from_secs(5);
+
+/// How long a user stays online after closing their last websocket, so that reloading a page does not flicker
+const OFFLINE_DELAY: time::Duration = time::Duration::from_secs(2);
 
-const SERVER_SECRET: &str = "KOAfpNcYBnmqEi9Yxg9335bP0nWOL3I5upuSXVkiLw4";
-const TOKEN_EXPIRATION: time::Duration = time::Duration::from_secs(86400);
+/// Longest time the scheduler sleeps without checking for due messages
+const SCHEDULER_IDLE: time::Duration = time::Duration::from_secs(3600);
+
+/// Time to wait before a background task tries again after an error
+const TASK_RETRY: time::Duration = time::Duration::from_secs(10);
+
+/// Messages deleted in one transaction by the purger, so that requests are not held up for long
+const PURGE_BATCH_SIZE: u32 = 500;
 
 #[derive(Clone)]
 struct State {
+    config: Arc<config::Config>,
+    passwords: password::Hasher,
     repo: Arc<Mutex<repository::Repo>>,
     view: Arc<view::View>, 
-    messages_txs: Arc<Mutex<Vec<(u32, mpsc::Sender<model::MessageResponse>)>>>
+    hub: Arc<hub::Hub<protocol::ServerFrame>>,
+    presence: Arc<presence::Presence>,
+    /// Wakes the task sending scheduled messages
+    scheduler: async_std::channel::Sender<()>,
+    /// Wakes the task deleting expired messages
+    purger: async_std::channel::Sender<()>
 }
 
 impl State {
-    fn lock_repo(&self) -> Result<MutexGuard<repository::Repo>, tide::Error> {
+    fn lock_repo(&self) -> Result<MutexGuard<'_, repository::Repo>, tide::Error> {
         tide::log::debug!("Locking repo...");
         return self.repo.lock().map_err(|e|tide::Error::from_str(500,format!("Couldn't lock database: {:?}",e)));
     }
 
-    fn broadcast_message(&self, msg: &model::MessageResponse) -> Result<(), tide::Error> {
-        tide::log::debug!("Locking message listeners for notification...");
-        self.messages_txs.lock()
-            .map_err(|e| tide::Error::from_str(500, format!("Could not lock websockets: {:?}",e)))?
-            .retain(|(id, tx)| {
-                if !msg.sender_id.eq(id) && !msg.recipients.iter().any(|r| r.id.eq(id)) {
-                    return true;
+    /// Selects every user with their presence
+    fn select_users(&self, repo: &repository::Repo) -> Result<Vec<model::User>, tide::Error> {
+        let online = self.presence.online_users();
+        let mut users = repo.select_users_all()?;
+        for user in users.iter_mut() {
+            user.online = online.contains(&user.id);
+        }
+        return Ok(users);
+    }
+
+    /// Sends an event about a message to its sender and recipients, or to the
+    /// current members of its channel. Locks the repo.
+    fn broadcast_message(&self, event: protocol::ServerFrame) -> Result<(), tide::Error> {
+        if let Some(msg) = event.message() {
+            match msg.channel_id {
+                Some(channel_id) => {
+                    let members = self.lock_repo()?.select_channel_member_ids(channel_id)?;
+                    self.hub.publish(members, &event);
                 }
-                let sending_result = tx.send(msg.clone());
-                if let Err(mpsc::SendError(_)) = sending_result { 
-                    tide::log::debug!("Removing one message listener");
-                    return false;
-                } else { return true; }
-            });
-        tide::log::debug!("Releasing message listeners for notification...");
+                None => {
+                    let users = msg.recipients.iter().map(|r| r.id).chain(Some(msg.sender_id));
+                    self.hub.publish(users, &event);
+                }
+            }
+            if let protocol::ServerFrame::Message { message } = &event {
+                if !message.mentions.is_empty() {
+                    let mention = protocol::ServerFrame::Mention {
+                        message_id: message.id,
+                        sender_id: message.sender_id,
+                        channel_id: message.channel_id
+                    };
+                    self.hub.publish(message.mentions.clone(), &mention);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    /// Sends a new message to whoever should get it now. A scheduled one is left
+    /// for the scheduler, which is told about it.
+    fn publish_new_message(&self, message: model::MessageResponse) -> Result<(), tide::Error> {
+        if message.send_at.is_some() {
+            self.wake_scheduler();
+            return Ok(());
+        }
+        if message.expires_at.is_some() {
+            self.wake_purger();
+        }
+        return self.broadcast_message(protocol::ServerFrame::Message { message });
+    }
+
+    fn wake_scheduler(&self) {
+        // a full channel already holds a wake up
+        let _ = self.scheduler.try_send(());
+    }
+
+    fn wake_purger(&self) {
+        let _ = self.purger.try_send(());
+    }
+
+    /// Sends the messages that are due. Returns the time the next one is due at.
+    fn send_due_messages(&self) -> Result<Option<i64>, tide::Error> {
+        let (sent, next) = {
+            let repo = self.lock_repo()?;
+            (repo.send_due_messages()?, repo.next_send_at()?)
+        };
+        for message in sent {
+            self.publish_new_message(message)?;
+        }
+        return Ok(next);
+    }
+
+    /// Deletes messages whose self-destruct timer ran out or which are past
+    /// retention, in batches, and tells those who could see them. Returns the
+    /// time the next self-destruct timer runs out at.
+    fn purge_expired_messages(&self) -> Result<Option<i64>, tide::Error> {
+        let retention = repository::Retention {
+            days: self.config.retention_days,
+            keep_last: self.config.retention_keep_last
+        };
+        let mut purged_any = false;
+        loop {
+            // the repo is unlocked between batches
+            let mut expired: HashMap<u32, Vec<u32>> = HashMap::new();
+            let stored_names = {
+                let repo = self.lock_repo()?;
+                let message_ids = repo.select_expired_messages(retention, PURGE_BATCH_SIZE)?;
+                if message_ids.is_empty() {
+                    break;
+                }
+                let (purged, stored_names) = repo.purge_messages(&message_ids)?;
+                let mut members: HashMap<u32, Vec<u32>> = HashMap::new();
+                for message in purged {
+                    // those who got events about the message
+                    let users = match message.channel_id {
+                        Some(channel_id) => {
+                            if let hash_map::Entry::Vacant(entry) = members.entry(channel_id) {
+                                entry.insert(repo.select_channel_member_ids(channel_id)?);
+                            }
+                            members[&channel_id].clone()
+                        }
+                        None => { message.user_ids }
+                    };
+                    for user_id in users {
+                        expired.entry(user_id).or_default().push(message.id);
+                    }
+                }
+                stored_names
+            };
+            self.remove_files(&stored_names);
+            for (user_id, message_ids) in expired {
+                self.hub.publish(Some(user_id), &protocol::ServerFrame::MessagesExpired { message_ids });
+            }
+            purged_any = true;
+        }
+        let repo = self.lock_repo()?;
+        if purged_any {
+            repo.incremental_vacuum()?;
+        }
+        return Ok(repo.next_destruct_at()?);
+    }
+
+    /// Removes stored files from disk, ones that can not be removed are only logged
+    fn remove_files(&self, stored_names: &[String]) {
+        for stored_name in stored_names.iter() {
+            if let Err(e) = std::fs::remove_file(self.config.files_dir.join(stored_name)) {
+                tide::log::warn!("Could not remove file {}: {}", stored_name, e);
+            }
+        }
+    }
+
+    /// Records that the user got the messages and tells their senders
+    fn mark_delivered(&self, user_id: u32, message_ids: &[u32]) -> Result<(), tide::Error> {
+        let receipts = self.lock_repo()?.mark_delivered(user_id, message_ids)?;
+        self.publish_receipts(receipts);
         return Ok(());
     }
 
-    fn create_token(username: String, user_id: u32, exp_time: u64) -> String {
-        let token_a = base64::encode(format!("{}:{}:{}", username, user_id, exp_time));
-        let token_a_salt = format!("{}{}", token_a, SERVER_SECRET);
+    /// Records that the user read the messages and tells their senders
+    fn mark_read(&self, user_id: u32, message_ids: &[u32]) -> Result<(), tide::Error> {
+        if message_ids.len() > MAX_PAGE_SIZE as usize {
+            return Err(tide::Error::from_str(400,
+                format!("At most {} messages can be marked at once", MAX_PAGE_SIZE)));
+        }
+        let receipts = self.lock_repo()?.mark_read(user_id, message_ids)?;
+        self.publish_receipts(receipts);
+        return Ok(());
+    }
+
+    fn publish_receipts(&self, receipts: Vec<model::Receipt>) {
+        for receipt in receipts {
+            self.hub.publish(Some(receipt.sender_id), &protocol::ServerFrame::Receipt(receipt));
+        }
+    }
+
+    /// Handles a frame sent over the websocket after the hello. Returns the reply, if any.
+    fn handle_client_frame(&self, user_id: u32, frame: protocol::ClientFrame) -> Option<protocol::ServerFrame> {
+        use protocol::{ClientFrame, ServerFrame};
+        match frame {
+            ClientFrame::Hello { .. } => {
+                return Some(ServerFrame::error(None, 400, "Already authenticated"));
+            }
+            ClientFrame::SendMessage { id, recipients, text, reply_to, channel_id, send_at, destruct_after } => {
+                let inserted = match self.lock_repo() {
+                    Ok(repo) => {
+                        let request = model::PostMessageRequest {
+                            recipients, text, reply_to, channel_id, send_at, destruct_after
+                        };
+                        repo.insert_message(user_id, request, &[])
+                    }
+                    Err(e) => { return Some(ServerFrame::error(Some(id), 500, e)); }
+                };
+                match inserted {
+                    Ok(response) => {
+                        let message_id = response.id;
+                        if let Err(e) = self.publish_new_message(response) {
+                            return Some(ServerFrame::error(Some(id), 500, e));
+                        }
+                        return Some(ServerFrame::Ack { id, message_id: Some(message_id) });
+                    }
+                    Err(e) => { return Some(ServerFrame::error(Some(id), e.status(), e)); }
+                }
+            }
+            ClientFrame::Typing { recipients } => {
+                let recipients = recipients.into_iter().filter(|id| *id != user_id);
+                self.hub.publish(recipients, &ServerFrame::Typing { user_id });
+                return None;
+            }
+            ClientFrame::MarkRead { id, message_ids } => {
+                match self.mark_read(user_id, &message_ids) {
+                    Ok(()) => { return Some(ServerFrame::Ack { id, message_id: None }); }
+                    Err(e) => { return Some(ServerFrame::error(Some(id), e.status().into(), e)); }
+                }
+            }
+            ClientFrame::Ping { id } => {
+                return Some(ServerFrame::Ack { id, message_id: None });
+            }
+        }
+    }
+
+    fn create_token(&self, username: String, user_id: u32, generation: u32, exp_time: u64) -> String {
+        let token_a = base64::encode(format!("{}:{}:{}:{}", username, user_id, exp_time, generation));
+        let token_a_salt = format!("{}{}", token_a, self.config.secret);
         let token_b = blake3::hash(token_a_salt.as_bytes()).to_hex().to_string();
         return format!("{}.{}", token_a, token_b);
     }
 
-    fn parse_token(token: &str) -> Option<(String,u32)> {
+    /// Checks signature and expiration of a token. Returns username, user id and token generation.
+    fn parse_token(&self, token: &str) -> Option<(String,u32,u32)> {
         let mut token_split = token.split('.');
         let token_a = token_split.next()?;
         let token_b = token_split.next()?;
-        let token_a_salt = format!("{}{}", token_a, SERVER_SECRET);
+        let token_a_salt = format!("{}{}", token_a, self.config.secret);
 
-        // TODO: Check if password changed!!!
         if blake3::hash(token_a_salt.as_bytes()).to_hex().to_string().ne(token_b) {
             tide::log::warn!("Token {} has incorrect hash.", token_a);
             return None;
         } else {
             let decoded = String::from_utf8(base64::decode(token_a).ok()?).ok()?;
-            let mut split = decoded.split(':');
-            let username = split.next()?;
-            let user_id = split.next()?;
+            let mut split = decoded.rsplitn(4, ':');
+            let generation = split.next()?;
             let exp_time = split.next()?;
+            let user_id = split.next()?;
+            let username = split.next()?;
 
             let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH).ok()?;
             if time::Duration::new(exp_time.parse().ok()?, 0) < now { 
@@ -83,11 +301,97 @@ impl State {
                 return None;
             }
 
-            return Some((username.to_string(), user_id.parse().ok()?));
+            return Some((username.to_string(), user_id.parse().ok()?, generation.parse().ok()?));
         }
     }
 
-    fn get_authenticated_user_id(&self, req: &Request<State>) -> Result<(u32, String), tide::Error> {
+    /// Checks that sessions of the user were not revoked since the token was issued
+    fn is_token_current(&self, user_id: u32, generation: u32) -> Result<bool, tide::Error> {
+        let current = self.lock_repo()?.select_token_generation(user_id)?;
+        return Ok(current == Some(generation));
+    }
+
+    /// Invalidates all tokens of the user and closes their websockets
+    fn revoke_sessions(&self, user_id: u32) -> Result<(), tide::Error> {
+        self.lock_repo()?.revoke_sessions(user_id)?;
+        self.hub.disconnect_user(user_id);
+        return Ok(());
+    }
+
+    /// Adds a user with an invite
+    async fn register(&self, body: &model::RegisterRequest) -> Result<u32, tide::Error> {
+        let color = body.color.as_deref().map(str::trim).filter(|c| !c.is_empty()).unwrap_or("gray");
+        util::check_username(&body.username)
+            .and(util::check_display_name(body.name.trim()))
+            .and(util::check_color(color))
+            .and(util::check_password(&body.password))
+            .map_err(|e| tide::Error::from_str(422, e))?;
+
+        let passwords = self.passwords.clone();
+        let password = body.password.clone();
+        let hash = async_std::task::spawn_blocking(move || passwords.hash(&password)).await
+            .map_err(|e| tide::Error::from_str(500, e.to_string()))?;
+        let user_id = self.lock_repo()?.register_user(body, color, &hash)
+            .map_err(|e| tide::Error::new(e.status(), e))?;
+        tide::log::info!("Registered user {} with an invite", user_id);
+        return Ok(user_id);
+    }
+
+    /// Changes the profile of a user and tells everyone about a new name or color.
+    /// A new password revokes sessions of the user.
+    async fn update_profile(&self, user_id: u32, body: &model::UpdateProfileRequest) -> Result<model::Profile, tide::Error> {
+        let name = body.name.as_deref().map(str::trim);
+        let color = body.color.as_deref().map(str::trim);
+        if let Some(name) = name {
+            util::check_display_name(name).map_err(|e| tide::Error::from_str(422, e))?;
+        }
+        if let Some(color) = color {
+            util::check_color(color).map_err(|e| tide::Error::from_str(422, e))?;
+        }
+
+        let profile = self.lock_repo()?.select_profile(user_id)?
+            .ok_or(tide::Error::from_str(401, "User does not exist"))?;
+        let new_hash = match body.password.as_deref().filter(|p| !p.is_empty()) {
+            Some(password) => {
+                util::check_password(password).map_err(|e| tide::Error::from_str(422, e))?;
+                let current = body.current_password.clone()
+                    .ok_or(tide::Error::from_str(403, "Current password is required to change it"))?;
+                let stored = self.lock_repo()?.select_user_credentials(&profile.username)?
+                    .map(|cred| cred.password)
+                    .unwrap_or_default();
+                let passwords = self.passwords.clone();
+                let password = password.to_owned();
+                let new_hash = async_std::task::spawn_blocking(move || {
+                    match passwords.verify(&current, &stored) {
+                        password::Verification::Invalid => { None }
+                        _ => { Some(passwords.hash(&password)) }
+                    }
+                }).await.ok_or(tide::Error::from_str(403, "Current password is incorrect"))?;
+                Some(new_hash.map_err(|e| tide::Error::from_str(500, e.to_string()))?)
+            }
+            None => { None }
+        };
+
+        let repo = self.lock_repo()?;
+        repo.update_profile(user_id, name, color)?;
+        if let Some(new_hash) = &new_hash {
+            repo.set_user_password(user_id, new_hash, true)?;
+        }
+        let profile = repo.select_profile(user_id)?
+            .ok_or(tide::Error::from_str(401, "User does not exist"))?;
+        drop(repo);
+
+        if new_hash.is_some() {
+            self.hub.disconnect_user(user_id);
+        }
+        if name.is_some() || color.is_some() {
+            let user = model::EmbeddedRecipient { id: profile.id, name: profile.name.clone(), color: profile.color.clone() };
+            self.hub.publish_all(&protocol::ServerFrame::UserUpdated { user });
+        }
+        return Ok(profile);
+    }
+
+    async fn get_authenticated_user_id(&self, req: &Request<State>) -> Result<(u32, String), tide::Error> {
         let mut authorization_words = req.header("Authorization")
             .ok_or(tide::Error::from_str(401, "Authorization token is not provided"))?
             .as_str()
@@ -96,17 +400,18 @@ impl State {
         let auth_type = authorization_words.next()
             .ok_or(tide::Error::from_str(400, "Unexpected end of Authorization token"))?;
 
-        let repo = self.lock_repo()?;
-        tide::log::debug!("AUTH: Repo locked.");
-
         match auth_type {
             "Bearer" => {
                 let token_encoded = authorization_words.next()
                     .ok_or(tide::Error::from_str(400, "Unexpected end of Authorization token"))?;
 
-                let (username, id) = Self::parse_token(token_encoded)
+                let (username, id, generation) = self.parse_token(token_encoded)
                     .ok_or(tide::Error::from_str(401, "Bearer token is invalid"))?;
 
+                if !self.is_token_current(id, generation)? {
+                    return Err(tide::Error::from_str(401, "Bearer token is revoked"));
+                }
+
                 return Ok((id, username));
             },
             "Basic" => {
@@ -118,90 +423,471 @@ impl State {
 
                 let token_string = String::from_utf8(token_bytes)?;
 
-                let mut token_split = token_string.split(":");
+                let mut token_split = token_string.splitn(2, ':');
                 
                 let username = token_split.next()
                     .ok_or(tide::Error::from_str(400,"Incorrect token format"))?
                     .to_string();
 
                 let password = token_split.next()
-                    .ok_or(tide::Error::from_str(400,"Incorrect token format"))
-                    .map(|v| v.as_bytes())
-                    .map(|v| blake3::hash(&v).to_hex().to_string())?;
-
-                let cred = model::UserCredentials { username: username.clone(), password };
+                    .ok_or(tide::Error::from_str(400,"Incorrect token format"))?
+                    .to_string();
 
-                let user_id: u32 = match repo.get_authenticated_user_id(&cred)? {
-                    Some(n) => { n }
-                    None => { repo.register_user(&cred)?
-                        .ok_or(tide::Error::from_str(401, "Incorrect username or password"))? }
+                let cred = self.lock_repo()?.select_user_credentials(&username)?;
+                let passwords = self.passwords.clone();
+
+                // hashing is slow on purpose, so it is done off the executor threads
+                let cred = match cred {
+                    // users without a password need a reset by an administrator
+                    Some(cred) if !cred.password.is_empty() => {
+                        let stored = cred.password.clone();
+                        let (verification, new_hash) = async_std::task::spawn_blocking(move || {
+                            let verification = passwords.verify(&password, &stored);
+                            let new_hash = match verification {
+                                password::Verification::NeedsRehash => Some(passwords.hash(&password)),
+                                _ => None
+                            };
+                            (verification, new_hash)
+                        }).await;
+
+                        if verification == password::Verification::Invalid {
+                            return Err(tide::Error::from_str(401, "Incorrect username or password"));
+                        }
+                        if let Some(new_hash) = new_hash {
+                            let new_hash = new_hash.map_err(|e| tide::Error::from_str(500, e.to_string()))?;
+                            self.lock_repo()?.set_user_password(cred.id, &new_hash, false)?;
+                            tide::log::info!("Upgraded password hash of user {}", cred.id);
+                        }
+                        cred
+                    }
+                    _ => {
+                        async_std::task::spawn_blocking(move || passwords.verify_dummy(&password)).await;
+                        return Err(tide::Error::from_str(401, "Incorrect username or password"));
+                    }
                 };
 
-                return Ok((user_id, username));
+                return Ok((cred.id, cred.username));
             },
             _ => { return Err(tide::Error::from_str(400, "Authroization type is unknown")) }
         }
     }
 }
 
+/// Checks `limit` of a paged request
+fn page_limit(limit: Option<u32>) -> Result<u32, tide::Error> {
+    match limit {
+        None => { return Ok(PAGE_SIZE); }
+        Some(limit) if (1..=MAX_PAGE_SIZE).contains(&limit) => { return Ok(limit); }
+        Some(_) => { return Err(tide::Error::from_str(400,
+            format!("limit must be between 1 and {}", MAX_PAGE_SIZE))); }
+    }
+}
+
+/// Finds a channel the user is a member of
+fn select_joined_channel(repo: &repository::Repo, user_id: u32, channel_id: u32) -> Result<model::ChannelResponse, tide::Error> {
+    match repo.select_channel(user_id, channel_id)? {
+        Some(channel) if channel.joined => { return Ok(channel); }
+        Some(_) => { return Err(tide::Error::from_str(403, "Not a member of the channel")); }
+        None => { return Err(tide::Error::from_str(404, "Channel does not exist")); }
+    }
+}
+
+/// Tells if the body is a form of the page rather than JSON
+fn is_form(req: &Request<State>) -> bool {
+    return req.content_type().map(|mime| mime.essence() == "application/x-www-form-urlencoded").unwrap_or(false);
+}
+
+/// Reads an optional id from a form field, empty fields are missing
+fn form_id(body: &HashMap<String, String>, field: &str) -> Result<Option<u32>, tide::Error> {
+    match body.get(field).map(|id| id.trim()).filter(|id| !id.is_empty()) {
+        Some(id) => { return Ok(Some(id.parse().map_err(|e| tide::Error::new(400, e))?)); }
+        None => { return Ok(None); }
+    }
+}
+
+/// Answers a request changing channels: forms of the page are redirected
+/// to `location`, other clients get no content
+fn channel_changed(req: &Request<State>, location: &str) -> tide::Response {
+    let from_page = req.header("Accept").map(|accept| accept.as_str().contains("text/html")).unwrap_or(false);
+    if from_page {
+        return tide::Redirect::see_other(location).into();
+    }
+    return tide::Response::new(204);
+}
+
+/// Sends messages of the user newer than `last_seen` over the websocket, oldest
+/// first. Returns id of the last message sent.
+async fn replay_messages(
+    state: &State,
+    stream: &tide_websockets::WebSocketConnection,
+    user_id: u32,
+    last_seen: u32
+) -> tide::Result<u32> {
+    let mut last_sent = last_seen;
+    loop {
+        let page = state.lock_repo()?
+            .select_messages_for_user(user_id, repository::Conversation::All, None, Some(last_sent), MAX_PAGE_SIZE)?;
+        let ids: Vec<u32> = page.messages.iter().map(|m| m.id).collect();
+        for message in page.messages {
+            last_sent = message.id;
+            stream.send_json(&protocol::ServerFrame::Message { message }).await?;
+        }
+        state.mark_delivered(user_id, &ids)?;
+        if !page.has_more {
+            return Ok(last_sent);
+        }
+    }
+}
+
+/// Files stored while handling a request. They are removed from disk when
+/// dropped, unless the request succeeded and called `keep`.
+struct Uploads {
+    files_dir: PathBuf,
+    files: Vec<model::StoredFile>
+}
+
+impl Uploads {
+    fn new(files_dir: &Path) -> Self {
+        return Self { files_dir: files_dir.to_path_buf(), files: Vec::new() };
+    }
+
+    /// Leaves files on disk, once they are referenced by a message
+    fn keep(mut self) {
+        self.files.clear();
+    }
+}
+
+impl Drop for Uploads {
+    fn drop(&mut self) {
+        for file in self.files.iter() {
+            if let Err(e) = std::fs::remove_file(self.files_dir.join(&file.stored_name)) {
+                tide::log::warn!("Could not remove file {}: {}", file.stored_name, e);
+            }
+        }
+    }
+}
+
+/// Reads form fields and stores uploaded files
+async fn read_multipart_form<R>(mut form: multipart::Multipart<R>, files_dir: &Path) 
+    -> Result<(HashMap<String, String>, Uploads), tide::Error> 
+    where R: futures::io::AsyncBufRead + Unpin
+{
+    let mut body = HashMap::new();
+    let mut uploads = Uploads::new(files_dir);
+    let result: Result<(), multipart::Error> = async {
+        while let Some(mut part) = form.next_part().await? {
+            match part.headers().file_name.clone() {
+                Some(file_name) if file_name.is_empty() => { continue; }
+                Some(file_name) => {
+                    let stored_name = util::generate_stored_name();
+                    tide::log::debug!("Storing file {} ({:?}) as {}", 
+                        file_name, part.headers().content_type, stored_name);
+                    let file = File::create(files_dir.join(&stored_name)).await?;
+                    uploads.files.push(model::StoredFile { original_name: file_name, stored_name });
+                    let mut file = futures::io::BufWriter::new(file);
+                    futures::io::copy(&mut part, &mut file).await?;
+                    file.flush().await?;
+                }
+                None => {
+                    let value = part.text().await?;
+                    body.insert(part.headers().field_name.clone(), value);
+                }
+            }
+        }
+        return Ok(());
+    }.await;
+
+    if let Err(e) = result {
+        return Err(tide::Error::new(e.status(), e));
+    }
+    return Ok((body, uploads));
+}
+
+/// Runs a background job over and over, until the server stops. The job returns
+/// the time it is due again at; it waits for `idle` at most and can be woken up
+/// earlier. It starts with the server, so work that became due while the server
+/// was down is done right away.
+async fn run_when_due(
+    name: &str,
+    idle: time::Duration,
+    woken: async_std::channel::Receiver<()>,
+    job: impl Fn() -> Result<Option<i64>, tide::Error>
+) {
+    loop {
+        let delay = match job() {
+            Ok(Some(due_at)) => {
+                let seconds = due_at - chrono::Utc::now().timestamp();
+                time::Duration::from_secs(seconds.max(0) as u64).min(idle)
+            }
+            Ok(None) => { idle }
+            Err(e) => {
+                tide::log::warn!("Could not {}: {}", name, e);
+                TASK_RETRY
+            }
+        };
+        // work added in the meantime may be due earlier
+        let _ = async_std::future::timeout(delay, woken.recv()).await;
+    }
+}
+
 #[async_std::main]
 async fn main() -> tide::Result<()> {
-    tide::log::with_level(tide::log::LevelFilter::Debug);
+    let args = config::Args::from_args();
+    tide::log::with_level(if args.command.is_some() { 
+        tide::log::LevelFilter::Warn 
+    } else { 
+        tide::log::LevelFilter::Debug 
+    });
+
+    let config_path = config::file_path(&args);
+    let mut config = match config::Config::load(&config_path, &args) {
+        Ok(config) => { config }
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    let repo = repository::Repo::new("messages.db")
-        .expect("Error while initializing database");
-    let mut tera = tera::Tera::new("templates/*.html")
+    // administrative commands only need the database
+    if let Some(command) = args.command {
+        if let Err(e) = admin::run(command, &config) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Err(e) = config.validate().and_then(|_| config.ensure_secret(&config_path)) {
+        eprintln!("Configuration error: {}", e);
+        std::process::exit(1);
+    }
+
+    let repo = match repository::Repo::new(&config.database) {
+        Ok(repo) => { repo }
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    std::fs::create_dir_all(&config.files_dir)
+        .expect("Could not create files directory");
+    let mut tera = tera::Tera::new(&config.templates)
         .expect("Could not load templates");
     tera.autoescape_on(vec!["html", ".sql"]);
 
-
+    let passwords = password::Hasher::new(
+        config.argon2_memory_kib, config.argon2_iterations, config.argon2_parallelism)
+        .expect("Password hashing parameters were validated");
+    let listen = config.listen;
+    let static_dir = config.static_dir.clone();
+    let repo = Arc::new(Mutex::new(repo));
+    let hub = Arc::new(hub::Hub::new(SUBSCRIBER_QUEUE_SIZE));
+    let (wake_scheduler, scheduler_woken) = async_std::channel::bounded(1);
+    let (wake_purger, purger_woken) = async_std::channel::bounded(1);
     let mut app = tide::with_state( State {
-        repo: Arc::new(Mutex::new(repo)),
+        config: Arc::new(config),
+        passwords,
+        repo: repo.clone(),
         view: Arc::new(view::View { tera }),
-        messages_txs: Arc::new(Mutex::new(Vec::new()))
+        hub: hub.clone(),
+        presence: Arc::new(presence::Presence::new(hub, repo, OFFLINE_DELAY)),
+        scheduler: wake_scheduler,
+        purger: wake_purger
     });
+    let state = app.state().clone();
+    async_std::task::spawn(run_when_due("send scheduled messages", SCHEDULER_IDLE, scheduler_woken,
+        move || state.send_due_messages()));
+    let state = app.state().clone();
+    let purge_interval = time::Duration::from_secs(state.config.purge_interval);
+    async_std::task::spawn(run_when_due("purge expired messages", purge_interval, purger_woken,
+        move || state.purge_expired_messages()));
     app.with(tide_compress::CompressMiddleware::new());
 
 
-    app.at("/static").serve_dir("templates/static")?;
+    app.at("/static").serve_dir(static_dir)?;
 
 
 
     // web pages
     app.at("/").get(|req: Request<State>| async move {
         // authenticate with credentials
-        let (user_id, username) = match req.state().get_authenticated_user_id(&req) {
+        let (user_id, username) = match req.state().get_authenticated_user_id(&req).await {
             Ok(ok) => { ok }
             Err(e) => { return Ok(tide::Response::builder(401)
                             .header("WWW-Authenticate", "Basic")
-                            .body(e.to_string())
+                            .body(format!("{}. <a href=\"/register\">Sign up with an invite</a>", e))
+                            .content_type(tide::http::mime::HTML)
                             .build()); }
         };
         // generate authorization token
-        let expiration_time = (time::SystemTime::now()+TOKEN_EXPIRATION)
+        let token_expiration = time::Duration::from_secs(req.state().config.token_expiration);
+        let expiration_time = (time::SystemTime::now()+token_expiration)
             .duration_since(time::UNIX_EPOCH)
             .expect("Can not count time anymore")
             .as_secs();
-        let token = State::create_token(username, user_id, expiration_time);
 
         // render page
+        let query: model::IndexRequest = req.query()?;
         let repo = req.state().lock_repo()?;
-        let messages = repo.select_messages_for_user(user_id)?;
-        let users = repo.select_users_all()?;
-        let body = req.state().view.render_index(messages, users)
+        let generation = repo.select_token_generation(user_id)?
+            .ok_or(tide::Error::from_str(401, "User does not exist"))?;
+        let token = req.state().create_token(username, user_id, generation, expiration_time);
+        let users = req.state().select_users(&repo)?;
+        let reply = match query.reply_to {
+            Some(id) => {
+                let message = repo.select_message_for_user(user_id, id)?
+                    .ok_or(tide::Error::from_str(404, "Message does not exist"))?;
+                // everyone else who got the message
+                let recipients = message.recipients.iter().map(|r| r.id)
+                    .chain(Some(message.sender_id))
+                    .filter(|id| *id != user_id)
+                    .collect();
+                Some(view::Reply { message, recipients })
+            }
+            None => { None }
+        };
+        // replies are written in the conversation of the message
+        let channel = match query.channel.or(reply.as_ref().and_then(|r| r.message.channel_id)) {
+            Some(id) => { Some(select_joined_channel(&repo, user_id, id)?) }
+            None => { None }
+        };
+        let conversation = match &channel {
+            Some(channel) => { repository::Conversation::Channel(channel.id) }
+            None => { repository::Conversation::Direct }
+        };
+        let page = repo.select_messages_for_user(user_id, conversation, query.before, None, PAGE_SIZE)?;
+        let channels = repo.select_channels(user_id)?;
+        drop(repo);
+        let ids: Vec<u32> = page.messages.iter().map(|m| m.id).collect();
+        req.state().mark_delivered(user_id, &ids)?;
+        let body = req.state().view.render_index(user_id, page, query.before.is_none(), users, reply, channels, channel)
             .map_err(|e| tide::Error::new(500, e))?;
 
         return Ok(tide::Response::builder(200)
             .body(body)
             .content_type(tide::http::mime::HTML)
-            .header("Set-Cookie", format!("token={}; Max-Age={}", token, TOKEN_EXPIRATION.as_secs()))
+            .header("Set-Cookie", format!("token={}; Max-Age={}", token, token_expiration.as_secs()))
             .build())
     });
 
+    // search results page, same as `/messages/search`
+    app.at("/search").get(|req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let query: model::SearchRequest = req.query()?;
+        let offset = query.offset.unwrap_or(0);
+        let page = req.state().lock_repo()?.search_messages(user_id, &query.q, offset, PAGE_SIZE)?;
+        let body = req.state().view.render_search(&query.q, page, offset, PAGE_SIZE)?;
+
+        return Ok(tide::Response::builder(200)
+            .body(body)
+            .content_type(tide::http::mime::HTML)
+            .build());
+    });
+
+    app.at("/users").get(|req: Request<State>| async move {
+        req.state().get_authenticated_user_id(&req).await?;
+        let users = req.state().select_users(&*req.state().lock_repo()?)?;
+        return Ok(json!(model::UserList { users }));
+    });
+
+    app.at("/me").get(|req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let profile = req.state().lock_repo()?.select_profile(user_id)?
+            .ok_or(tide::Error::from_str(401, "User does not exist"))?;
+        return Ok(json!(profile));
+    });
+
+    app.at("/me").patch(|mut req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let body: model::UpdateProfileRequest = req.body_json().await?;
+        let profile = req.state().update_profile(user_id, &body).await?;
+        return Ok(json!(profile));
+    });
+
+    app.at("/settings").get(|req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let query: HashMap<String, String> = req.query()?;
+        let profile = req.state().lock_repo()?.select_profile(user_id)?
+            .ok_or(tide::Error::from_str(401, "User does not exist"))?;
+        let body = req.state().view.render_settings(&profile, None, query.contains_key("saved"))?;
+
+        return Ok(tide::Response::builder(200)
+            .body(body)
+            .content_type(tide::http::mime::HTML)
+            .build());
+    });
+
+    // settings form, same as `PATCH /me`
+    app.at("/settings").post(|mut req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let body: model::UpdateProfileRequest = req.body_form().await?;
+
+        match req.state().update_profile(user_id, &body).await {
+            // the browser asks for the new password on the next page
+            Ok(_) if body.password.as_deref().map(|p| !p.is_empty()).unwrap_or(false) => {
+                return Ok(tide::Redirect::see_other("/").into());
+            }
+            Ok(_) => {
+                return Ok(tide::Redirect::see_other("/settings?saved").into());
+            }
+            Err(e) => {
+                let mut profile = req.state().lock_repo()?.select_profile(user_id)?
+                    .ok_or(tide::Error::from_str(401, "User does not exist"))?;
+                profile.name = body.name.unwrap_or(profile.name);
+                profile.color = body.color.unwrap_or(profile.color);
+                let page = req.state().view.render_settings(&profile, Some(&e.to_string()), false)?;
+                return Ok(tide::Response::builder(e.status())
+                    .body(page)
+                    .content_type(tide::http::mime::HTML)
+                    .build());
+            }
+        }
+    });
+
+    // sign-up form, an invite link fills in the code
+    app.at("/register").get(|req: Request<State>| async move {
+        let query: HashMap<String, String> = req.query()?;
+        let form = model::RegisterRequest {
+            invite: query.get("invite").cloned().unwrap_or_default(),
+            ..Default::default()
+        };
+        let body = req.state().view.render_register(&form, None)?;
+
+        return Ok(tide::Response::builder(200)
+            .body(body)
+            .content_type(tide::http::mime::HTML)
+            .build());
+    });
+
+    // JSON, or the sign-up form
+    app.at("/register").post(|mut req: Request<State>| async move {
+        let is_form = is_form(&req);
+        let body: model::RegisterRequest = if is_form { req.body_form().await? } else { req.body_json().await? };
+
+        match req.state().register(&body).await {
+            Ok(_) if is_form => {
+                return Ok(tide::Redirect::see_other("/").into());
+            }
+            Ok(id) => {
+                return Ok(tide::Response::builder(201)
+                    .body(json!(model::RegisteredUser { id, username: body.username }))
+                    .build());
+            }
+            Err(e) if is_form => {
+                let page = req.state().view.render_register(&body, Some(&e.to_string()))?;
+                return Ok(tide::Response::builder(e.status())
+                    .body(page)
+                    .content_type(tide::http::mime::HTML)
+                    .build());
+            }
+            Err(e) => { return Err(e); }
+        }
+    });
+
     // html form 
     app.at("/").post(|mut req: Request<State>| async move {
         // auth
-        let (user_id, _) = req.state().get_authenticated_user_id(&req)?;
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
 
         // get file
         let content_type = req.header("Content-Type")
@@ -210,167 +896,66 @@ async fn main() -> tide::Result<()> {
             .as_str()
             .to_string();
 
-        let mut content_type_split = content_type.split(";");
-        let content_type_type = content_type_split.next()
-            .ok_or(tide::Error::from_str(400, "Content-Type is not provided"))?;
-
-        let mut body = std::collections::HashMap::<String, String>::new();
-        if content_type_type == "multipart/form-data" {
-            //Можно найти подстроку boundary= и избавится от сплита
-            let mut content_type_boundary = content_type_split.next()
-                .ok_or(tide::Error::from_str(400, "Boundary is not provided (A)"))?
-                .trim()
-                .split("=");
-
-            if content_type_boundary.next()
-                .ok_or(tide::Error::from_str(400,"Boundary is not provided (B)"))? != "boundary" {
-                return Err(tide::Error::from_str(400, "Boundary is not provided (C)"));
-            }
-
-            let provided_boundary = content_type_boundary.next()
-                .ok_or(tide::Error::from_str(400,"Boundary is not provided (D)"))?;
-
-            let boundary = format!("--{}", &provided_boundary);
-            let boundary = boundary.as_bytes();
-
-            let mut reader = req.take_body().into_reader();
-            let mut window: Vec<u8> = Vec::with_capacity(8192);
-            loop {
-                if window.len() > 8192 {
-                    tide::log::warn!("Collected {} bytes before giving up", window.len());
-                    return Err(tide::Error::from_str(500, "Could not read multipart data - too big"));
-                }
-                let buf = reader.fill_buf().await?;
-                let buf_len = buf.len();
-                if buf_len == 0 && window.len() == 0 {
-                    return Err(tide::Error::from_str(500, "Unexpected end of entity stream (A)"));
-                }
-
-                window.extend_from_slice(buf);
-                reader.consume_unpin(buf_len);
-
-                match util::contains(&window, &boundary) {
-                    util::ContainsResult::DoesNotContain => {
-                        return Err(tide::Error::from_str(500, "Could not find boundary"));
-                    }
-                    util::ContainsResult::PossiblyContains(p) => {
-                        tide::log::warn!("Had to skip {} bytes to find boundary!", p);
-                    }
-                    util::ContainsResult::Contains(p) => {
-                        let shift = p + boundary.len();
-                        window.drain(..shift);
-                        if window.len() == 0 {
-                                tide::log::debug!("Success in reading multipart/form-data (kinda)");
-                                break;
-                        }
-                        if let util::ContainsResult::Contains(0) = util::contains(&window[..4], b"--\r\n") {
-                            if 0 == reader.fill_buf().await?.len() && window.len() == 4 {
-                                tide::log::debug!("Success in reading multipart/form-data");
-                                break;
-                            }
-                        }
-                        if let util::ContainsResult::Contains(p) = util::contains(&window, b"\r\n\r\n") {
-                            let headers_bytes: Vec<u8> = window.drain(..p+4).collect();
-                            let headers_slice = std::str::from_utf8(&headers_bytes)?;
-                            let info = util::multipart::BodyPartInfo::from_headers(&headers_slice)?;
-
-                            if let Some(file_name) = info.file_name {
-                                if file_name.is_empty() { continue; }
-                                let     file = File::create(file_name).await?;
-                                let mut file = futures::io::BufWriter::new(file); 
-                                loop {
-                                    match util::contains(&window, &boundary) {
-                                        util::ContainsResult::DoesNotContain => {
-                                            file.write_all(&window).await?;
-                                            window.clear();
-                                            let buf = reader.fill_buf().await?;
-                                            let buf_len = buf.len();
-                                            if buf_len == 0 {
-                                                return Err(tide::Error::from_str(500, 
-                                                        "Unexpected end of entity stream (B)"));
-                                            }
-                                            window.extend_from_slice(buf);
-                                            reader.consume_unpin(buf_len);
-                                        }
-                                        util::ContainsResult::PossiblyContains(p) => {
-                                            for byte in window.drain(..p) {
-                                                file.write_all(std::slice::from_ref(&byte)).await?;
-                                            }
-                                            let buf = reader.fill_buf().await?;
-                                            let buf_len = buf.len();
-                                            if buf_len == 0 {
-                                                return Err(tide::Error::from_str(500, 
-                                                        "Unexpected end of entity stream (C)"));
-                                            }
-                                            window.extend_from_slice(buf);
-                                            reader.consume_unpin(buf_len);
-                                        }
-                                        util::ContainsResult::Contains(p) => {
-                                            if p > 0 {
-                                                for byte in window.drain(..p-2) {
-                                                    file.write_all(std::slice::from_ref(&byte)).await?;
-                                                }
-                                            }
-                                            break;
-                                        }
-                                    }
-                                }
-                                file.flush().await?;
-                            } else {
-                                loop {
-                                    match util::contains(&window, &boundary) {
-                                        util::ContainsResult::Contains(p) => {
-                                            let value = String::from_utf8(window.drain(..p).collect())
-                                                .map_err(|e| tide::Error::new(422, e))?;
-                                            body.insert(info.field_name, value);
-                                            break;
-                                        }
-                                        _ => {
-                                            let buf = reader.fill_buf().await?;
-                                            let buf_len = buf.len();
-                                            if buf_len == 0 && window.len() == 0 {
-                                                return Err(tide::Error::from_str(500,
-                                                        "Unexpected end of entity stream (D)"));
-                                            }
-                                            window.extend_from_slice(buf);
-                                            reader.consume_unpin(buf_len);
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+        let content_type_type = content_type.split(';').next()
+            .ok_or(tide::Error::from_str(400, "Content-Type is not provided"))?
+            .trim();
+
+        let (body, uploads) = if content_type_type == "multipart/form-data" {
+            let config = req.state().config.clone();
+            let limits = multipart::Limits { 
+                part_size: config.max_file_size, 
+                total_size: config.max_upload_size, 
+                ..multipart::Limits::default() 
+            };
+            let form = multipart::Multipart::from_content_type(
+                req.take_body().into_reader(), &content_type, limits)
+                .map_err(|e| tide::Error::new(e.status(), e))?;
+            read_multipart_form(form, &config.files_dir).await?
         } else {
-            body = req.body_form().await?;
-        }
+            (req.body_form().await?, Uploads::new(&req.state().config.files_dir))
+        };
 
 
         let repo = req.state().lock_repo()?;
-        let users = repo.select_users_all()?;
+        let users = req.state().select_users(&repo)?;
         let text = body.get("text")
             .ok_or(tide::Error::from_str(400, "Missing text field"))?;
 
         let recipients: Vec<u32> = users.iter()
             .map(|u| (u.id, format!("usr{}", u.id)))
-            .map(|i| (i.0, body.get(&i.1).is_some()))
+            .map(|i| (i.0, body.contains_key(&i.1)))
             .filter(|i| i.1)
             .map(|i| i.0)
             .collect();
-        if recipients.len() == 0 {
-            return Ok(tide::Response::builder(400)
-                .body(format!("No message recipients provided. Your message: {}", text))
-                .build());
-        }
+        // set on the page of a channel
+        let channel_id = form_id(&body, "channel_id")?;
+
+        let reply_to = form_id(&body, "reply_to")?;
+        // seconds, chosen in the form
+        let destruct_after = form_id(&body, "destruct_after")?;
+
+        let message = model::PostMessageRequest {
+            recipients, text: text.to_string(), reply_to, channel_id, send_at: None, destruct_after
+        };
+        let response = repo.insert_message(user_id, message, &uploads.files)
+            .map_err(|e| tide::Error::new(e.status(), e))?;
+        uploads.keep();
+        drop(repo);
 
-        let message = model::PostMessageRequest { recipients, text: text.to_string() };
-        let response = repo.insert_message(user_id, message)?;
+        req.state().publish_new_message(response)?;
 
-        req.state().broadcast_message(&response)?;
-        
-        let messages = repo.select_messages_for_user(user_id)?;
-        let body = req.state().view.render_index(messages, users)?;
+        let repo = req.state().lock_repo()?;
+        let channel = match channel_id {
+            Some(id) => { Some(select_joined_channel(&repo, user_id, id)?) }
+            None => { None }
+        };
+        let conversation = match channel_id {
+            Some(id) => { repository::Conversation::Channel(id) }
+            None => { repository::Conversation::Direct }
+        };
+        let page = repo.select_messages_for_user(user_id, conversation, None, None, PAGE_SIZE)?;
+        let channels = repo.select_channels(user_id)?;
+        let body = req.state().view.render_index(user_id, page, true, users, None, channels, channel)?;
 
         return Ok(tide::Response::builder(200)
             .body(body)
@@ -378,79 +963,420 @@ async fn main() -> tide::Result<()> {
             .build());
     });
 
-    // websocket
+    // websocket, see docs/websocket.md
     app.at("/websocket").get(WebSocket::new(|req: Request<State>, mut stream| async move {
-        use std::borrow::Cow;
+        use protocol::{ClientFrame, ServerFrame};
+        let state = req.state();
+
+        tide::log::debug!("Websocket: Reading hello from websocket");
+        let hello = match stream.next().await {
+            Some(Ok(tide_websockets::Message::Text(text))) => { protocol::parse(&text).ok() }
+            Some(Ok(_)) => { None }
+            Some(Err(_)) | None => { return Ok(()); }
+        };
+        let (token, last_seen) = match hello {
+            Some(ClientFrame::Hello { version, token, last_seen }) if version == protocol::VERSION => { (token, last_seen) }
+            Some(ClientFrame::Hello { version, .. }) => {
+                let reason = format!("Protocol version {} is not supported, expected {}", version, protocol::VERSION);
+                return stream.send_json(&ServerFrame::error(None, 400, reason)).await;
+            }
+            _ => { return stream.send_json(&ServerFrame::error(None, 400, "Expected hello frame")).await; }
+        };
 
-        tide::log::debug!("Websocket: Reading first msg from websocket");
-        let first_msg = stream.next().await
-            .ok_or(tide_websockets::Error::Protocol(Cow::from("Unexpected end of stream")))??;
+        let (_, user_id, generation) = match state.parse_token(&token) {
+            Some(parsed) => { parsed }
+            None => { return stream.send_json(&ServerFrame::error(None, 401, "Token is invalid")).await; }
+        };
+        let is_token_current = || state.is_token_current(user_id, generation);
+        if !is_token_current()? {
+            return stream.send_json(&ServerFrame::error(None, 401, "Token is revoked")).await;
+        }
+        stream.send_json(&ServerFrame::Hello { version: protocol::VERSION, user_id }).await?;
+
+        tide::log::debug!("Websockets: User {} connected", user_id);
+        // subscribing before reading missed messages leaves no gap, messages
+        // both read and received are skipped
+        let subscription = state.hub.subscribe(user_id);
+        let replayed = match last_seen {
+            Some(last_seen) => { Some(replay_messages(state, &stream, user_id, last_seen).await?) }
+            None => { None }
+        };
+        let _session = state.presence.connect(user_id);
+
+        // only one is alive at a time, boxing events would not save anything
+        #[allow(clippy::large_enum_variant)]
+        enum Wake {
+            Event(Option<ServerFrame>),
+            Frame(Option<Result<tide_websockets::Message, tide_websockets::Error>>),
+            Tick
+        }
 
-        tide::log::debug!("Websockets: Received token");
-        let token: String = if let tide_websockets::Message::Text(s) = first_msg {
-            Ok(s) 
-        } else {
-            Err(tide_websockets::Error::Protocol(Cow::from("Unexpected end of stream")))
-        }?;
-
-        tide::log::debug!("Websockets: Parsing token...");
-        let (_, user_id) = State::parse_token(&token)
-            .ok_or(tide_websockets::Error::Protocol(Cow::from("Could not parse token")))?;
-
-        tide::log::debug!("Websockets: Token parsed.");
-        let (tx, rx): (mpsc::Sender<_>, mpsc::Receiver<_>) = mpsc::channel();
-        tide::log::debug!("Websockets: Pushing listener to State...");
-        { req.state()
-            .messages_txs
-            .lock()
-            .map_err(|_| tide_websockets::Error::Protocol(Cow::from("Could not lock state")))?
-            .push( (user_id,tx) ); }
-        
-
-        tide::log::debug!("Websockets: Entering loop...");
+        let mut last_checked = time::Instant::now();
         loop {
-            let rcv = rx.recv();
-            if let Ok(msg) = rcv {
-                if let Err(_) = stream.send_json(&msg).await {
+            // counted from the last check, so that steady traffic does not postpone it
+            let until_check = SESSION_CHECK_INTERVAL.checked_sub(last_checked.elapsed()).unwrap_or_default();
+            let wake = async { Wake::Event(subscription.recv().await) }
+                .race(async { Wake::Frame(stream.next().await) })
+                .race(async { async_std::task::sleep(until_check).await; Wake::Tick })
+                .await;
+            // sessions may be revoked by another process
+            if last_checked.elapsed() >= SESSION_CHECK_INTERVAL {
+                if !is_token_current()? {
                     break;
                 }
-            } else { break; }
+                last_checked = time::Instant::now();
+            }
+            let reply = match wake {
+                Wake::Event(Some(ServerFrame::Message { message })) if matches!(replayed, Some(last) if message.id <= last) => {
+                    continue;
+                }
+                Wake::Event(Some(event)) => { event }
+                // revoked or too slow
+                Wake::Event(None) => { break; }
+                Wake::Frame(Some(Ok(tide_websockets::Message::Text(text)))) => {
+                    match protocol::parse(&text) {
+                        Ok(frame) => {
+                            match state.handle_client_frame(user_id, frame) {
+                                Some(reply) => { reply }
+                                None => { continue; }
+                            }
+                        }
+                        Err(e) => { ServerFrame::error(None, 400, e) }
+                    }
+                }
+                Wake::Frame(Some(Ok(tide_websockets::Message::Binary(_)))) => {
+                    ServerFrame::error(None, 400, "Binary frames are not supported")
+                }
+                // pings are answered by the websocket library
+                Wake::Frame(Some(Ok(_))) => { continue; }
+                Wake::Frame(Some(Err(_))) | Wake::Frame(None) => { break; }
+                Wake::Tick => { continue; }
+            };
+            if stream.send_json(&reply).await.is_err() {
+                break;
+            }
+            if let ServerFrame::Message { message } = &reply {
+                state.mark_delivered(user_id, &[message.id])?;
+            }
         }
+
+        drop(subscription);
+        tide::log::debug!("Websockets: User {} disconnected", user_id);
         return Ok(());
     }));
 
-    // WIP
+    app.at("/logout").post(|req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        req.state().revoke_sessions(user_id)?;
+
+        return Ok(tide::Response::builder(200)
+            .body("Logged out")
+            .header("Set-Cookie", "token=; Max-Age=0")
+            .build());
+    });
+
+    // direct messages or ?channel= history, paginated with ?before=&after=&limit=
     app.at("/messages").get(|req: Request<State>| async move {
         // auth
-        let (user_id, _) = req.state().get_authenticated_user_id(&req)?;
-        // lock
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let query: model::HistoryRequest = req.query()?;
+        let limit = page_limit(query.limit)?;
         let repo = req.state().lock_repo()?;
-        // get
-        let messages = repo.select_messages_for_user(user_id)?;
+        let conversation = match query.channel {
+            Some(id) => { repository::Conversation::Channel(select_joined_channel(&repo, user_id, id)?.id) }
+            None => { repository::Conversation::Direct }
+        };
+        let page = repo.select_messages_for_user(user_id, conversation, query.before, query.after, limit)?;
+        drop(repo);
+        let ids: Vec<u32> = page.messages.iter().map(|m| m.id).collect();
+        req.state().mark_delivered(user_id, &ids)?;
+
+        return Ok(json!(page));
+
+    });
+
+    // ?q=&offset=&limit=
+    app.at("/messages/search").get(|req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let query: model::SearchRequest = req.query()?;
+        let limit = page_limit(query.limit)?;
+        if query.q.trim().is_empty() {
+            return Err(tide::Error::from_str(400, "Search query is empty"));
+        }
+        let page = req.state().lock_repo()?.search_messages(user_id, &query.q, query.offset.unwrap_or(0), limit)?;
 
-        return Ok(json!(messages));
+        return Ok(json!(page));
+    });
+
+    app.at("/messages/read").post(|mut req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let body: model::MarkReadRequest = req.body_json().await?;
+        req.state().mark_read(user_id, &body.message_ids)?;
 
+        return Ok(tide::Response::new(204));
     });
 
     // REST post message. TODO: Two-way auth when https is ready
     app.at("/messages").post(|mut req: Request<State>| async move {
-        let (user_id, _) = req.state().get_authenticated_user_id(&req)?;
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
 
         let body: model::PostMessageRequest = req.body_json().await?;
-        let repo = req.state().lock_repo()?;
-        let response = repo.insert_message(user_id, body)?;
+        let response = req.state().lock_repo()?.insert_message(user_id, body, &[])
+            .map_err(|e| tide::Error::new(e.status(), e))?;
 
-        req.state().broadcast_message(&response)?;
+        req.state().publish_new_message(response.clone())?;
 
         return Ok(tide::Response::builder(201)
             .body(json!(response))
             .build());
     });
 
-    app.listen("0.0.0.0:8080").await?;
+    app.at("/messages/:id").patch(|mut req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let message_id: u32 = req.param("id")?.parse()
+            .map_err(|e| tide::Error::new(400, e))?;
+
+        let body: model::EditMessageRequest = req.body_json().await?;
+        let response = req.state().lock_repo()?.edit_message(user_id, message_id, &body.text)
+            .map_err(|e| tide::Error::new(e.status(), e))?;
+
+        req.state().broadcast_message(protocol::ServerFrame::MessageEdited { message: response.clone() })?;
+        return Ok(json!(response));
+    });
+
+    app.at("/messages/scheduled").get(|req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let messages = req.state().lock_repo()?.select_scheduled_messages(user_id)?;
+        return Ok(json!(model::ScheduledList { messages }));
+    });
+
+    app.at("/messages/scheduled/:id").patch(|mut req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let message_id: u32 = req.param("id")?.parse()
+            .map_err(|e| tide::Error::new(400, e))?;
+
+        let body: model::RescheduleRequest = req.body_json().await?;
+        let response = req.state().lock_repo()?.reschedule_message(user_id, message_id, body.send_at)
+            .map_err(|e| tide::Error::new(e.status(), e))?;
+
+        req.state().wake_scheduler();
+        return Ok(json!(response));
+    });
+
+    app.at("/messages/scheduled/:id").delete(|req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let message_id: u32 = req.param("id")?.parse()
+            .map_err(|e| tide::Error::new(400, e))?;
+
+        let stored_names = req.state().lock_repo()?.cancel_message(user_id, message_id)
+            .map_err(|e| tide::Error::new(e.status(), e))?;
+        req.state().remove_files(&stored_names);
+        return Ok(tide::Response::new(204));
+    });
+
+    app.at("/messages/:id/thread").get(|req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let message_id: u32 = req.param("id")?.parse()
+            .map_err(|e| tide::Error::new(400, e))?;
+
+        let messages = req.state().lock_repo()?.select_thread(user_id, message_id)?
+            .ok_or(tide::Error::from_str(404, "Message does not exist"))?;
+        return Ok(json!(model::Thread { messages }));
+    });
+
+    app.at("/messages/:id").delete(|req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let message_id: u32 = req.param("id")?.parse()
+            .map_err(|e| tide::Error::new(400, e))?;
+
+        let response = req.state().lock_repo()?.delete_message(user_id, message_id)
+            .map_err(|e| tide::Error::new(e.status(), e))?;
+
+        req.state().broadcast_message(protocol::ServerFrame::MessageDeleted { message: response.clone() })?;
+        return Ok(json!(response));
+    });
+
+    app.at("/messages/:id/reactions").post(|mut req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let message_id: u32 = req.param("id")?.parse()
+            .map_err(|e| tide::Error::new(400, e))?;
+
+        let body: model::ReactionRequest = req.body_json().await?;
+        util::check_emoji(&body.emoji).map_err(|e| tide::Error::from_str(422, e))?;
+        let response = req.state().lock_repo()?.add_reaction(user_id, message_id, &body.emoji)
+            .map_err(|e| tide::Error::new(e.status(), e))?;
+
+        req.state().broadcast_message(protocol::ServerFrame::MessageReacted { message: response.clone() })?;
+        return Ok(json!(response));
+    });
+
+    app.at("/messages/:id/reactions").delete(|req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let message_id: u32 = req.param("id")?.parse()
+            .map_err(|e| tide::Error::new(400, e))?;
+
+        let query: model::ReactionRequest = req.query()?;
+        let response = req.state().lock_repo()?.remove_reaction(user_id, message_id, &query.emoji)
+            .map_err(|e| tide::Error::new(e.status(), e))?;
+
+        req.state().broadcast_message(protocol::ServerFrame::MessageReacted { message: response.clone() })?;
+        return Ok(json!(response));
+    });
+
+    app.at("/channels").get(|req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let channels = req.state().lock_repo()?.select_channels(user_id)?;
+        return Ok(json!(model::ChannelList { channels }));
+    });
+
+    // JSON, or a form of the page
+    app.at("/channels").post(|mut req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let is_form = is_form(&req);
+        let body: model::CreateChannelRequest = if is_form { req.body_form().await? } else { req.body_json().await? };
+        let channel = req.state().lock_repo()?.create_channel(user_id, &body.name)
+            .map_err(|e| tide::Error::new(e.status(), e))?;
+
+        if is_form {
+            return Ok(channel_changed(&req, &format!("/?channel={}", channel.id)));
+        }
+        return Ok(tide::Response::builder(201)
+            .body(json!(channel))
+            .build());
+    });
+
+    app.at("/channels/:id/join").post(|req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let channel_id: u32 = req.param("id")?.parse()
+            .map_err(|e| tide::Error::new(400, e))?;
+
+        req.state().lock_repo()?.join_channel(user_id, channel_id)
+            .map_err(|e| tide::Error::new(e.status(), e))?;
+        return Ok(channel_changed(&req, &format!("/?channel={}", channel_id)));
+    });
+
+    app.at("/channels/:id/leave").post(|req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let channel_id: u32 = req.param("id")?.parse()
+            .map_err(|e| tide::Error::new(400, e))?;
+
+        req.state().lock_repo()?.leave_channel(user_id, channel_id)
+            .map_err(|e| tide::Error::new(e.status(), e))?;
+        return Ok(channel_changed(&req, "/"));
+    });
+
+    // live connection counts
+    app.at("/status").get(|req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let hub = &req.state().hub;
+        return Ok(json!({
+            "connections": hub.subscriber_count(),
+            "user_connections": hub.user_subscriber_count(user_id)
+        }));
+    });
+
+    // attachments
+    app.at("/files/:id").get(|req: Request<State>| async move {
+        let (user_id, _) = req.state().get_authenticated_user_id(&req).await?;
+        let file_id: u32 = req.param("id")?.parse()
+            .map_err(|e| tide::Error::new(400, e))?;
+
+        let file = req.state().lock_repo()?
+            .select_file_for_user(file_id, user_id)?
+            .ok_or(tide::Error::from_str(404, "File not found"))?;
+
+        let mut body = tide::Body::from_file(req.state().config.files_dir.join(&file.stored_name)).await
+            .map_err(|e| tide::Error::new(404, e))?;
+        body.set_mime(Path::new(&file.original_name)
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(tide::http::Mime::from_extension)
+            .unwrap_or(tide::http::mime::BYTE_STREAM));
+
+        return Ok(tide::Response::builder(200)
+            .body(body)
+            .header("Content-Disposition", util::content_disposition(&file.original_name))
+            .build());
+    });
+
+    app.listen(listen).await?;
     Ok(())
 }
 
 
 
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> State {
+        let repo = repository::Repo::new(Path::new(":memory:")).unwrap();
+        repo.conn.execute_batch("
+            INSERT INTO users (username, password, color, name) VALUES ('alice', '', 'red', 'Alice A');
+        ").unwrap();
+        let repo = Arc::new(Mutex::new(repo));
+        let hub = Arc::new(hub::Hub::new(4));
+        return State {
+            config: Arc::new(config::Config { secret: "0123456789abcdef".to_owned(), ..config::Config::default() }),
+            passwords: password::Hasher::new(1024, 1, 1).unwrap(),
+            repo: repo.clone(),
+            view: Arc::new(view::View { tera: tera::Tera::default() }),
+            hub: hub.clone(),
+            presence: Arc::new(presence::Presence::new(hub, repo, OFFLINE_DELAY)),
+            scheduler: async_std::channel::bounded(1).0,
+            purger: async_std::channel::bounded(1).0
+        };
+    }
+
+    fn in_an_hour() -> u64 {
+        return (time::SystemTime::now() + time::Duration::from_secs(3600))
+            .duration_since(time::UNIX_EPOCH).unwrap().as_secs();
+    }
+
+    /// Signs a token body the way `create_token` does
+    fn sign(state: &State, token_a: &str) -> String {
+        let token_b = blake3::hash(format!("{}{}", token_a, state.config.secret).as_bytes()).to_hex();
+        return format!("{}.{}", token_a, token_b);
+    }
+
+    #[test]
+    fn parses_own_tokens() {
+        let state = state();
+        let token = state.create_token("al:ice".to_owned(), 1, 7, in_an_hour());
+        assert_eq!(state.parse_token(&token), Some(("al:ice".to_owned(), 1, 7)));
+
+        let expired = state.create_token("alice".to_owned(), 1, 7, 1);
+        assert_eq!(state.parse_token(&expired), None);
+
+        let (token_a, _) = token.split_at(token.find('.').unwrap());
+        assert_eq!(state.parse_token(&format!("{}.{}", token_a, "0".repeat(64))), None);
+    }
+
+    #[test]
+    fn rejects_tokens_without_generation() {
+        let state = state();
+        let old = sign(&state, &base64::encode(format!("alice:1:{}", in_an_hour())));
+        assert_eq!(state.parse_token(&old), None);
+    }
+
+    #[test]
+    fn revoked_tokens_are_not_current() {
+        let state = state();
+        let generation = state.lock_repo().unwrap().select_token_generation(1).unwrap().unwrap();
+        let token = state.create_token("alice".to_owned(), 1, generation, in_an_hour());
+        let (_, id, token_generation) = state.parse_token(&token).unwrap();
+        assert!(state.is_token_current(id, token_generation).unwrap());
+
+        let _subscription = state.hub.subscribe(1);
+        state.revoke_sessions(1).unwrap();
+        assert!(!state.is_token_current(id, token_generation).unwrap());
+        assert_eq!(state.hub.subscriber_count(), 0);
+
+        let token = state.create_token("alice".to_owned(), 1, generation + 1, in_an_hour());
+        let (_, id, token_generation) = state.parse_token(&token).unwrap();
+        assert!(state.is_token_current(id, token_generation).unwrap());
+    }
+}
@@ -0,0 +1,265 @@
+//! Renders the Markdown subset of messages into HTML.
+//!
+//! Paragraphs, line breaks, `inline` and fenced code, **bold**, *italic*, block
+//! quotes, http(s) URLs written out in the text and @mentions of users are
+//! supported, anything else is shown as typed. All text is escaped and only the
+//! tags produced here get into the output, so it can be put into a page as is.
+
+use std::collections::HashMap;
+use regex::Regex;
+use lazy_static::lazy_static;
+
+lazy_static!{
+    static ref URL: Regex = Regex::new(r#"https?://[^\s<>"'`]+"#).unwrap();
+    // not inside of words, like in e-mail addresses
+    static ref MENTION: Regex = Regex::new(r"(?:^|[^\w@])@([A-Za-z0-9._-]+)").unwrap();
+    static ref STRONG: Regex = Regex::new(r"\*\*([^\s*](?:[^*]*[^\s*])?)\*\*").unwrap();
+    static ref EMPHASIS: Regex = Regex::new(r"\*([^\s*](?:[^*]*[^\s*])?)\*").unwrap();
+    // not inside of words, like in snake_case_names
+    static ref UNDERSCORE_EMPHASIS: Regex = Regex::new(r"\b_([^\s_](?:[^_]*[^\s_])?)_\b").unwrap();
+}
+
+const FENCE: &str = "```";
+
+pub struct Rendered {
+    pub html: String,
+    /// Users mentioned outside of code, each once
+    pub mentions: Vec<u32>
+}
+
+/// State of rendering one text
+struct Renderer<'a> {
+    /// Ids of users that can be mentioned by lowercase username
+    users: &'a HashMap<String, u32>,
+    mentions: Vec<u32>
+}
+
+/// Renders a text, `@username` of `users` is a mention. They are keyed by lowercase username.
+pub fn render(text: &str, users: &HashMap<String, u32>) -> Rendered {
+    let lines: Vec<&str> = text.lines().map(str::trim_end).collect();
+    let mut html = String::new();
+    let mut renderer = Renderer { users, mentions: Vec::new() };
+    renderer.render_blocks(&lines, &mut html);
+    return Rendered { html, mentions: renderer.mentions };
+}
+
+impl Renderer<'_> {
+    fn render_blocks(&mut self, lines: &[&str], html: &mut String) {
+        let mut paragraph: Vec<&str> = Vec::new();
+        let mut i = 0;
+        while i < lines.len() {
+            let line = lines[i];
+            if line.trim_start().starts_with(FENCE) {
+                self.render_paragraph(&mut paragraph, html);
+                // an unclosed fence runs to the end
+                let end = lines[i + 1..].iter().position(|l| l.trim() == FENCE)
+                    .map(|n| i + 1 + n)
+                    .unwrap_or(lines.len());
+                html.push_str("<pre><code>");
+                html.push_str(&escape(&lines[i + 1..end].join("\n")));
+                html.push_str("</code></pre>");
+                i = end + 1;
+            } else if line.trim_start().starts_with('>') {
+                self.render_paragraph(&mut paragraph, html);
+                let quoted: Vec<&str> = lines[i..].iter()
+                    .take_while(|l| l.trim_start().starts_with('>'))
+                    .map(|l| {
+                        let l = &l.trim_start()[1..];
+                        return l.strip_prefix(' ').unwrap_or(l);
+                    })
+                    .collect();
+                i += quoted.len();
+                html.push_str("<blockquote>");
+                self.render_blocks(&quoted, html);
+                html.push_str("</blockquote>");
+            } else if line.trim().is_empty() {
+                self.render_paragraph(&mut paragraph, html);
+                i += 1;
+            } else {
+                paragraph.push(line);
+                i += 1;
+            }
+        }
+        self.render_paragraph(&mut paragraph, html);
+    }
+
+    /// Renders collected lines as a paragraph and clears them
+    fn render_paragraph(&mut self, lines: &mut Vec<&str>, html: &mut String) {
+        if lines.is_empty() {
+            return;
+        }
+        html.push_str("<p>");
+        let rendered: Vec<String> = lines.iter().map(|l| self.render_inline(l)).collect();
+        html.push_str(&rendered.join("<br>"));
+        html.push_str("</p>");
+        lines.clear();
+    }
+
+    /// Renders code spans, the rest is formatted by `render_text`
+    fn render_inline(&mut self, line: &str) -> String {
+        let mut html = String::new();
+        let mut rest = line;
+        while let Some(start) = rest.find('`') {
+            let ticks = rest[start..].len() - rest[start..].trim_start_matches('`').len();
+            let after = &rest[start + ticks..];
+            // a span ends with the same number of backticks it starts with
+            let end = after.match_indices(&"`".repeat(ticks))
+                .map(|(n, _)| n)
+                .find(|n| !after[n + ticks..].starts_with('`') && (*n == 0 || !after[..*n].ends_with('`')));
+            match end {
+                Some(end) => {
+                    html.push_str(&self.render_text(&rest[..start]));
+                    html.push_str("<code>");
+                    html.push_str(&escape(after[..end].trim()));
+                    html.push_str("</code>");
+                    rest = &after[end + ticks..];
+                }
+                None => {
+                    html.push_str(&self.render_text(&rest[..start + ticks]));
+                    rest = after;
+                }
+            }
+        }
+        html.push_str(&self.render_text(rest));
+        return html;
+    }
+
+    /// Renders links, and mentions and emphasis between them
+    fn render_text(&mut self, text: &str) -> String {
+        let mut html = String::new();
+        let mut last = 0;
+        for found in URL.find_iter(text) {
+            let url = trim_url(found.as_str());
+            html.push_str(&self.render_mentions(&text[last..found.start()]));
+            html.push_str(&format!("<a href=\"{0}\" rel=\"nofollow noopener noreferrer\" target=\"_blank\">{0}</a>", escape(url)));
+            last = found.start() + url.len();
+        }
+        html.push_str(&self.render_mentions(&text[last..]));
+        return html;
+    }
+
+    /// Renders mentions of known users, and emphasis between them
+    fn render_mentions(&mut self, text: &str) -> String {
+        let mut html = String::new();
+        let mut last = 0;
+        for found in MENTION.captures_iter(text) {
+            let name = found.get(1).unwrap();
+            // a sentence may end right after the name
+            let username = name.as_str().trim_end_matches('.');
+            let user_id = match self.users.get(&username.to_ascii_lowercase()) {
+                Some(user_id) => { *user_id }
+                None => { continue; }
+            };
+            let at = name.start() - 1;
+            html.push_str(&emphasize(&escape(&text[last..at])));
+            html.push_str(&format!("<span class=\"mention\" data-user-id=\"{}\">@{}</span>", user_id, escape(username)));
+            last = name.start() + username.len();
+            if !self.mentions.contains(&user_id) {
+                self.mentions.push(user_id);
+            }
+        }
+        html.push_str(&emphasize(&escape(&text[last..])));
+        return html;
+    }
+}
+
+/// Leaves out punctuation ending a sentence, and a closing bracket without an opening one
+fn trim_url(url: &str) -> &str {
+    let mut url = url.trim_end_matches(&['.', ',', ';', ':', '!', '?'][..]);
+    while url.ends_with(')') && url.matches(')').count() > url.matches('(').count() {
+        url = url[..url.len() - 1].trim_end_matches(&['.', ',', ';', ':', '!', '?'][..]);
+    }
+    return url;
+}
+
+/// Turns markers into tags in escaped text
+fn emphasize(html: &str) -> String {
+    let html = STRONG.replace_all(html, "<strong>$1</strong>");
+    let html = EMPHASIS.replace_all(&html, "<em>$1</em>");
+    return UNDERSCORE_EMPHASIS.replace_all(&html, "<em>$1</em>").into_owned();
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => { escaped.push_str("&amp;"); }
+            '<' => { escaped.push_str("&lt;"); }
+            '>' => { escaped.push_str("&gt;"); }
+            '"' => { escaped.push_str("&quot;"); }
+            '\'' => { escaped.push_str("&#39;"); }
+            _ => { escaped.push(c); }
+        }
+    }
+    return escaped;
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(text: &str) -> String {
+        return super::render(text, &HashMap::new()).html;
+    }
+
+    #[test]
+    fn renders_paragraphs_and_line_breaks() {
+        assert_eq!(render("hello"), "<p>hello</p>");
+        assert_eq!(render("one\ntwo\r\n\n\nthree"), "<p>one<br>two</p><p>three</p>");
+        assert_eq!(render(""), "");
+    }
+
+    #[test]
+    fn renders_code() {
+        assert_eq!(render("run `cargo test` now"), "<p>run <code>cargo test</code> now</p>");
+        assert_eq!(render("``a ` b`` and `*c*`"), "<p><code>a ` b</code> and <code>*c*</code></p>");
+        assert_eq!(render("a ` alone"), "<p>a ` alone</p>");
+        assert_eq!(render("```\nfn main() {\n    **x** < 1\n}\n```\nafter"),
+            "<pre><code>fn main() {\n    **x** &lt; 1\n}</code></pre><p>after</p>");
+        assert_eq!(render("```rust\nunclosed"), "<pre><code>unclosed</code></pre>");
+    }
+
+    #[test]
+    fn renders_emphasis() {
+        assert_eq!(render("**bold** and *italic* and _also_"),
+            "<p><strong>bold</strong> and <em>italic</em> and <em>also</em></p>");
+        assert_eq!(render("2 * 3 * 4 and snake_case_name"), "<p>2 * 3 * 4 and snake_case_name</p>");
+    }
+
+    #[test]
+    fn renders_block_quotes() {
+        assert_eq!(render("> quoted\n>> nested\n\nanswer"),
+            "<blockquote><p>quoted</p><blockquote><p>nested</p></blockquote></blockquote><p>answer</p>");
+    }
+
+    #[test]
+    fn links_urls() {
+        assert_eq!(render("see https://example.com/a?b=1&c=2."),
+            "<p>see <a href=\"https://example.com/a?b=1&amp;c=2\" rel=\"nofollow noopener noreferrer\" \
+            target=\"_blank\">https://example.com/a?b=1&amp;c=2</a>.</p>");
+        assert!(render("(https://en.wikipedia.org/wiki/Rust_(programming_language))")
+            .contains(">https://en.wikipedia.org/wiki/Rust_(programming_language)</a>)"));
+        assert_eq!(render("javascript:alert(1)"), "<p>javascript:alert(1)</p>");
+    }
+
+    #[test]
+    fn renders_mentions_of_known_users() {
+        let users: HashMap<String, u32> = vec![("bob".to_owned(), 2), ("carol.c".to_owned(), 3)].into_iter().collect();
+        let rendered = super::render("@Bob and @carol.c. and @nobody, not `@bob` or bob@bob.org; @bob again", &users);
+        assert_eq!(rendered.html, "<p><span class=\"mention\" data-user-id=\"2\">@Bob</span> and \
+            <span class=\"mention\" data-user-id=\"3\">@carol.c</span>. and @nobody, not <code>@bob</code> \
+            or bob@bob.org; <span class=\"mention\" data-user-id=\"2\">@bob</span> again</p>");
+        assert_eq!(rendered.mentions, vec![2, 3]);
+        assert!(super::render("`@bob`", &users).mentions.is_empty());
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(render("<script>alert('x')</script>"), "<p>&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;</p>");
+        assert_eq!(render("https://x.org/\"onmouseover=\"alert(1)"),
+            "<p><a href=\"https://x.org/\" rel=\"nofollow noopener noreferrer\" target=\"_blank\">https://x.org/</a>\
+            &quot;onmouseover=&quot;alert(1)</p>");
+        assert_eq!(render("**<b>**"), "<p><strong>&lt;b&gt;</strong></p>");
+    }
+}
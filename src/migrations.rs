@@ -0,0 +1,583 @@
+//! Versioned schema migrations.
+//!
+//! Schema version is kept in `PRAGMA user_version`. Each migration brings the
+//! database to the version equal to its position in `MIGRATIONS` plus one, and
+//! is applied in its own transaction. New migrations are only ever appended.
+
+use std::fmt;
+use rusqlite::{Connection, Transaction, NO_PARAMS};
+
+struct Migration {
+    description: &'static str,
+    apply: fn(&Transaction) -> rusqlite::Result<()>,
+    /// Foreign keys of the old schema reference ROWID and can not be checked
+    check_foreign_keys: bool
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration { description: "initial schema", apply: initial_schema, check_foreign_keys: false },
+    Migration { description: "primary keys and enforceable foreign keys", apply: primary_keys, check_foreign_keys: true },
+    Migration { description: "delivery and read receipts", apply: receipts, check_foreign_keys: true },
+    Migration { description: "message edits and deletion", apply: revisions, check_foreign_keys: true },
+    Migration { description: "replies", apply: replies, check_foreign_keys: true },
+    Migration { description: "channels", apply: channels, check_foreign_keys: true },
+    Migration { description: "full-text search", apply: search, check_foreign_keys: true },
+    Migration { description: "channels outliving their creators", apply: channel_creators, check_foreign_keys: true },
+    Migration { description: "invites and unique usernames", apply: invites, check_foreign_keys: true },
+    Migration { description: "last seen times", apply: last_seen, check_foreign_keys: false },
+    Migration { description: "formatted message texts", apply: message_html, check_foreign_keys: false },
+    Migration { description: "mentions", apply: mentions, check_foreign_keys: true },
+    Migration { description: "reactions", apply: reactions, check_foreign_keys: true },
+    Migration { description: "scheduled messages", apply: scheduled_messages, check_foreign_keys: false },
+    Migration { description: "retention", apply: retention, check_foreign_keys: false }
+];
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Database(rusqlite::Error),
+    /// Database was written by a newer version of the server
+    TooNew { database: u32, supported: u32 },
+    /// Data violates foreign keys after applying the migration
+    ForeignKeys { version: u32, violations: usize }
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Database(e) => write!(f, "{}", e),
+            MigrationError::TooNew { database, supported } => write!(f,
+                "Database schema version {} is newer than the latest supported version {}. \
+                 Please upgrade the server.", database, supported),
+            MigrationError::ForeignKeys { version, violations } => write!(f,
+                "Migration to version {} left {} foreign key violations", version, violations)
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<rusqlite::Error> for MigrationError {
+    fn from(e: rusqlite::Error) -> Self {
+        return MigrationError::Database(e);
+    }
+}
+
+pub fn latest_version() -> u32 {
+    return MIGRATIONS.len() as u32;
+}
+
+pub fn schema_version(conn: &Connection) -> rusqlite::Result<u32> {
+    return conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0));
+}
+
+/// Applies pending migrations and enables foreign key enforcement
+pub fn migrate(conn: &mut Connection) -> Result<(), MigrationError> {
+    // can not be changed inside of a transaction, and tables are rebuilt below
+    conn.execute_batch("PRAGMA foreign_keys = OFF")?;
+
+    let current = schema_version(conn)?;
+    if current > latest_version() {
+        return Err(MigrationError::TooNew { database: current, supported: latest_version() });
+    }
+
+    for (version, migration) in (1..).zip(MIGRATIONS.iter()).skip(current as usize) {
+        let tx = conn.transaction()?;
+        (migration.apply)(&tx)?;
+
+        if migration.check_foreign_keys {
+            let violations = tx.prepare("PRAGMA foreign_key_check")?
+                .query_map(NO_PARAMS, |_| Ok(()))?
+                .count();
+            if violations > 0 {
+                return Err(MigrationError::ForeignKeys { version, violations });
+            }
+        }
+
+        tx.execute_batch(&format!("PRAGMA user_version = {}", version))?;
+        tx.commit()?;
+        tide::log::info!("Migrated database to version {}: {}", version, migration.description);
+    }
+
+    conn.execute_batch("PRAGMA foreign_keys = ON")?;
+    return Ok(());
+}
+
+/// Schema as it was created before migrations existed. Tables may already be there.
+fn initial_schema(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("
+        CREATE TABLE IF NOT EXISTS users (
+            username TEXT NOT NULL,
+            password TEXT NOT NULL,
+            color TEXT NOT NULL,
+            name TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS messages (
+            text TEXT NOT NULL,
+            user_id INTEGER NOT NULL,
+            timestamp INTEGER NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users(ROWID)
+        );
+        CREATE TABLE IF NOT EXISTS message_recipients (
+            user_id INTEGER NOT NULL,
+            message_id INTEGER NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users(ROWID),
+            FOREIGN KEY (message_id) REFERENCES messages(ROWID)
+        );
+        CREATE TABLE IF NOT EXISTS files (
+            original_name TEXT_NOT_NULL,
+            stored_name TEXT NOT NULL,
+            owner_id INTEGER NOT NULL,
+            is_deleted INTEGER NOT NULL,
+            FOREIGN KEY (owner_id) REFERENCES users(ROWID)
+        );
+        CREATE TABLE IF NOT EXISTS message_files (
+            file_id INTEGER NOT NULL,
+            message_id INTEGER NOT NULL,
+            FOREIGN KEY (file_id) REFERENCES files(ROWID),
+            FOREIGN KEY (message_id) REFERENCES messages(ROWID)
+        );
+    ")?;
+
+    let has_token_generation = tx.prepare("
+        SELECT 1 FROM pragma_table_info('users') WHERE name = 'token_generation'
+    ")?.exists(NO_PARAMS)?;
+    if !has_token_generation {
+        tx.execute_batch("
+            ALTER TABLE users ADD COLUMN token_generation INTEGER NOT NULL DEFAULT 0
+        ")?;
+    }
+    return Ok(());
+}
+
+/// Describes rows that `primary_keys` drops because they point to missing
+/// users, messages or files, directly or through a dropped row.
+fn orphans(tx: &Transaction) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = tx.prepare("
+        WITH
+            valid_messages AS (SELECT m.ROWID AS id FROM messages m JOIN users u ON u.ROWID = m.user_id),
+            valid_files AS (SELECT f.ROWID AS id FROM files f JOIN users u ON u.ROWID = f.owner_id)
+        SELECT 'message ' || ROWID || ' of missing user ' || user_id || ': ' || quote(text)
+            FROM messages WHERE ROWID NOT IN valid_messages
+        UNION ALL
+        SELECT 'recipient ' || user_id || ' of message ' || message_id
+            FROM message_recipients
+            WHERE user_id NOT IN (SELECT ROWID FROM users) OR message_id NOT IN valid_messages
+        UNION ALL
+        SELECT 'file ' || ROWID || ' ' || quote(original_name) || ' of missing user ' || owner_id
+                || ', stored as ' || stored_name
+            FROM files WHERE ROWID NOT IN valid_files
+        UNION ALL
+        SELECT 'attachment of file ' || file_id || ' to message ' || message_id
+            FROM message_files
+            WHERE file_id NOT IN valid_files OR message_id NOT IN valid_messages
+    ")?;
+    return stmt.query_map(NO_PARAMS, |row| row.get(0))?.collect();
+}
+
+/// Foreign keys can not reference ROWID, so every table gets an `id` column
+/// aliasing it. Rows pointing to missing parents can not be kept and are
+/// dropped, each of them is logged.
+fn primary_keys(tx: &Transaction) -> rusqlite::Result<()> {
+    let orphans = orphans(tx)?;
+    for orphan in orphans.iter() {
+        tide::log::warn!("Dropping {}", orphan);
+    }
+    if !orphans.is_empty() {
+        tide::log::warn!("Dropped {} rows referencing missing users, messages or files. \
+            Stored files of dropped files are left in the files directory.", orphans.len());
+    }
+
+    return tx.execute_batch("
+        CREATE TABLE users_new (
+            id INTEGER PRIMARY KEY,
+            username TEXT NOT NULL,
+            password TEXT NOT NULL,
+            color TEXT NOT NULL,
+            name TEXT NOT NULL,
+            token_generation INTEGER NOT NULL DEFAULT 0
+        );
+        INSERT INTO users_new (id, username, password, color, name, token_generation)
+            SELECT ROWID, username, password, color, name, token_generation FROM users;
+        DROP TABLE users;
+        ALTER TABLE users_new RENAME TO users;
+
+        CREATE TABLE messages_new (
+            id INTEGER PRIMARY KEY,
+            text TEXT NOT NULL,
+            user_id INTEGER NOT NULL REFERENCES users(id),
+            timestamp INTEGER NOT NULL
+        );
+        INSERT INTO messages_new (id, text, user_id, timestamp)
+            SELECT ROWID, text, user_id, timestamp FROM messages
+            WHERE user_id IN (SELECT id FROM users);
+        DROP TABLE messages;
+        ALTER TABLE messages_new RENAME TO messages;
+        CREATE INDEX messages_user_id ON messages (user_id);
+
+        CREATE TABLE message_recipients_new (
+            user_id INTEGER NOT NULL REFERENCES users(id),
+            message_id INTEGER NOT NULL REFERENCES messages(id),
+            PRIMARY KEY (message_id, user_id)
+        );
+        INSERT OR IGNORE INTO message_recipients_new (user_id, message_id)
+            SELECT user_id, message_id FROM message_recipients
+            WHERE user_id IN (SELECT id FROM users) AND message_id IN (SELECT id FROM messages);
+        DROP TABLE message_recipients;
+        ALTER TABLE message_recipients_new RENAME TO message_recipients;
+        CREATE INDEX message_recipients_user_id ON message_recipients (user_id, message_id);
+
+        CREATE TABLE files_new (
+            id INTEGER PRIMARY KEY,
+            original_name TEXT NOT NULL,
+            stored_name TEXT NOT NULL,
+            owner_id INTEGER NOT NULL REFERENCES users(id),
+            is_deleted INTEGER NOT NULL DEFAULT 0
+        );
+        INSERT INTO files_new (id, original_name, stored_name, owner_id, is_deleted)
+            SELECT ROWID, coalesce(original_name, stored_name), stored_name, owner_id, is_deleted FROM files
+            WHERE owner_id IN (SELECT id FROM users);
+        DROP TABLE files;
+        ALTER TABLE files_new RENAME TO files;
+
+        CREATE TABLE message_files_new (
+            file_id INTEGER NOT NULL REFERENCES files(id),
+            message_id INTEGER NOT NULL REFERENCES messages(id),
+            PRIMARY KEY (message_id, file_id)
+        );
+        INSERT OR IGNORE INTO message_files_new (file_id, message_id)
+            SELECT file_id, message_id FROM message_files
+            WHERE file_id IN (SELECT id FROM files) AND message_id IN (SELECT id FROM messages);
+        DROP TABLE message_files;
+        ALTER TABLE message_files_new RENAME TO message_files;
+    ");
+}
+
+/// Times a recipient got and read a message, unknown for older messages
+fn receipts(tx: &Transaction) -> rusqlite::Result<()> {
+    return tx.execute_batch("
+        ALTER TABLE message_recipients ADD COLUMN delivered_at INTEGER;
+        ALTER TABLE message_recipients ADD COLUMN read_at INTEGER;
+    ");
+}
+
+/// Texts messages had before being edited or deleted
+fn revisions(tx: &Transaction) -> rusqlite::Result<()> {
+    return tx.execute_batch("
+        ALTER TABLE messages ADD COLUMN edited_at INTEGER;
+        ALTER TABLE messages ADD COLUMN deleted_at INTEGER;
+        CREATE TABLE message_revisions (
+            id INTEGER PRIMARY KEY,
+            message_id INTEGER NOT NULL REFERENCES messages(id),
+            text TEXT NOT NULL,
+            edited_at INTEGER,
+            replaced_at INTEGER NOT NULL
+        );
+        CREATE INDEX message_revisions_message_id ON message_revisions (message_id);
+    ");
+}
+
+fn replies(tx: &Transaction) -> rusqlite::Result<()> {
+    return tx.execute_batch("
+        ALTER TABLE messages ADD COLUMN reply_to INTEGER REFERENCES messages(id);
+        CREATE INDEX messages_reply_to ON messages (reply_to);
+    ");
+}
+
+/// Named group conversations. Messages sent to a channel also get a recipient
+/// row for each member at the time of sending, for receipts.
+fn channels(tx: &Transaction) -> rusqlite::Result<()> {
+    return tx.execute_batch("
+        CREATE TABLE channels (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            created_by INTEGER NOT NULL REFERENCES users(id),
+            created_at INTEGER NOT NULL
+        );
+        CREATE UNIQUE INDEX channels_name ON channels (lower(name));
+        CREATE TABLE channel_members (
+            channel_id INTEGER NOT NULL REFERENCES channels(id),
+            user_id INTEGER NOT NULL REFERENCES users(id),
+            joined_at INTEGER NOT NULL,
+            PRIMARY KEY (channel_id, user_id)
+        );
+        CREATE INDEX channel_members_user_id ON channel_members (user_id, channel_id);
+        ALTER TABLE messages ADD COLUMN channel_id INTEGER REFERENCES channels(id);
+        CREATE INDEX messages_channel_id ON messages (channel_id, id);
+    ");
+}
+
+/// Index of message texts, kept in sync with `messages` by triggers
+fn search(tx: &Transaction) -> rusqlite::Result<()> {
+    return tx.execute_batch("
+        CREATE VIRTUAL TABLE messages_fts USING fts5 (text, content = 'messages', content_rowid = 'id');
+        INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');
+        CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+            INSERT INTO messages_fts (rowid, text) VALUES (new.id, new.text);
+        END;
+        CREATE TRIGGER messages_fts_update AFTER UPDATE OF text ON messages BEGIN
+            INSERT INTO messages_fts (messages_fts, rowid, text) VALUES ('delete', old.id, old.text);
+            INSERT INTO messages_fts (rowid, text) VALUES (new.id, new.text);
+        END;
+        CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+            INSERT INTO messages_fts (messages_fts, rowid, text) VALUES ('delete', old.id, old.text);
+        END;
+    ");
+}
+
+/// Creator of a channel becomes optional, so that their account can be deleted
+fn channel_creators(tx: &Transaction) -> rusqlite::Result<()> {
+    return tx.execute_batch("
+        CREATE TABLE channels_new (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            created_by INTEGER REFERENCES users(id),
+            created_at INTEGER NOT NULL
+        );
+        INSERT INTO channels_new (id, name, created_by, created_at)
+            SELECT id, name, created_by, created_at FROM channels;
+        DROP TABLE channels;
+        ALTER TABLE channels_new RENAME TO channels;
+        CREATE UNIQUE INDEX channels_name ON channels (lower(name));
+    ");
+}
+
+/// Registration with invite codes. Usernames become unique regardless of
+/// case; later duplicates are renamed. Passwords are no longer set by the
+/// first login, users without one are logged.
+fn invites(tx: &Transaction) -> rusqlite::Result<()> {
+    let duplicates = tx.prepare("
+        SELECT id, username FROM users u
+        WHERE EXISTS (SELECT 1 FROM users WHERE lower(username) = lower(u.username) AND id < u.id)
+    ")?.query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<(u32, String)>>>()?;
+    for (id, username) in duplicates {
+        let renamed = format!("{}_{}", username, id);
+        tide::log::warn!("Renaming user {} from {} to {}, the username is taken", id, username, renamed);
+        tx.execute("UPDATE users SET username = ?2 WHERE id = ?1", rusqlite::params![ id, renamed ])?;
+    }
+
+    let unregistered = tx.prepare("SELECT username FROM users WHERE password = ''")?
+        .query_map(NO_PARAMS, |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    for username in unregistered {
+        tide::log::warn!("User {} has no password and can not log in. \
+            Set one with `localpost-server user reset-password {}`.", username, username);
+    }
+
+    return tx.execute_batch("
+        CREATE UNIQUE INDEX users_username ON users (lower(username));
+        CREATE TABLE invites (
+            id INTEGER PRIMARY KEY,
+            code TEXT NOT NULL UNIQUE,
+            created_at INTEGER NOT NULL,
+            expires_at INTEGER NOT NULL,
+            max_uses INTEGER NOT NULL,
+            uses INTEGER NOT NULL DEFAULT 0
+        );
+        ALTER TABLE users ADD COLUMN invite_id INTEGER REFERENCES invites(id);
+    ");
+}
+
+fn last_seen(tx: &Transaction) -> rusqlite::Result<()> {
+    return tx.execute_batch("ALTER TABLE users ADD COLUMN last_seen_at INTEGER");
+}
+
+/// Text rendered from Markdown, stored next to the typed one
+fn message_html(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("ALTER TABLE messages ADD COLUMN html TEXT NOT NULL DEFAULT ''")?;
+    let texts = tx.prepare("SELECT id, text FROM messages WHERE text != ''")?
+        .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<(u32, String)>>>()?;
+    let mut update = tx.prepare("UPDATE messages SET html = ?2 WHERE id = ?1")?;
+    // A deliberate exception to migrations giving the same result once written:
+    // the backfill uses the current renderer, so a database upgraded later gets
+    // the formatting of the version it is upgraded by, the same as messages sent
+    // with that version. Keeping an old copy of the renderer would not be better.
+    for (id, text) in texts {
+        update.execute(rusqlite::params![ id, crate::markdown::render(&text, &Default::default()).html ])?;
+    }
+    return Ok(());
+}
+
+
+fn mentions(tx: &Transaction) -> rusqlite::Result<()> {
+    return tx.execute_batch("
+        CREATE TABLE message_mentions (
+            message_id INTEGER NOT NULL REFERENCES messages(id),
+            user_id INTEGER NOT NULL REFERENCES users(id),
+            PRIMARY KEY (message_id, user_id)
+        );
+        CREATE INDEX message_mentions_user ON message_mentions (user_id);
+    ");
+}
+
+fn reactions(tx: &Transaction) -> rusqlite::Result<()> {
+    return tx.execute_batch("
+        CREATE TABLE message_reactions (
+            message_id INTEGER NOT NULL REFERENCES messages(id),
+            user_id INTEGER NOT NULL REFERENCES users(id),
+            emoji TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            PRIMARY KEY (message_id, user_id, emoji)
+        );
+        CREATE INDEX message_reactions_user ON message_reactions (user_id);
+    ");
+}
+
+/// Time a message waits for, it is hidden from everyone until then
+fn scheduled_messages(tx: &Transaction) -> rusqlite::Result<()> {
+    return tx.execute_batch("
+        ALTER TABLE messages ADD COLUMN send_at INTEGER;
+        CREATE INDEX messages_send_at ON messages (send_at) WHERE send_at IS NOT NULL;
+    ");
+}
+
+/// Self-destruct timers of messages and retention policies of channels,
+/// which take the global ones when NULL
+fn retention(tx: &Transaction) -> rusqlite::Result<()> {
+    return tx.execute_batch("
+        ALTER TABLE messages ADD COLUMN destruct_after INTEGER;
+        CREATE INDEX messages_destruct_at ON messages (timestamp + destruct_after) WHERE destruct_after IS NOT NULL;
+        CREATE INDEX messages_timestamp ON messages (timestamp);
+        ALTER TABLE channels ADD COLUMN retention_days INTEGER;
+        ALTER TABLE channels ADD COLUMN retention_keep_last INTEGER;
+    ");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::{Conversation, Repo};
+    use crate::model as m;
+
+    /// Database as created by `Repo::new` with attachments and token generations,
+    /// before migrations were introduced
+    fn fixture() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("migrations/fixtures/unversioned.sql")).unwrap();
+        return conn;
+    }
+
+    /// Database as created by the first released version
+    fn baseline() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("migrations/fixtures/baseline.sql")).unwrap();
+        return conn;
+    }
+
+    /// Rows that the second migration is going to drop
+    fn pending_orphans(conn: &mut Connection) -> Vec<String> {
+        let tx = conn.transaction().unwrap();
+        initial_schema(&tx).unwrap();
+        return orphans(&tx).unwrap();
+    }
+
+    #[test]
+    fn upgrades_baseline_database() {
+        let mut conn = baseline();
+        assert_eq!(pending_orphans(&mut conn), vec![
+            "message 2 of missing user 3: 'x'",
+            "recipient 2 of message 2"
+        ]);
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+
+        let repo = Repo { conn };
+        assert_eq!(repo.select_token_generation(1).unwrap(), Some(0));
+        assert_eq!(repo.select_token_generation(2).unwrap(), Some(0));
+        let alice = repo.select_user_credentials("Alice").unwrap().unwrap();
+        assert_eq!(alice.password, blake3::hash(b"secret").to_hex().as_str());
+
+        let messages = repo.select_messages_for_user(2, Conversation::All, None, None, 10).unwrap().messages;
+        assert_eq!(messages.iter().map(|m| m.id).collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(messages[0].text, "hello bob");
+        assert_eq!(messages[0].html, "<p>hello bob</p>");
+        // messages from before the search index are found
+        assert_eq!(repo.search_messages(2, "bob", 0, 10).unwrap().results.len(), 1);
+        assert!(messages[0].attachments.is_empty());
+
+        let message = repo.insert_message(2, m::PostMessageRequest { recipients: vec![1], text: "hi".to_owned(), reply_to: None, channel_id: None, send_at: None,
+            destruct_after: None },
+            &[m::StoredFile { original_name: "a.txt".to_owned(), stored_name: "s".to_owned() }]).unwrap();
+        assert_eq!(message.id, 4);
+        assert_eq!(message.attachments.len(), 1);
+    }
+
+    #[test]
+    fn upgrades_unversioned_database() {
+        let mut conn = fixture();
+        assert_eq!(pending_orphans(&mut conn), vec![
+            "recipient 3 of message 2",
+            "recipient 7 of message 3",
+            "attachment of file 2 to message 9"
+        ]);
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+
+        let repo = Repo { conn };
+        let users = repo.select_users_all().unwrap();
+        assert_eq!(users.iter().map(|u| u.id).collect::<Vec<_>>(), vec![1, 2, 4]);
+
+        let messages = repo.select_messages_for_user(2, Conversation::All, None, None, 10).unwrap().messages;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].id, 1);
+        assert_eq!(messages[0].attachments.len(), 1);
+        assert_eq!(messages[0].attachments[0].name, "report.pdf");
+        assert_eq!(messages[0].recipients.len(), 1);
+        assert_eq!(messages[1].id, 3);
+        assert_eq!(messages[1].recipients.len(), 1);
+
+        // file saved without a name falls back to the stored one
+        let name: String = repo.conn.query_row(
+            "SELECT original_name FROM files WHERE id = 2", NO_PARAMS, |row| row.get(0)).unwrap();
+        assert_eq!(name, "k2J9d0aPq");
+
+        assert_eq!(repo.select_token_generation(2).unwrap(), Some(2));
+
+        // rows pointing to missing users, messages or files are gone
+        let orphans: u32 = repo.conn.query_row(
+            "SELECT (SELECT count(*) FROM message_recipients WHERE user_id NOT IN (1, 2, 4))
+                + (SELECT count(*) FROM message_files WHERE message_id = 9)", NO_PARAMS, |row| row.get(0)).unwrap();
+        assert_eq!(orphans, 0);
+    }
+
+    #[test]
+    fn renames_usernames_that_differ_in_case_only() {
+        let mut conn = fixture();
+        conn.execute_batch("INSERT INTO users (username, password, color, name) VALUES ('ALICE', '', 'red', 'Alice')").unwrap();
+        migrate(&mut conn).unwrap();
+
+        let repo = Repo { conn };
+        assert_eq!(repo.select_user_credentials("Alice").unwrap().unwrap().id, 1);
+        assert_eq!(repo.select_user_credentials("alice_5").unwrap().unwrap().username, "ALICE_5");
+        assert!(repo.conn.execute("INSERT INTO users (username, password, color, name) VALUES ('Bob', '', 'red', 'Bob')", NO_PARAMS)
+            .is_err());
+    }
+
+    #[test]
+    fn enforces_foreign_keys() {
+        let mut conn = fixture();
+        migrate(&mut conn).unwrap();
+        assert!(conn.execute("INSERT INTO message_recipients (user_id, message_id) VALUES (42, 1)", NO_PARAMS)
+            .is_err());
+        assert!(conn.execute("INSERT INTO files (original_name, stored_name, owner_id) VALUES (NULL, 'x', 1)", NO_PARAMS)
+            .is_err());
+    }
+
+    #[test]
+    fn creates_empty_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+        // running again is a no-op
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn refuses_newer_database() {
+        let mut conn = fixture();
+        conn.execute_batch(&format!("PRAGMA user_version = {}", latest_version() + 1)).unwrap();
+        assert!(matches!(migrate(&mut conn), Err(MigrationError::TooNew { .. })));
+    }
+}
@@ -0,0 +1,42 @@
+-- Schema and data as left by the first released version (before attachments
+-- and token generations). Includes a message of a user that no longer exists.
+CREATE TABLE users (
+    username TEXT NOT NULL,
+    password TEXT NOT NULL,
+    color TEXT NOT NULL,
+    name TEXT NOT NULL
+);
+CREATE TABLE messages (
+    text TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    timestamp INTEGER NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(ROWID)
+);
+CREATE TABLE message_recipients (
+    user_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(ROWID),
+    FOREIGN KEY (message_id) REFERENCES messages(ROWID)
+);
+CREATE TABLE files (
+    original_name TEXT_NOT_NULL,
+    stored_name TEXT NOT NULL,
+    owner_id INTEGER NOT NULL,
+    is_deleted INTEGER NOT NULL,
+    FOREIGN KEY (owner_id) REFERENCES users(ROWID)
+);
+
+-- blake3("secret") and a user who has not logged in yet
+INSERT INTO users (username, password, color, name) VALUES
+    ('alice', '5fbf08af2b116ab8f7f3c14b8ec01a46ce23d290e2ebc7a752d0982d54c054f2', 'red', 'Alice'),
+    ('bob', '', 'blue', 'Bob');
+
+INSERT INTO messages (text, user_id, timestamp) VALUES
+    ('hello bob', 1, 1),
+    ('x', 3, 2),
+    ('hello again', 1, 3);
+
+INSERT INTO message_recipients (user_id, message_id) VALUES
+    (2, 1),
+    (2, 2),
+    (2, 3);
@@ -0,0 +1,62 @@
+-- Schema and data as left by the server before schema versioning.
+-- Includes rows that the old schema allowed but foreign keys do not.
+CREATE TABLE users (
+    username TEXT NOT NULL,
+    password TEXT NOT NULL,
+    color TEXT NOT NULL,
+    name TEXT NOT NULL,
+    token_generation INTEGER NOT NULL DEFAULT 0
+);
+CREATE TABLE messages (
+    text TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    timestamp INTEGER NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(ROWID)
+);
+CREATE TABLE message_recipients (
+    user_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(ROWID),
+    FOREIGN KEY (message_id) REFERENCES messages(ROWID)
+);
+CREATE TABLE files (
+    original_name TEXT_NOT_NULL,
+    stored_name TEXT NOT NULL,
+    owner_id INTEGER NOT NULL,
+    is_deleted INTEGER NOT NULL,
+    FOREIGN KEY (owner_id) REFERENCES users(ROWID)
+);
+CREATE TABLE message_files (
+    file_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    FOREIGN KEY (file_id) REFERENCES files(ROWID),
+    FOREIGN KEY (message_id) REFERENCES messages(ROWID)
+);
+
+INSERT INTO users (username, password, color, name, token_generation) VALUES
+    ('alice', '', 'red', 'Alice', 0),
+    ('bob', '', 'blue', 'Bob', 2),
+    ('carol', '', 'green', 'Carol', 0),
+    ('dave', '', 'black', 'Dave', 0);
+-- leaves a gap in ROWIDs, which must be kept
+DELETE FROM users WHERE username = 'carol';
+
+INSERT INTO messages (text, user_id, timestamp) VALUES
+    ('report attached', 1, 1600000000),
+    ('to a deleted user', 1, 1600000100),
+    ('hi bob', 4, 1600000200);
+
+INSERT INTO message_recipients (user_id, message_id) VALUES
+    (2, 1),
+    (2, 1),
+    (3, 2),
+    (2, 3),
+    (7, 3);
+
+INSERT INTO files (original_name, stored_name, owner_id, is_deleted) VALUES
+    ('report.pdf', 'Xq8fV2mT0c', 1, 0),
+    (NULL, 'k2J9d0aPq', 1, 0);
+
+INSERT INTO message_files (file_id, message_id) VALUES
+    (1, 1),
+    (2, 9);
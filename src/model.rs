@@ -3,8 +3,21 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize)]
 pub struct PostMessageRequest {
+    /// Empty for channel messages, they go to all members
+    #[serde(default)]
     pub recipients: Vec<u32>,
-    pub text: String
+    pub text: String,
+    /// Id of the message this one answers
+    #[serde(default)]
+    pub reply_to: Option<u32>,
+    #[serde(default)]
+    pub channel_id: Option<u32>,
+    /// UNIX time to send the message at instead of now
+    #[serde(default)]
+    pub send_at: Option<i64>,
+    /// Seconds after sending the message is deleted for everyone
+    #[serde(default)]
+    pub destruct_after: Option<u32>
 }
 
 #[derive(Serialize, Clone)]
@@ -14,20 +27,313 @@ pub struct EmbeddedRecipient {
     pub color: String
 }
 
+/// User one can send messages to, with their presence
+#[derive(Serialize)]
+pub struct User {
+    pub id: u32,
+    /// Mentions of the user are written as `@username`
+    pub username: String,
+    pub name: String,
+    pub color: String,
+    /// Has a websocket open
+    pub online: bool,
+    /// When the user closed their last websocket, none if they never had one
+    pub last_seen: Option<i64>
+}
+
+/// Response of `GET /users`
+#[derive(Serialize)]
+pub struct UserList {
+    pub users: Vec<User>
+}
+
+/// Recipient of a message with times it was delivered to and read by them
+#[derive(Serialize, Clone)]
+pub struct MessageRecipient {
+    pub id: u32,
+    pub name: String,
+    pub color: String,
+    pub delivered_at: Option<i64>,
+    pub read_at: Option<i64>
+}
+
+#[derive(Serialize, Clone)]
+pub struct EmbeddedAttachment {
+    pub id: u32,
+    pub name: String
+}
+
 #[derive(Serialize, Clone)]
 pub struct MessageResponse {
     pub id: u32,
+    /// As typed by the sender
     pub text: String,
+    /// Text rendered from Markdown, safe to put into a page
+    pub html: String,
     pub timestamp: i64,
     pub sender_name: String,
     pub sender_id: u32,
     pub sender_color: String,
-    pub recipients: Vec<EmbeddedRecipient>
+    pub recipients: Vec<MessageRecipient>,
+    pub attachments: Vec<EmbeddedAttachment>,
+    /// Time of the last edit
+    pub edited_at: Option<i64>,
+    /// Retracted by the sender, text and attachments are removed
+    pub deleted: bool,
+    pub reply_to: Option<u32>,
+    /// Beginning of the message replied to
+    pub quote: Option<Quote>,
+    /// Channel the message was sent to, direct messages have none
+    pub channel_id: Option<u32>,
+    pub channel_name: Option<String>,
+    /// Ids of users mentioned with `@username`
+    pub mentions: Vec<u32>,
+    /// Reactions by emoji, in the order they were first added
+    pub reactions: Vec<Reaction>,
+    /// Time the message is going to be sent at, while it waits
+    pub send_at: Option<i64>,
+    /// Self-destruct timer in seconds, set by the sender
+    pub destruct_after: Option<u32>,
+    /// Time the message is deleted at by its timer, once it is sent
+    pub expires_at: Option<i64>
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Reaction {
+    pub emoji: String,
+    /// Users who reacted with the emoji, earliest first
+    pub user_ids: Vec<u32>
+}
+
+#[derive(Serialize, Clone)]
+pub struct Quote {
+    pub sender_name: String,
+    pub text: String,
+    pub deleted: bool
+}
+
+#[derive(Serialize)]
+pub struct MessagePage {
+    pub messages: Vec<MessageResponse>,
+    /// More messages exist past the end of the page in the direction of paging
+    pub has_more: bool
+}
+
+/// Query of `GET /`
+#[derive(Deserialize)]
+pub struct IndexRequest {
+    pub before: Option<u32>,
+    /// Channel to show instead of direct messages
+    pub channel: Option<u32>,
+    /// Id of the message to write an answer to
+    pub reply_to: Option<u32>
+}
+
+/// Query of `GET /messages`. Cursors are message ids, bounds are exclusive.
+#[derive(Deserialize)]
+pub struct HistoryRequest {
+    pub before: Option<u32>,
+    pub after: Option<u32>,
+    pub limit: Option<u32>,
+    /// Channel to page through instead of direct messages
+    pub channel: Option<u32>
+}
+
+/// Query of `GET /messages/search`, results are paged with `offset`
+#[derive(Deserialize)]
+pub struct SearchRequest {
+    pub q: String,
+    pub offset: Option<u32>,
+    pub limit: Option<u32>
+}
+
+/// Piece of a search snippet, `highlighted` ones match the query
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct SnippetPart {
+    pub text: String,
+    pub highlighted: bool
+}
+
+#[derive(Serialize)]
+pub struct SearchResult {
+    pub message: MessageResponse,
+    pub snippet: Vec<SnippetPart>
+}
+
+/// Response of `GET /messages/search`, best matches first
+#[derive(Serialize)]
+pub struct SearchPage {
+    pub results: Vec<SearchResult>,
+    pub has_more: bool
+}
+
+#[derive(Serialize, Clone)]
+pub struct ChannelResponse {
+    pub id: u32,
+    pub name: String,
+    pub member_count: u32,
+    /// The user is a member
+    pub joined: bool
+}
+
+/// Response of `GET /channels`
+#[derive(Serialize)]
+pub struct ChannelList {
+    pub channels: Vec<ChannelResponse>
+}
+
+/// Body of `POST /channels`
+#[derive(Deserialize)]
+pub struct CreateChannelRequest {
+    pub name: String
 }
 
+/// Response of `GET /messages/{id}/thread`
+#[derive(Serialize)]
+pub struct Thread {
+    pub messages: Vec<MessageResponse>
+}
+
+/// Response of `GET /messages/scheduled`
+#[derive(Serialize)]
+pub struct ScheduledList {
+    pub messages: Vec<MessageResponse>
+}
+
+/// Body of `PATCH /messages/scheduled/{id}`
+#[derive(Deserialize)]
+pub struct RescheduleRequest {
+    pub send_at: i64
+}
+
+/// Body of `PATCH /messages/{id}`
+#[derive(Deserialize)]
+pub struct EditMessageRequest {
+    pub text: String
+}
+
+/// Body of `POST /messages/{id}/reactions`, and query of `DELETE` of them
+#[derive(Deserialize)]
+pub struct ReactionRequest {
+    pub emoji: String
+}
+
+/// Body of `POST /messages/read`
+#[derive(Deserialize)]
+pub struct MarkReadRequest {
+    pub message_ids: Vec<u32>
+}
+
+/// Change of delivery state of a message for one recipient, sent to the sender
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Receipt {
+    pub message_id: u32,
+    #[serde(skip)]
+    pub sender_id: u32,
+    pub user_id: u32,
+    pub delivered_at: i64,
+    pub read_at: Option<i64>
+}
+
+
+/// Body of `POST /register`, also sent by the sign-up form
+#[derive(Deserialize, Serialize, Default)]
+pub struct RegisterRequest {
+    pub invite: String,
+    pub username: String,
+    pub name: String,
+    /// Gray if not chosen
+    #[serde(default)]
+    pub color: Option<String>,
+    #[serde(skip_serializing)]
+    pub password: String
+}
+
+/// Response of `POST /register`
+#[derive(Serialize)]
+pub struct RegisteredUser {
+    pub id: u32,
+    pub username: String
+}
+
+/// Response of `GET /me`
+#[derive(Serialize)]
+pub struct Profile {
+    pub id: u32,
+    pub username: String,
+    pub name: String,
+    pub color: String
+}
+
+/// Body of `PATCH /me`, also sent by the settings form. Missing fields are kept.
+#[derive(Deserialize)]
+pub struct UpdateProfileRequest {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub color: Option<String>,
+    /// New password, `current_password` is required to change it
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub current_password: Option<String>
+}
+
+/// Code letting people register, issued by an administrator
+#[derive(Debug)]
+pub struct Invite {
+    pub code: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub max_uses: u32,
+    pub uses: u32
+}
+
+/// User as listed by administrative commands
+#[derive(Debug)]
+pub struct UserSummary {
+    pub id: u32,
+    pub username: String,
+    pub name: String,
+    pub color: String,
+    /// Has a password
+    pub registered: bool,
+    pub message_count: u32
+}
+
+/// Retention policy of a channel, unset values follow the global one
+#[derive(Debug)]
+pub struct ChannelRetention {
+    pub id: u32,
+    pub name: String,
+    /// Days messages are kept for, zero keeps them forever
+    pub days: Option<u32>,
+    /// Number of latest messages kept, zero keeps all of them
+    pub keep_last: Option<u32>
+}
+
+/// Row counts shown by `db stats`
+#[derive(Debug)]
+pub struct DatabaseStats {
+    pub schema_version: u32,
+    pub users: i64,
+    pub messages: i64,
+    pub deleted_messages: i64,
+    pub channels: i64,
+    pub files: i64,
+    pub size_bytes: i64
+}
 
 #[derive(Debug)]
 pub struct UserCredentials {
+    pub id: u32,
     pub username: String,
+    /// Password hash, empty if the user can not log in
     pub password: String
 }
+
+#[derive(Debug)]
+pub struct StoredFile {
+    pub original_name: String,
+    pub stored_name: String
+}
@@ -0,0 +1,687 @@
+//! Streaming `multipart/form-data` parser.
+//!
+//! Parts are read one after another from any `AsyncBufRead`. Each part gives
+//! access to its headers and implements `AsyncRead` for its body, so files can
+//! be copied to disk without being collected in memory first.
+
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::io::{AsyncBufRead, AsyncRead, AsyncReadExt};
+use futures::ready;
+
+
+/// Size limits enforced while parsing
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Maximum size of a single part body
+    pub part_size: u64,
+    /// Maximum amount of bytes read from the underlying stream
+    pub total_size: u64,
+    /// Maximum size of the header block of a single part
+    pub headers_size: usize
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        return Self {
+            part_size: 16 * 1024 * 1024,
+            total_size: 32 * 1024 * 1024,
+            headers_size: 8 * 1024
+        };
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    NoBoundary,
+    MalformedHeaders(&'static str),
+    UnexpectedEof,
+    HeadersTooLarge,
+    PartTooLarge,
+    BodyTooLarge,
+    NotUtf8
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "Could not read multipart body: {}", e),
+            Error::NoBoundary => write!(f, "Boundary is not provided or invalid"),
+            Error::MalformedHeaders(m) => write!(f, "Malformed part headers: {}", m),
+            Error::UnexpectedEof => write!(f, "Unexpected end of multipart body"),
+            Error::HeadersTooLarge => write!(f, "Part headers are too large"),
+            Error::PartTooLarge => write!(f, "Part is too large"),
+            Error::BodyTooLarge => write!(f, "Multipart body is too large"),
+            Error::NotUtf8 => write!(f, "Part is not valid UTF-8")
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        // errors returned from `Part::poll_read` are wrapped into io::Error
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *e.into_inner()
+                .expect("Checked above")
+                .downcast::<Error>()
+                .expect("Checked above");
+        }
+        return Error::Io(e);
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e)
+        }
+    }
+}
+
+impl Error {
+    /// HTTP status code to respond with
+    pub fn status(&self) -> u16 {
+        match self {
+            Error::Io(_) => 500,
+            Error::PartTooLarge | Error::BodyTooLarge => 413,
+            Error::NotUtf8 => 422,
+            _ => 400
+        }
+    }
+}
+
+/// Extracts boundary parameter from a Content-Type header value
+pub fn boundary(content_type: &str) -> Option<&str> {
+    return content_type.split(';')
+        .skip(1)
+        .filter_map(|param| {
+            let (key, value) = param.trim().split_once('=')?;
+            if key.trim().eq_ignore_ascii_case("boundary") {
+                Some(value.trim().trim_matches('"'))
+            } else { None }
+        })
+        .next();
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartHeaders {
+    pub field_name: String,
+    pub file_name: Option<String>,
+    pub content_type: Option<String>
+}
+
+impl PartHeaders {
+    pub fn parse(headers: &str) -> Result<Self, Error> {
+        let mut content_type = None;
+        let mut file_name = None;
+        let mut field_name = None;
+
+        for header in headers.split("\r\n") {
+            if header.is_empty() { continue; }
+            let mut header_split = header.splitn(2, ':');
+            let name = header_split.next()
+                .ok_or(Error::MalformedHeaders("empty header"))?
+                .trim();
+            let value = header_split.next()
+                .ok_or(Error::MalformedHeaders("header without value"))?
+                .trim();
+
+            if name.eq_ignore_ascii_case("Content-Disposition") {
+                let mut params = split_params(value).into_iter();
+                let disposition = params.next()
+                    .ok_or(Error::MalformedHeaders("empty content disposition"))?;
+                if !disposition.eq_ignore_ascii_case("form-data") {
+                    return Err(Error::MalformedHeaders("unknown content disposition"));
+                }
+                for param in params {
+                    let mut key_value = param.splitn(2, '=');
+                    let key = key_value.next()
+                        .ok_or(Error::MalformedHeaders("empty parameter"))?
+                        .trim();
+                    let value = unquote(key_value.next()
+                        .ok_or(Error::MalformedHeaders("parameter without value"))?
+                        .trim());
+
+                    match key {
+                        "name" => { field_name = Some(value) },
+                        "filename" => { file_name = Some(value) },
+                        _ => {}
+                    }
+                }
+            } else if name.eq_ignore_ascii_case("Content-Type") {
+                content_type = Some(value.to_owned());
+            }
+        }
+        let field_name = field_name
+            .ok_or(Error::MalformedHeaders("no field name provided"))?;
+        return Ok(Self { field_name, file_name, content_type });
+    }
+}
+
+/// Splits header value by semicolons which are not inside of quotes
+fn split_params(value: &str) -> Vec<&str> {
+    let mut params = Vec::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => { escaped = false; }
+            '\\' if in_quotes => { escaped = true; }
+            '"' => { in_quotes = !in_quotes; }
+            ';' if !in_quotes => {
+                params.push(value[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    params.push(value[start..].trim());
+    return params;
+}
+
+/// Removes quotes around a parameter value. Browsers percent-encode quotes
+/// and line breaks in file names, so those are decoded as well.
+fn unquote(value: &str) -> String {
+    let value = if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        &value[1..value.len() - 1]
+    } else { value };
+    return value
+        .replace("\\\"", "\"")
+        .replace("%22", "\"")
+        .replace("%0D", "\r")
+        .replace("%0A", "\n");
+}
+
+
+#[derive(PartialEq, Debug)]
+pub enum ContainsResult {
+    DoesNotContain,
+    PossiblyContains (usize),
+    Contains (usize)
+}
+
+//generate bm_bc for Boyer-Moore algorithm.
+fn generate_bm_bc(subslice: &[u8]) -> [u8; 256] {
+    let len = subslice.len() as u8;
+    let mut bm_bc: [u8; 256] = [len; 256];
+
+    for i in 0..len - 1 {
+        bm_bc[ subslice[i as usize] as usize ] = len - i - 1;
+    }
+    return bm_bc;
+}
+
+
+//find subslice with Boyer-Moore algorithm.
+fn find_subslice(slice: &[u8], subslice: &[u8]) -> Option<usize> {
+    let size_subslice = subslice.len();
+    let size_slice = slice.len();
+    let mut j = 0;
+    let bm_bc = generate_bm_bc(subslice);
+    let mut c;
+   /* Searching */
+
+    while j <= (size_slice - size_subslice) {
+        c = slice[j + size_subslice - 1];
+        if subslice[size_subslice - 1] == c &&
+            subslice[..size_subslice] == slice[j..j+size_subslice] {
+            return Some(j);
+        }
+        j += bm_bc[c as usize] as usize;
+    }
+
+    return None;
+}
+
+/// Returns option of position of one subslice in an other
+pub fn contains(slice: &[u8], subslice: &[u8]) -> ContainsResult {
+
+    let mut i;
+
+    if slice.len() >= subslice.len() {
+        if let Some(value) = find_subslice(slice, subslice) {
+            return ContainsResult::Contains(value);
+        }
+        i = slice.len() + 1 - subslice.len();
+    }
+    else {
+        i = 0;
+    }
+
+    let mut streak = 0;
+    while i < slice.len() {
+        if slice[i] == subslice[streak] {
+            streak += 1;
+        } else {
+            i -= streak;
+            streak = 0;
+        }
+        i += 1;
+    }
+    if streak > 0 {
+        return ContainsResult::PossiblyContains (slice.len() - streak);
+    } else {
+        return ContainsResult::DoesNotContain;
+    }
+}
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum State {
+    Preamble,
+    Tail,
+    Headers,
+    Body,
+    Done
+}
+
+pub struct Multipart<R> {
+    reader: R,
+    delimiter: Vec<u8>,
+    window: Vec<u8>,
+    state: State,
+    limits: Limits,
+    total_read: u64,
+    part_read: u64
+}
+
+impl<R: AsyncBufRead + Unpin> Multipart<R> {
+    pub fn new(reader: R, boundary: &str, limits: Limits) -> Result<Self, Error> {
+        // RFC 2046 limits boundary to 70 characters
+        if boundary.is_empty() || boundary.len() > 70 {
+            return Err(Error::NoBoundary);
+        }
+        return Ok(Self {
+            reader,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // the first boundary is not preceded by a line break
+            window: b"\r\n".to_vec(),
+            state: State::Preamble,
+            limits,
+            total_read: 0,
+            part_read: 0
+        });
+    }
+
+    /// Creates parser using boundary from a Content-Type header value
+    pub fn from_content_type(reader: R, content_type: &str, limits: Limits) -> Result<Self, Error> {
+        let boundary = boundary(content_type).ok_or(Error::NoBoundary)?;
+        return Self::new(reader, boundary, limits);
+    }
+
+    /// Returns the next part or `None` if the closing boundary is reached.
+    /// Unread remainder of the previous part is skipped.
+    pub async fn next_part(&mut self) -> Result<Option<Part<'_, R>>, Error> {
+        let headers = futures::future::poll_fn(|cx| self.poll_next_headers(cx)).await?;
+        return Ok(headers.map(move |headers| Part { multipart: self, headers }));
+    }
+
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize, Error>> {
+        let chunk = ready!(Pin::new(&mut self.reader).poll_fill_buf(cx))?;
+        let len = chunk.len();
+        self.total_read += len as u64;
+        if self.total_read > self.limits.total_size {
+            return Poll::Ready(Err(Error::BodyTooLarge));
+        }
+        self.window.extend_from_slice(chunk);
+        Pin::new(&mut self.reader).consume(len);
+        return Poll::Ready(Ok(len));
+    }
+
+    fn poll_fill_more(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if ready!(self.poll_fill(cx))? == 0 {
+            return Poll::Ready(Err(Error::UnexpectedEof));
+        }
+        return Poll::Ready(Ok(()));
+    }
+
+    /// Reads body of the current part. Returns 0 when the part is over.
+    fn poll_body(&mut self, cx: &mut Context<'_>, out: &mut [u8]) -> Poll<Result<usize, Error>> {
+        if out.is_empty() { return Poll::Ready(Ok(0)); }
+        loop {
+            let available = match contains(&self.window, &self.delimiter) {
+                ContainsResult::Contains(0) => {
+                    self.window.drain(..self.delimiter.len());
+                    self.state = State::Tail;
+                    return Poll::Ready(Ok(0));
+                }
+                ContainsResult::Contains(p) | ContainsResult::PossiblyContains(p) => p,
+                ContainsResult::DoesNotContain => self.window.len()
+            };
+            if available > 0 {
+                let n = available.min(out.len());
+                if self.state == State::Body {
+                    self.part_read += n as u64;
+                    if self.part_read > self.limits.part_size {
+                        return Poll::Ready(Err(Error::PartTooLarge));
+                    }
+                }
+                out[..n].copy_from_slice(&self.window[..n]);
+                self.window.drain(..n);
+                return Poll::Ready(Ok(n));
+            }
+            ready!(self.poll_fill_more(cx))?;
+        }
+    }
+
+    /// Reads what follows a boundary: either `--` or a line break
+    fn poll_tail(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        loop {
+            if self.window.starts_with(b"--") {
+                self.state = State::Done;
+                return Poll::Ready(Ok(()));
+            }
+            if let ContainsResult::Contains(p) = contains(&self.window, b"\r\n") {
+                // boundary may be followed by transport padding
+                if !self.window[..p].iter().all(|b| *b == b' ' || *b == b'\t') {
+                    return Poll::Ready(Err(Error::MalformedHeaders("garbage after boundary")));
+                }
+                self.window.drain(..p + 2);
+                self.state = State::Headers;
+                return Poll::Ready(Ok(()));
+            }
+            if self.window.len() > self.limits.headers_size {
+                return Poll::Ready(Err(Error::MalformedHeaders("garbage after boundary")));
+            }
+            ready!(self.poll_fill_more(cx))?;
+        }
+    }
+
+    fn poll_headers(&mut self, cx: &mut Context<'_>) -> Poll<Result<PartHeaders, Error>> {
+        loop {
+            let headers_end = if self.window.starts_with(b"\r\n") {
+                Some(0)
+            } else if let ContainsResult::Contains(p) = contains(&self.window, b"\r\n\r\n") {
+                Some(p + 2)
+            } else { None };
+
+            if let Some(p) = headers_end {
+                if p > self.limits.headers_size {
+                    return Poll::Ready(Err(Error::HeadersTooLarge));
+                }
+                let headers_bytes: Vec<u8> = self.window.drain(..p + 2).collect();
+                let headers = std::str::from_utf8(&headers_bytes)
+                    .map_err(|_| Error::MalformedHeaders("headers are not valid UTF-8"))?;
+                return Poll::Ready(PartHeaders::parse(headers));
+            }
+            if self.window.len() > self.limits.headers_size {
+                return Poll::Ready(Err(Error::HeadersTooLarge));
+            }
+            ready!(self.poll_fill_more(cx))?;
+        }
+    }
+
+    fn poll_next_headers(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<PartHeaders>, Error>> {
+        loop {
+            match self.state {
+                State::Preamble | State::Body => {
+                    let mut scratch = [0u8; 1024];
+                    while ready!(self.poll_body(cx, &mut scratch))? > 0 {}
+                }
+                State::Tail => { ready!(self.poll_tail(cx))?; }
+                State::Headers => {
+                    let headers = ready!(self.poll_headers(cx))?;
+                    self.state = State::Body;
+                    self.part_read = 0;
+                    return Poll::Ready(Ok(Some(headers)));
+                }
+                State::Done => { return Poll::Ready(Ok(None)); }
+            }
+        }
+    }
+}
+
+
+/// Single part of a multipart body. Reading it yields the part body.
+pub struct Part<'a, R> {
+    multipart: &'a mut Multipart<R>,
+    headers: PartHeaders
+}
+
+impl<'a, R: AsyncBufRead + Unpin> Part<'a, R> {
+    pub fn headers(&self) -> &PartHeaders {
+        return &self.headers;
+    }
+
+    /// Reads the rest of the part as a string
+    pub async fn text(&mut self) -> Result<String, Error> {
+        let mut bytes = Vec::new();
+        self.read_to_end(&mut bytes).await?;
+        return String::from_utf8(bytes).map_err(|_| Error::NotUtf8);
+    }
+}
+
+impl<'a, R: AsyncBufRead + Unpin> AsyncRead for Part<'a, R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.multipart.state != State::Body {
+            return Poll::Ready(Ok(0));
+        }
+        return this.multipart.poll_body(cx, buf).map_err(io::Error::from);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::{BufReader, Cursor};
+
+    struct Field {
+        headers: PartHeaders,
+        body: Vec<u8>
+    }
+
+    fn parse_with(body: &[u8], boundary: &str, capacity: usize, limits: Limits) -> Result<Vec<Field>, Error> {
+        let reader = BufReader::with_capacity(capacity, Cursor::new(body.to_vec()));
+        return async_std::task::block_on(async {
+            let mut form = Multipart::new(reader, boundary, limits)?;
+            let mut fields = Vec::new();
+            while let Some(mut part) = form.next_part().await? {
+                let mut body = Vec::new();
+                part.read_to_end(&mut body).await?;
+                fields.push(Field { headers: part.headers().clone(), body });
+            }
+            return Ok(fields);
+        });
+    }
+
+    /// Parses the body with different read sizes to move chunk edges around
+    fn parse(body: &[u8], boundary: &str) -> Vec<Field> {
+        let reference = parse_with(body, boundary, 8192, Limits::default()).unwrap();
+        for capacity in [1, 2, 3, 7, 16, 41, 64, 500].iter() {
+            let fields = parse_with(body, boundary, *capacity, Limits::default()).unwrap();
+            assert_eq!(fields.len(), reference.len(), "capacity {}", capacity);
+            for (a, b) in fields.iter().zip(reference.iter()) {
+                assert_eq!(a.headers, b.headers, "capacity {}", capacity);
+                assert_eq!(a.body, b.body, "capacity {}", capacity);
+            }
+        }
+        return reference;
+    }
+
+    #[test]
+    fn parses_webkit_style_text_and_file() {
+        let fields = parse(
+            include_bytes!("multipart/fixtures/handmade_text_and_file.bin"),
+            "----WebKitFormBoundaryGkEAO60J3WyaOnEr"
+        );
+        assert_eq!(fields.len(), 4);
+        assert_eq!(fields[0].headers.field_name, "text");
+        assert_eq!(fields[0].headers.file_name, None);
+        assert_eq!(fields[0].body, b"Hello, world");
+        assert_eq!(fields[1].headers.field_name, "upload-file");
+        assert_eq!(fields[1].headers.file_name.as_deref(), Some("report; final \"v2\".txt"));
+        assert_eq!(fields[1].headers.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(
+            fields[1].body,
+            &b"line one\r\nline two\n------WebKitFormBoundaryGkEAO60J3Wya\r\n--\r\nlast line"[..]
+        );
+        assert_eq!(fields[2].headers.field_name, "usr1");
+        assert_eq!(fields[2].body, b"on");
+        assert_eq!(fields[3].headers.field_name, "usr3");
+    }
+
+    #[test]
+    fn parses_webkit_style_without_file() {
+        let fields = parse(
+            include_bytes!("multipart/fixtures/handmade_without_file.bin"),
+            "----WebKitFormBoundary7MA4YWxkTrZu0gW"
+        );
+        assert_eq!(fields.len(), 3);
+        assert_eq!(fields[0].body, b"no attachment");
+        assert_eq!(fields[1].headers.file_name.as_deref(), Some(""));
+        assert!(fields[1].body.is_empty());
+        assert_eq!(fields[2].headers.field_name, "usr2");
+    }
+
+    #[test]
+    fn parses_gecko_style_binary() {
+        let fields = parse(
+            include_bytes!("multipart/fixtures/handmade_binary.bin"),
+            "---------------------------9051914041544843365972754266"
+        );
+        let mut expected: Vec<u8> = (0..3000u32).map(|i| (i * 37 % 256) as u8).collect();
+        expected.extend_from_slice(b"\r\n-----------------------------9051914041544843365972754");
+
+        assert_eq!(fields.len(), 3);
+        assert_eq!(String::from_utf8(fields[0].body.clone()).unwrap(), "Привет");
+        assert_eq!(fields[1].headers.file_name.as_deref(), Some("image.png"));
+        assert_eq!(fields[1].headers.content_type.as_deref(), Some("image/png"));
+        assert_eq!(fields[1].body, expected);
+        assert_eq!(fields[2].body, b"on");
+    }
+
+    #[test]
+    fn parses_curl_text_and_binary() {
+        let fields = parse(
+            include_bytes!("multipart/fixtures/curl_text_and_binary.bin"),
+            "------------------------baa7bda5c5a1ebdd"
+        );
+        let mut expected: Vec<u8> = (0..=255u8).collect();
+        expected.extend_from_slice(b"\r\n--\r\n-- not a boundary\r\n");
+
+        assert_eq!(fields.len(), 3);
+        assert_eq!(String::from_utf8(fields[0].body.clone()).unwrap(), "привет, мир");
+        assert_eq!(fields[1].headers.file_name.as_deref(), Some("photo.bin"));
+        assert_eq!(fields[1].body, expected);
+        assert_eq!(fields[2].headers.field_name, "usr1");
+    }
+
+    #[test]
+    fn skips_unread_parts() {
+        let body = include_bytes!("multipart/fixtures/handmade_text_and_file.bin");
+        let reader = BufReader::with_capacity(5, Cursor::new(body.to_vec()));
+        let names = async_std::task::block_on(async {
+            let mut form = Multipart::new(reader, "----WebKitFormBoundaryGkEAO60J3WyaOnEr", Limits::default())
+                .unwrap();
+            let mut names = Vec::new();
+            while let Some(part) = form.next_part().await.unwrap() {
+                names.push(part.headers().field_name.clone());
+            }
+            return names;
+        });
+        assert_eq!(names, vec!["text", "upload-file", "usr1", "usr3"]);
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let body = include_bytes!("multipart/fixtures/handmade_binary.bin");
+        let boundary = "---------------------------9051914041544843365972754266";
+
+        let limits = Limits { part_size: 1000, ..Limits::default() };
+        assert!(matches!(parse_with(body, boundary, 64, limits), Err(Error::PartTooLarge)));
+
+        let limits = Limits { total_size: 2000, ..Limits::default() };
+        assert!(matches!(parse_with(body, boundary, 64, limits), Err(Error::BodyTooLarge)));
+
+        let limits = Limits { headers_size: 16, ..Limits::default() };
+        assert!(matches!(parse_with(body, boundary, 64, limits), Err(Error::HeadersTooLarge)));
+
+        let limits = Limits { part_size: 3100, total_size: body.len() as u64, headers_size: 128 };
+        assert!(parse_with(body, boundary, 64, limits).is_ok());
+    }
+
+    #[test]
+    fn fails_on_truncated_body() {
+        let body = include_bytes!("multipart/fixtures/handmade_text_and_file.bin");
+        let boundary = "----WebKitFormBoundaryGkEAO60J3WyaOnEr";
+        for cut in [0, 10, 45, 100, 300, body.len() - 10].iter() {
+            assert!(matches!(
+                parse_with(&body[..*cut], boundary, 16, Limits::default()),
+                Err(Error::UnexpectedEof)
+            ), "cut at {}", cut);
+        }
+    }
+
+    #[test]
+    fn extracts_boundary() {
+        assert_eq!(boundary("multipart/form-data; boundary=abc"), Some("abc"));
+        assert_eq!(boundary("multipart/form-data; charset=utf-8; boundary=\"a=b\""), Some("a=b"));
+        assert_eq!(boundary("multipart/form-data"), None);
+    }
+
+    #[test]
+    fn parses_headers() {
+        let headers = PartHeaders::parse(
+            "content-disposition: form-data; name=\"upload-file\"; filename=\"a;b=c.txt\"\r\n\
+             Content-Type: text/plain\r\n"
+        ).unwrap();
+        assert_eq!(headers, PartHeaders {
+            field_name: "upload-file".to_owned(),
+            file_name: Some("a;b=c.txt".to_owned()),
+            content_type: Some("text/plain".to_owned())
+        });
+        assert!(PartHeaders::parse("Content-Disposition: attachment; name=\"a\"\r\n").is_err());
+        assert!(PartHeaders::parse("Content-Type: text/plain\r\n").is_err());
+    }
+
+    #[test]
+    fn contains_works_with_bytes() {
+        assert_eq!(contains(&[1,2,3,4,5], &[1,2,3]), ContainsResult::Contains(0));
+        assert_eq!(contains(&[1,2,3,4,5], &[3,4,5]), ContainsResult::Contains(2));
+        assert_eq!(contains(&[1,2,1,2,3], &[1,2,1]), ContainsResult::Contains(0));
+        assert_eq!(contains(&[1,2,1,2,3], &[1,2,3]), ContainsResult::Contains(2));
+        assert_eq!(contains(&[1,2,3,4,5], &[1,2,5]), ContainsResult::DoesNotContain);
+        assert_eq!(contains(&[1,2,1,2,3], &[1,2,4]), ContainsResult::DoesNotContain);
+        assert_eq!(contains(&[1,2,3,4,5], &[4,5,6]), ContainsResult::PossiblyContains(3));
+
+    }
+
+    #[test]
+    fn contains_works_with_strs() {
+        assert_eq!(contains(b"hello world", b"hello"), ContainsResult::Contains(0));
+        assert_eq!(contains(b"hello there", b"there"), ContainsResult::Contains(6));
+        assert_eq!(contains(b"----hello", b"--hello"), ContainsResult::Contains(2));
+        assert_eq!(contains(b"----hell", b"--hello"), ContainsResult::PossiblyContains(2));
+        assert_eq!(contains(b"--hello there general", b"hello there"), ContainsResult::Contains(2));
+        assert_eq!(contains(b"hello there general", b"general kenobi"), ContainsResult::PossiblyContains(12));
+        assert_eq!(contains(b"hello there kenobi", b"general kenobi"), ContainsResult::DoesNotContain);
+    }
+
+    #[test]
+    fn contains_works_with_reqs() {
+        assert_eq!(
+            contains(
+                b"------WebKitFormBoundaryGkEAO60J3WyaOnEr\r\nContent-Disposition: form-data; name=\"t",
+                b"----WebKitFormBoundaryGkEAO60J3WyaOnEr"
+            ),
+            ContainsResult::Contains(2)
+        );
+        assert_eq!(
+            contains(
+                b"------WebKitFormBoundaryGkEAO60J3Wya",
+                b"----WebKitFormBoundaryGkEAO60J3WyaOnEr"
+            ),
+            ContainsResult::PossiblyContains(2)
+        );
+    }
+}
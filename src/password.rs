@@ -0,0 +1,125 @@
+//! Password hashing.
+//!
+//! Passwords are stored as Argon2id PHC strings with a random salt each.
+//! Hashes written by older versions (unsalted blake3, hex encoded) are still
+//! accepted, so that they can be rewritten on the next successful login.
+
+use std::convert::TryFrom;
+use argon2::{Algorithm, Argon2, Params, Version};
+use argon2::password_hash::{self, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use subtle::ConstantTimeEq;
+
+#[derive(Debug, PartialEq)]
+pub enum Verification {
+    Valid,
+    /// Password is correct, but the stored hash is outdated
+    NeedsRehash,
+    Invalid
+}
+
+#[derive(Clone)]
+pub struct Hasher {
+    params: Params,
+    /// Verified against when the user does not exist, to keep timing the same
+    dummy: String
+}
+
+impl Hasher {
+    pub fn new(memory_kib: u32, iterations: u32, parallelism: u32) -> Result<Self, password_hash::Error> {
+        let params = Params::new(memory_kib, iterations, parallelism, None)?;
+        let mut hasher = Self { params, dummy: String::new() };
+        hasher.dummy = hasher.hash("")?;
+        return Ok(hasher);
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        return Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone());
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String, password_hash::Error> {
+        let salt = SaltString::generate(&mut rand::rngs::OsRng);
+        return Ok(self.argon2().hash_password(password.as_bytes(), &salt)?.to_string());
+    }
+
+    pub fn verify(&self, password: &str, stored: &str) -> Verification {
+        if is_legacy(stored) {
+            let computed = blake3::hash(password.as_bytes()).to_hex();
+            if bool::from(computed.as_bytes().ct_eq(stored.to_ascii_lowercase().as_bytes())) {
+                return Verification::NeedsRehash;
+            }
+            return Verification::Invalid;
+        }
+
+        let parsed = match PasswordHash::new(stored) {
+            Ok(parsed) => { parsed }
+            Err(e) => {
+                tide::log::warn!("Stored password hash is malformed: {}", e);
+                return Verification::Invalid;
+            }
+        };
+        // verification itself compares in constant time
+        if self.argon2().verify_password(password.as_bytes(), &parsed).is_err() {
+            return Verification::Invalid;
+        }
+
+        let is_current = parsed.algorithm == Algorithm::Argon2id.ident() && Params::try_from(&parsed)
+            .map(|p| p.m_cost() == self.params.m_cost()
+                && p.t_cost() == self.params.t_cost()
+                && p.p_cost() == self.params.p_cost())
+            .unwrap_or(false);
+        if is_current {
+            return Verification::Valid;
+        }
+        return Verification::NeedsRehash;
+    }
+
+    /// Spends as much time as a failed verification would
+    pub fn verify_dummy(&self, password: &str) {
+        let _ = self.verify(password, &self.dummy);
+    }
+}
+
+fn is_legacy(stored: &str) -> bool {
+    return stored.len() == 64 && stored.chars().all(|c| c.is_ascii_hexdigit());
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hasher() -> Hasher {
+        return Hasher::new(1024, 1, 1).unwrap();
+    }
+
+    #[test]
+    fn hashes_are_salted() {
+        let hasher = hasher();
+        let a = hasher.hash("secret").unwrap();
+        let b = hasher.hash("secret").unwrap();
+        assert!(a.starts_with("$argon2id$"));
+        assert_ne!(a, b);
+        assert_eq!(hasher.verify("secret", &a), Verification::Valid);
+        assert_eq!(hasher.verify("secret", &b), Verification::Valid);
+        assert_eq!(hasher.verify("Secret", &a), Verification::Invalid);
+    }
+
+    #[test]
+    fn accepts_legacy_hashes() {
+        let legacy = blake3::hash(b"secret").to_hex().to_string();
+        assert_eq!(hasher().verify("secret", &legacy), Verification::NeedsRehash);
+        assert_eq!(hasher().verify("other", &legacy), Verification::Invalid);
+    }
+
+    #[test]
+    fn asks_to_rehash_on_params_change() {
+        let old = Hasher::new(1024, 2, 1).unwrap().hash("secret").unwrap();
+        assert_eq!(hasher().verify("secret", &old), Verification::NeedsRehash);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert_eq!(hasher().verify("secret", ""), Verification::Invalid);
+        assert_eq!(hasher().verify("secret", "$argon2id$broken"), Verification::Invalid);
+    }
+}
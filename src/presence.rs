@@ -0,0 +1,173 @@
+//! Who is online, derived from open websockets.
+//!
+//! A user is online while they have at least one connection. Going offline is
+//! announced only after a delay, so that a client reconnecting right away, like
+//! a reloaded page, does not flicker. The time a user went offline is stored as
+//! their last seen time.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::hub::Hub;
+use crate::protocol::ServerFrame;
+use crate::repository::Repo;
+
+pub struct Presence {
+    hub: Arc<Hub<ServerFrame>>,
+    repo: Arc<Mutex<Repo>>,
+    offline_delay: Duration,
+    users: Mutex<HashMap<u32, UserState>>
+}
+
+struct UserState {
+    sessions: usize,
+    /// Changes with every connection, an offline check scheduled before it is stale
+    epoch: u64,
+    /// When the last connection closed
+    left_at: i64
+}
+
+/// Connection of a user, counted until it is dropped
+pub struct Session {
+    presence: Arc<Presence>,
+    user_id: u32
+}
+
+impl Presence {
+    pub fn new(hub: Arc<Hub<ServerFrame>>, repo: Arc<Mutex<Repo>>, offline_delay: Duration) -> Self {
+        return Self { hub, repo, offline_delay, users: Mutex::new(HashMap::new()) };
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<u32, UserState>> {
+        // nothing can panic while the lock is held, the data is consistent anyway
+        return self.users.lock().unwrap_or_else(|e| e.into_inner());
+    }
+
+    /// Counts a new connection of the user, announcing them if they were offline
+    pub fn connect(self: &Arc<Self>, user_id: u32) -> Session {
+        let mut users = self.lock();
+        let came_online = !users.contains_key(&user_id);
+        let user = users.entry(user_id).or_insert(UserState { sessions: 0, epoch: 0, left_at: 0 });
+        user.sessions += 1;
+        user.epoch += 1;
+        if came_online {
+            self.hub.publish_all(&ServerFrame::Presence { user_id, online: true });
+        }
+        return Session { presence: self.clone(), user_id };
+    }
+
+    fn disconnect(self: &Arc<Self>, user_id: u32) {
+        let mut users = self.lock();
+        let user = match users.get_mut(&user_id) {
+            Some(user) => { user }
+            None => { return; }
+        };
+        user.sessions -= 1;
+        if user.sessions > 0 {
+            return;
+        }
+        user.left_at = now();
+        let epoch = user.epoch;
+        let presence = self.clone();
+        async_std::task::spawn(async move {
+            async_std::task::sleep(presence.offline_delay).await;
+            presence.settle(user_id, epoch);
+        });
+    }
+
+    /// Announces that the user went offline, unless they came back in the meantime
+    fn settle(&self, user_id: u32, epoch: u64) {
+        let left_at = match self.lock().get(&user_id) {
+            Some(user) if user.sessions == 0 && user.epoch == epoch => { user.left_at }
+            _ => { return; }
+        };
+
+        // stored before the announcement, so that whoever hears about it can look it up.
+        // The repo is locked by callers of `online_users`, it must not wait for the presence.
+        let stored = self.repo.lock()
+            .map_err(|e| e.to_string())
+            .and_then(|repo| repo.set_last_seen(user_id, left_at).map_err(|e| e.to_string()));
+        if let Err(e) = stored {
+            tide::log::warn!("Could not store last seen time of user {}: {}", user_id, e);
+        }
+
+        let mut users = self.lock();
+        match users.get(&user_id) {
+            Some(user) if user.sessions == 0 && user.epoch == epoch => {}
+            _ => { return; }
+        }
+        users.remove(&user_id);
+        self.hub.publish_all(&ServerFrame::Presence { user_id, online: false });
+    }
+
+    /// Users that are online, including those who just left and may come back
+    pub fn online_users(&self) -> HashSet<u32> {
+        return self.lock().keys().copied().collect();
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.presence.disconnect(self.user_id);
+    }
+}
+
+fn now() -> i64 {
+    return chrono::Utc::now().timestamp();
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use async_std::task::block_on;
+
+    fn presence(offline_delay: Duration) -> (Arc<Presence>, Arc<Hub<ServerFrame>>) {
+        let hub = Arc::new(Hub::new(8));
+        let repo = Repo::new(Path::new(":memory:")).unwrap();
+        repo.conn.execute_batch("
+            INSERT INTO users (username, password, color, name) VALUES ('alice', '', 'red', 'Alice');
+        ").unwrap();
+        let presence = Arc::new(Presence::new(hub.clone(), Arc::new(Mutex::new(repo)), offline_delay));
+        return (presence, hub);
+    }
+
+    fn online(frame: Option<ServerFrame>) -> bool {
+        match frame {
+            Some(ServerFrame::Presence { online, .. }) => { return online; }
+            _ => { panic!("Expected a presence event"); }
+        }
+    }
+
+    #[test]
+    fn announces_first_connection_and_last_disconnection() {
+        let (presence, hub) = presence(Duration::from_millis(10));
+        let watcher = hub.subscribe(2);
+        let tab = presence.connect(1);
+        assert!(online(block_on(watcher.recv())));
+        let phone = presence.connect(1);
+        drop(tab);
+        assert_eq!(presence.online_users(), [1].iter().copied().collect());
+
+        drop(phone);
+        assert!(!online(block_on(watcher.recv())));
+        assert!(presence.online_users().is_empty());
+        let last_seen = presence.repo.lock().unwrap().select_users_all().unwrap()[0].last_seen;
+        assert!(last_seen.is_some());
+    }
+
+    #[test]
+    fn ignores_quick_reconnects() {
+        let (presence, hub) = presence(Duration::from_millis(50));
+        let watcher = hub.subscribe(2);
+        drop(presence.connect(1));
+        let _reloaded = presence.connect(1);
+        assert!(online(block_on(watcher.recv())));
+        block_on(async_std::task::sleep(Duration::from_millis(100)));
+        assert_eq!(presence.online_users(), [1].iter().copied().collect());
+        hub.publish(vec![2], &ServerFrame::Typing { user_id: 3 });
+        assert!(matches!(block_on(watcher.recv()), Some(ServerFrame::Typing { .. })));
+    }
+}
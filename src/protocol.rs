@@ -0,0 +1,143 @@
+//! Frames of the websocket protocol, see `docs/websocket.md`.
+//!
+//! Every frame is a JSON object tagged with `type`. The first frame sent by
+//! the client is `hello`, the server answers with its own `hello`.
+
+use serde::{Deserialize, Serialize};
+use crate::model;
+
+pub const VERSION: u32 = 1;
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    /// `last_seen` is the id of the newest message the client has, newer ones are replayed
+    Hello {
+        version: u32,
+        token: String,
+        #[serde(default)]
+        last_seen: Option<u32>
+    },
+    /// Same as `POST /messages`, answered with `ack` or `error` carrying the `id`
+    SendMessage {
+        id: String,
+        #[serde(default)]
+        recipients: Vec<u32>,
+        text: String,
+        #[serde(default)]
+        reply_to: Option<u32>,
+        #[serde(default)]
+        channel_id: Option<u32>,
+        #[serde(default)]
+        send_at: Option<i64>,
+        #[serde(default)]
+        destruct_after: Option<u32>
+    },
+    /// Tells recipients of a message being written that the user is typing
+    Typing { recipients: Vec<u32> },
+    /// Same as `POST /messages/read`, answered with `ack` or `error`
+    MarkRead { id: String, message_ids: Vec<u32> },
+    Ping { id: String }
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    Hello { version: u32, user_id: u32 },
+    Message { message: model::MessageResponse },
+    /// A message was edited, it holds the new text
+    MessageEdited { message: model::MessageResponse },
+    /// A message was deleted, it is a tombstone now
+    MessageDeleted { message: model::MessageResponse },
+    /// Someone added or removed a reaction, the message holds all of them
+    MessageReacted { message: model::MessageResponse },
+    Ack {
+        id: String,
+        /// Id of the sent message, if the acknowledged frame was `send_message`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message_id: Option<u32>
+    },
+    Error {
+        /// Id of the frame that caused the error, if it had one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        /// Same as HTTP status codes
+        code: u16,
+        reason: String
+    },
+    Typing { user_id: u32 },
+    Presence { user_id: u32, online: bool },
+    /// The user was mentioned in a new message, sent after the message itself
+    Mention {
+        message_id: u32,
+        sender_id: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        channel_id: Option<u32>
+    },
+    /// Messages were deleted by their self-destruct timer or a retention policy
+    MessagesExpired { message_ids: Vec<u32> },
+    /// A user changed their name or color
+    UserUpdated { user: model::EmbeddedRecipient },
+    /// A recipient got or read a message sent by the user
+    Receipt(model::Receipt)
+}
+
+impl ServerFrame {
+    /// Message the event is about, if it is about one
+    pub fn message(&self) -> Option<&model::MessageResponse> {
+        match self {
+            ServerFrame::Message { message }
+            | ServerFrame::MessageEdited { message }
+            | ServerFrame::MessageDeleted { message }
+            | ServerFrame::MessageReacted { message } => { return Some(message); }
+            _ => { return None; }
+        }
+    }
+
+    pub fn error(id: Option<String>, code: u16, reason: impl ToString) -> Self {
+        return ServerFrame::Error { id, code, reason: reason.to_string() };
+    }
+}
+
+pub fn parse(text: &str) -> Result<ClientFrame, serde_json::Error> {
+    return serde_json::from_str(text);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_client_frames() {
+        assert_eq!(parse(r#"{"type":"hello","version":1,"token":"abc"}"#).unwrap(),
+            ClientFrame::Hello { version: 1, token: "abc".to_owned(), last_seen: None });
+        assert_eq!(parse(r#"{"type":"hello","version":1,"token":"abc","last_seen":7}"#).unwrap(),
+            ClientFrame::Hello { version: 1, token: "abc".to_owned(), last_seen: Some(7) });
+        assert_eq!(parse(r#"{"type":"send_message","id":"1","recipients":[2],"text":"hi"}"#).unwrap(),
+            ClientFrame::SendMessage { id: "1".to_owned(), recipients: vec![2], text: "hi".to_owned(), reply_to: None,
+                channel_id: None, send_at: None, destruct_after: None });
+        assert_eq!(parse(r#"{"type":"ping","id":"p"}"#).unwrap(), ClientFrame::Ping { id: "p".to_owned() });
+        assert_eq!(parse(r#"{"type":"mark_read","id":"r","message_ids":[1,2]}"#).unwrap(),
+            ClientFrame::MarkRead { id: "r".to_owned(), message_ids: vec![1, 2] });
+        assert!(parse(r#"{"type":"shout"}"#).is_err());
+        assert!(parse(r#"{"type":"typing"}"#).is_err());
+        assert!(parse("token").is_err());
+    }
+
+    #[test]
+    fn serializes_server_frames() {
+        let ack = ServerFrame::Ack { id: "1".to_owned(), message_id: None };
+        assert_eq!(serde_json::to_string(&ack).unwrap(), r#"{"type":"ack","id":"1"}"#);
+        let error = ServerFrame::error(None, 422, "Recipient 9 does not exist");
+        assert_eq!(serde_json::to_string(&error).unwrap(),
+            r#"{"type":"error","code":422,"reason":"Recipient 9 does not exist"}"#);
+        let presence = ServerFrame::Presence { user_id: 2, online: true };
+        assert_eq!(serde_json::to_string(&presence).unwrap(), r#"{"type":"presence","user_id":2,"online":true}"#);
+        let receipt = ServerFrame::Receipt(model::Receipt {
+            message_id: 5, sender_id: 1, user_id: 2, delivered_at: 100, read_at: None
+        });
+        assert_eq!(serde_json::to_string(&receipt).unwrap(),
+            r#"{"type":"receipt","message_id":5,"user_id":2,"delivered_at":100,"read_at":null}"#);
+    }
+}
@@ -3,20 +3,248 @@ use itertools::Itertools;
 use std::iter::{IntoIterator};
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::convert::TryInto;
+use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
 use crate::model as m;
+use crate::migrations::{self, MigrationError};
+use crate::util;
+use crate::markdown;
+
+/// Characters of the parent message quoted in a reply
+const QUOTE_LENGTH: usize = 80;
+
+/// Words of a message around matches shown in a search snippet
+const SNIPPET_WORDS: u32 = 12;
+
+/// Characters allowed in a channel name
+const MAX_CHANNEL_NAME_LENGTH: usize = 64;
+
+/// Condition on message `m` being sent or received by user `?1`, or sent to
+/// a channel they are a member of
+const VISIBLE_TO_USER: &str = "
+    m.send_at IS NULL AND (m.user_id = ?1
+        OR EXISTS (SELECT 1 FROM message_recipients WHERE message_id = m.id AND user_id = ?1)
+        OR EXISTS (SELECT 1 FROM channel_members WHERE channel_id = m.channel_id AND user_id = ?1))
+";
+
+/// Messages a page of history is taken from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Conversation {
+    /// Every message the user sent or received
+    All,
+    /// Messages sent to chosen users, not to a channel
+    Direct,
+    Channel(u32)
+}
+
+/// Global retention policy, zero means no limit
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Retention {
+    /// Messages older than that many days are deleted
+    pub days: u32,
+    /// Only that many latest messages of each channel are kept, direct messages are not counted
+    pub keep_last: u32
+}
+
+/// Message deleted by `purge_messages`
+#[derive(Debug, PartialEq)]
+pub struct PurgedMessage {
+    pub id: u32,
+    pub channel_id: Option<u32>,
+    /// Sender and recipients
+    pub user_ids: Vec<u32>
+}
 
 
 pub struct Repo {
     pub conn: Connection
 }
 
+#[derive(Debug)]
+pub enum InsertMessageError {
+    NoRecipients,
+    UnknownRecipient(u32),
+    DuplicateRecipient(u32),
+    /// Replied message does not exist or is not visible to the sender
+    UnknownParent(u32),
+    UnknownChannel(u32),
+    /// Only members can write to a channel
+    NotMember(u32),
+    /// Channel messages go to its members, recipients can not be chosen
+    ChannelWithRecipients,
+    /// Self-destruct timer of zero seconds
+    InvalidSelfDestruct,
+    Database(Error)
+}
+
+impl InsertMessageError {
+    /// HTTP status code to respond with
+    pub fn status(&self) -> u16 {
+        match self {
+            InsertMessageError::NotMember(_) => 403,
+            InsertMessageError::Database(_) => 500,
+            _ => 422
+        }
+    }
+}
+
+impl fmt::Display for InsertMessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InsertMessageError::NoRecipients => write!(f, "No message recipients provided"),
+            InsertMessageError::UnknownRecipient(id) => write!(f, "Recipient {} does not exist", id),
+            InsertMessageError::DuplicateRecipient(id) => write!(f, "Recipient {} is listed more than once", id),
+            InsertMessageError::UnknownParent(id) => write!(f, "Message {} does not exist", id),
+            InsertMessageError::UnknownChannel(id) => write!(f, "Channel {} does not exist", id),
+            InsertMessageError::NotMember(id) => write!(f, "Not a member of channel {}", id),
+            InsertMessageError::ChannelWithRecipients => write!(f, "Channel messages can not have recipients"),
+            InsertMessageError::InvalidSelfDestruct => write!(f, "Self-destruct timer must be at least one second"),
+            InsertMessageError::Database(e) => write!(f, "Could not save message: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for InsertMessageError {}
+
+impl From<Error> for InsertMessageError {
+    fn from(e: Error) -> Self {
+        return InsertMessageError::Database(e);
+    }
+}
+
+#[derive(Debug)]
+pub enum EditMessageError {
+    NotFound,
+    /// Only the sender can change a message
+    NotSender,
+    Deleted,
+    Database(Error)
+}
+
+impl EditMessageError {
+    /// HTTP status code to respond with
+    pub fn status(&self) -> u16 {
+        match self {
+            EditMessageError::NotFound => 404,
+            EditMessageError::NotSender => 403,
+            EditMessageError::Deleted => 410,
+            EditMessageError::Database(_) => 500
+        }
+    }
+}
+
+impl fmt::Display for EditMessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EditMessageError::NotFound => write!(f, "Message does not exist"),
+            EditMessageError::NotSender => write!(f, "Only the sender can change a message"),
+            EditMessageError::Deleted => write!(f, "Message is deleted"),
+            EditMessageError::Database(e) => write!(f, "Could not change message: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for EditMessageError {}
+
+impl From<Error> for EditMessageError {
+    fn from(e: Error) -> Self {
+        return EditMessageError::Database(e);
+    }
+}
+
+#[derive(Debug)]
+pub enum RegisterError {
+    /// Invite does not exist, expired or was used up
+    InvalidInvite,
+    UsernameTaken,
+    Database(Error)
+}
+
+impl RegisterError {
+    /// HTTP status code to respond with
+    pub fn status(&self) -> u16 {
+        match self {
+            RegisterError::InvalidInvite => 403,
+            RegisterError::UsernameTaken => 409,
+            RegisterError::Database(_) => 500
+        }
+    }
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterError::InvalidInvite => write!(f, "Invite code is not valid"),
+            RegisterError::UsernameTaken => write!(f, "Username is taken"),
+            RegisterError::Database(e) => write!(f, "Could not register: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for RegisterError {}
+
+impl From<Error> for RegisterError {
+    fn from(e: Error) -> Self {
+        return RegisterError::Database(e);
+    }
+}
+
+#[derive(Debug)]
+pub enum ChannelError {
+    NotFound,
+    InvalidName,
+    NameTaken,
+    Database(Error)
+}
+
+impl ChannelError {
+    /// HTTP status code to respond with
+    pub fn status(&self) -> u16 {
+        match self {
+            ChannelError::NotFound => 404,
+            ChannelError::InvalidName => 422,
+            ChannelError::NameTaken => 409,
+            ChannelError::Database(_) => 500
+        }
+    }
+}
+
+impl fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelError::NotFound => write!(f, "Channel does not exist"),
+            ChannelError::InvalidName => write!(f,
+                "Channel name must have between 1 and {} characters", MAX_CHANNEL_NAME_LENGTH),
+            ChannelError::NameTaken => write!(f, "Channel with this name already exists"),
+            ChannelError::Database(e) => write!(f, "Could not change channel: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for ChannelError {}
+
+impl From<Error> for ChannelError {
+    fn from(e: Error) -> Self {
+        return ChannelError::Database(e);
+    }
+}
+
 struct MessageRow {
     id: u32,
     text: String,
-    recipient_id: u32,
-    recipient_name: String,
-    recipient_color: String,
+    html: String,
+    edited_at: Option<i64>,
+    deleted: bool,
+    reply_to: Option<u32>,
+    quote: Option<m::Quote>,
+    channel_id: Option<u32>,
+    channel_name: Option<String>,
+    send_at: Option<i64>,
+    destruct_after: Option<u32>,
+    /// Missing for channel messages nobody but the sender got
+    recipient: Option<m::MessageRecipient>,
     timestamp: i64,
     sender_name: String,
     sender_color: String,
@@ -24,201 +252,1678 @@ struct MessageRow {
 }
 
 impl Repo {
-    pub fn new(filename: &str) -> Result<Self, Error> {
-        let conn = Connection::open(filename)?;
-        conn.execute("
-            CREATE TABLE IF NOT EXISTS users (
-                username TEXT NOT NULL,
-                password TEXT NOT NULL,
-                color TEXT NOT NULL,
-                name TEXT NOT NULL
-            );
-        ", rusqlite::NO_PARAMS)?;
-        conn.execute("
-            CREATE TABLE IF NOT EXISTS messages (
-                text TEXT NOT NULL,
-                user_id INTEGER NOT NULL,
-                timestamp INTEGER NOT NULL,
-                FOREIGN KEY (user_id) REFERENCES users(ROWID)
-            )
-        ", rusqlite::NO_PARAMS)?;
-        conn.execute("
-            CREATE TABLE IF NOT EXISTS message_recipients (
-                user_id INTEGER NOT NULL,
-                message_id INTEGER NOT NULL,
-                FOREIGN KEY (user_id) REFERENCES users(ROWID),
-                FOREIGN KEY (message_id) REFERENCES messages(ROWID)
-            );
-        ", rusqlite::NO_PARAMS)?;
-        conn.execute("
-            CREATE TABLE IF NOT EXISTS files (
-                original_name TEXT_NOT_NULL,
-                stored_name TEXT NOT NULL,
-                owner_id INTEGER NOT NULL,
-                is_deleted INTEGER NOT NULL,
-                FOREIGN KEY (owner_id) REFERENCES users(ROWID)
-            );
-        ", rusqlite::NO_PARAMS)?;
+    /// Opens the database and brings its schema up to date
+    pub fn new(filename: &Path) -> Result<Self, MigrationError> {
+        let mut conn = Connection::open(filename)?;
+        // lets purging return free pages to the file system, applies to new databases only
+        conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL;")?;
+        migrations::migrate(&mut conn)?;
         return Ok(Self { conn });
     }
 
-    pub fn get_authenticated_user_id(&self, cred: &m::UserCredentials) -> Result<Option<u32>, Error> {
+    pub fn select_user_credentials(&self, username: &str) -> Result<Option<m::UserCredentials>, Error> {
         let mut stmt = self.conn.prepare("
-            SELECT u.ROWID FROM users u 
-            WHERE lower(u.username) = ?1 AND u.password = ?2
+            SELECT u.ROWID, u.username, u.password FROM users u 
+            WHERE lower(u.username) = lower(?1)
         ")?;
 
-        match stmt.query_row(params![cred.username.clone().to_lowercase(), cred.password], |row| {
-            Ok( row.get(0)? )
+        match stmt.query_row(params![ username ], |row| {
+            Ok(m::UserCredentials { id: row.get(0)?, username: row.get(1)?, password: row.get(2)? })
         }) {
-            Ok(id) => { return Ok(Some(id)); }
+            Ok(cred) => { return Ok(Some(cred)); }
             Err(Error::QueryReturnedNoRows) => { return Ok(None) }
             Err(n) => { return Err(n) }
         }
     }
 
-    pub fn register_user(&self, cred: &m::UserCredentials) -> Result<Option<u32>, Error> {
+    /// Adds a user. With an empty password hash the account can not log in.
+    pub fn insert_user(&self, username: &str, name: &str, color: &str, password_hash: &str) -> Result<u32, Error> {
+        self.conn.execute("
+            INSERT INTO users (username, name, color, password) VALUES (?1, ?2, ?3, ?4)
+        ", params![ username, name, color, password_hash ])?;
+        return Ok(self.conn.last_insert_rowid().try_into().unwrap());
+    }
+
+    /// Selects every user with the number of messages they sent, by username
+    pub fn select_user_summaries(&self) -> Result<Vec<m::UserSummary>, Error> {
+        let mut stmt = self.conn.prepare("
+            SELECT u.id, u.username, u.name, u.color, u.password != '',
+                (SELECT count(*) FROM messages WHERE user_id = u.id)
+            FROM users u
+            ORDER BY lower(u.username)
+        ")?;
+
+        return stmt.query_map(params![], |row| {
+            Ok(m::UserSummary {
+                id: row.get(0)?,
+                username: row.get(1)?,
+                name: row.get(2)?,
+                color: row.get(3)?,
+                registered: row.get(4)?,
+                message_count: row.get(5)?
+            })
+        })?.collect::<Result<Vec<_>,_>>();
+    }
+
+    /// Deletes a user with the messages and files they sent and their
+    /// memberships. Replies to their messages stop quoting them, messages
+    /// they received lose them as a recipient. Returns stored names of the
+    /// deleted files, which are left on disk.
+    pub fn delete_user(&self, user_id: u32) -> Result<Vec<String>, Error> {
+        let tx = self.conn.unchecked_transaction()?;
+        let stored_names = tx.prepare("SELECT stored_name FROM files WHERE owner_id = ?1")?
+            .query_map(params![ user_id ], |row| row.get(0))?
+            .collect::<Result<Vec<String>,_>>()?;
+        tx.execute_batch(&format!("
+            UPDATE messages SET reply_to = NULL
+                WHERE reply_to IN (SELECT id FROM messages WHERE user_id = {user});
+            DELETE FROM message_revisions
+                WHERE message_id IN (SELECT id FROM messages WHERE user_id = {user});
+            DELETE FROM message_recipients
+                WHERE user_id = {user} OR message_id IN (SELECT id FROM messages WHERE user_id = {user});
+            DELETE FROM message_mentions
+                WHERE user_id = {user} OR message_id IN (SELECT id FROM messages WHERE user_id = {user});
+            DELETE FROM message_reactions
+                WHERE user_id = {user} OR message_id IN (SELECT id FROM messages WHERE user_id = {user});
+            DELETE FROM message_files
+                WHERE file_id IN (SELECT id FROM files WHERE owner_id = {user})
+                    OR message_id IN (SELECT id FROM messages WHERE user_id = {user});
+            DELETE FROM files WHERE owner_id = {user};
+            DELETE FROM messages WHERE user_id = {user};
+            DELETE FROM channel_members WHERE user_id = {user};
+            UPDATE channels SET created_by = NULL WHERE created_by = {user};
+            DELETE FROM users WHERE id = {user};
+        ", user = user_id))?;
+        tx.commit()?;
+        return Ok(stored_names);
+    }
+
+    pub fn select_stats(&self) -> Result<m::DatabaseStats, Error> {
+        let count = |sql: &str| self.conn.query_row(sql, params![], |row| row.get::<_, i64>(0));
+        return Ok(m::DatabaseStats {
+            schema_version: migrations::schema_version(&self.conn)?,
+            users: count("SELECT count(*) FROM users")?,
+            messages: count("SELECT count(*) FROM messages")?,
+            deleted_messages: count("SELECT count(*) FROM messages WHERE deleted_at IS NOT NULL")?,
+            channels: count("SELECT count(*) FROM channels")?,
+            files: count("SELECT count(*) FROM files WHERE is_deleted = 0")?,
+            size_bytes: count("PRAGMA page_count")? * count("PRAGMA page_size")?
+        });
+    }
+
+    /// Compacts the search index and the database file. Databases created
+    /// before purging was introduced are switched to incremental vacuuming.
+    pub fn vacuum(&self) -> Result<(), Error> {
+        self.conn.execute_batch("
+            INSERT INTO messages_fts (messages_fts) VALUES ('optimize');
+            PRAGMA auto_vacuum = INCREMENTAL;
+            VACUUM;
+        ")?;
+        return Ok(());
+    }
+
+    pub fn select_profile(&self, user_id: u32) -> Result<Option<m::Profile>, Error> {
+        match self.conn.query_row("
+            SELECT ROWID, username, name, color FROM users WHERE ROWID = ?1
+        ", params![ user_id ], |row| {
+            Ok(m::Profile {
+                id: row.get(0)?,
+                username: row.get(1)?,
+                name: row.get(2)?,
+                color: row.get(3)?
+            })
+        }) {
+            Ok(profile) => { return Ok(Some(profile)); }
+            Err(Error::QueryReturnedNoRows) => { return Ok(None); }
+            Err(n) => { return Err(n); }
+        }
+    }
+
+    /// Changes name and color of a user, those that are `None` are kept.
+    /// Values are checked by the caller.
+    pub fn update_profile(&self, user_id: u32, name: Option<&str>, color: Option<&str>) -> Result<(), Error> {
+        self.conn.execute("
+            UPDATE users SET name = coalesce(?2, name), color = coalesce(?3, color) WHERE ROWID = ?1
+        ", params![ user_id, name, color ])?;
+        return Ok(());
+    }
+
+    /// Adds a user with an invite, using it up once. Fields are checked by the caller.
+    pub fn register_user(&self, req: &m::RegisterRequest, color: &str, password_hash: &str) -> Result<u32, RegisterError> {
+        let tx = self.conn.unchecked_transaction()?;
+        let invite_id: u32 = match tx.query_row("
+            SELECT id FROM invites WHERE code = ?1 AND uses < max_uses AND expires_at > ?2
+        ", params![ req.invite.trim(), now() ], |row| row.get(0)) {
+            Ok(id) => { id }
+            Err(Error::QueryReturnedNoRows) => { return Err(RegisterError::InvalidInvite); }
+            Err(n) => { return Err(n.into()); }
+        };
+        tx.execute("UPDATE invites SET uses = uses + 1 WHERE id = ?1", params![ invite_id ])?;
+        // the unique index on usernames decides, another process may have added the user just now
+        let inserted = tx.execute("
+            INSERT INTO users (username, name, color, password, invite_id) VALUES (?1, ?2, ?3, ?4, ?5)
+        ", params![ req.username, req.name.trim(), color, password_hash, invite_id ]);
+        match inserted {
+            Ok(_) => {}
+            Err(Error::SqliteFailure(e, _)) if e.code == rusqlite::ErrorCode::ConstraintViolation => {
+                return Err(RegisterError::UsernameTaken);
+            }
+            Err(e) => { return Err(e.into()); }
+        }
+        let user_id = tx.last_insert_rowid().try_into().unwrap();
+        tx.commit()?;
+        return Ok(user_id);
+    }
+
+    /// Creates an invite that can be used `max_uses` times until `expires_at`
+    pub fn create_invite(&self, max_uses: u32, expires_at: i64) -> Result<m::Invite, Error> {
+        let invite = m::Invite { code: util::generate_invite_code(), created_at: now(), expires_at, max_uses, uses: 0 };
+        self.conn.execute("
+            INSERT INTO invites (code, created_at, expires_at, max_uses) VALUES (?1, ?2, ?3, ?4)
+        ", params![ invite.code, invite.created_at, invite.expires_at, invite.max_uses ])?;
+        return Ok(invite);
+    }
+
+    /// Selects invites that can still be used, newest first
+    pub fn select_valid_invites(&self) -> Result<Vec<m::Invite>, Error> {
+        let mut stmt = self.conn.prepare("
+            SELECT code, created_at, expires_at, max_uses, uses FROM invites
+            WHERE uses < max_uses AND expires_at > ?1
+            ORDER BY id DESC
+        ")?;
+
+        return stmt.query_map(params![ now() ], |row| {
+            Ok(m::Invite {
+                code: row.get(0)?,
+                created_at: row.get(1)?,
+                expires_at: row.get(2)?,
+                max_uses: row.get(3)?,
+                uses: row.get(4)?
+            })
+        })?.collect::<Result<Vec<_>,_>>();
+    }
+
+    /// Makes an invite expire now. Returns false if it was not valid anyway.
+    pub fn revoke_invite(&self, code: &str) -> Result<bool, Error> {
+        let now = now();
         let updated_rows = self.conn.execute("
-            UPDATE users
-            SET password = ?1
-            WHERE username = :2 AND password = ''
-        ", params![ cred.password, cred.username ])?;
-
-        if updated_rows > 0 {
-            return self.get_authenticated_user_id(cred);
-        } else {
-            return Ok(None);
+            UPDATE invites SET expires_at = ?2 WHERE code = ?1 AND expires_at > ?2
+        ", params![ code, now ])?;
+        return Ok(updated_rows > 0);
+    }
+
+    /// Replaces password hash. When the password itself changes, sessions 
+    /// should be revoked; rehashing the same password keeps them.
+    pub fn set_user_password(&self, user_id: u32, password_hash: &str, revoke_sessions: bool) -> Result<(), Error> {
+        self.conn.execute("
+            UPDATE users 
+            SET password = ?1, token_generation = token_generation + ?3 
+            WHERE ROWID = ?2
+        ", params![ password_hash, user_id, revoke_sessions as u32 ])?;
+        return Ok(());
+    }
+
+    pub fn select_token_generation(&self, user_id: u32) -> Result<Option<u32>, Error> {
+        let mut stmt = self.conn.prepare_cached("
+            SELECT token_generation FROM users WHERE ROWID = ?1
+        ")?;
+
+        match stmt.query_row(params![ user_id ], |row| row.get(0)) {
+            Ok(generation) => { return Ok(Some(generation)); }
+            Err(Error::QueryReturnedNoRows) => { return Ok(None) }
+            Err(n) => { return Err(n) }
         }
-        
     }
 
-    pub fn select_messages_for_user(&self, user_id: u32) -> Result<Vec<m::MessageResponse>, Error> {
-        let mut stmt = self.conn.prepare(" 
+    /// Invalidates all tokens issued to the user
+    pub fn revoke_sessions(&self, user_id: u32) -> Result<(), Error> {
+        self.conn.execute("
+            UPDATE users SET token_generation = token_generation + 1 WHERE ROWID = ?1
+        ", params![ user_id ])?;
+        return Ok(());
+    }
+
+    /// Invalidates all tokens issued to anyone. Returns number of affected users.
+    pub fn revoke_all_sessions(&self) -> Result<usize, Error> {
+        return self.conn.execute("
+            UPDATE users SET token_generation = token_generation + 1
+        ", rusqlite::NO_PARAMS);
+    }
+
+    /// Selects a page of messages of a conversation sent or received by the
+    /// user, oldest first. Without cursors the newest messages are returned.
+    /// Pages are taken from the `before` end, unless only `after` is given.
+    /// Membership in a channel is checked by the caller.
+    pub fn select_messages_for_user(
+        &self,
+        user_id: u32,
+        conversation: Conversation,
+        before: Option<u32>,
+        after: Option<u32>,
+        limit: u32
+    ) -> Result<m::MessagePage, Error> {
+        let forward = after.is_some() && before.is_none();
+        let order = if forward { "ASC" } else { "DESC" };
+        // ?1 is the user, or the channel for a channel page. Every query walks
+        // an index from the cursor, so cost depends on the page size only;
+        // direct messages also skip channel messages on the way.
+        let (page, owner_id) = match conversation {
+            Conversation::Channel(channel_id) => {
+                (format!("
+                    SELECT id FROM messages
+                    WHERE channel_id = ?1 AND id > ?2 AND id < ?3 AND send_at IS NULL
+                    ORDER BY id {order} LIMIT ?4
+                ", order = order), channel_id)
+            }
+            Conversation::All | Conversation::Direct => {
+                let direct = if conversation == Conversation::Direct { "AND m.channel_id IS NULL" } else { "" };
+                (format!("
+                    SELECT id FROM (
+                        SELECT id FROM (
+                            SELECT mr.message_id AS id
+                            FROM message_recipients mr JOIN messages m ON m.id = mr.message_id
+                            WHERE mr.user_id = ?1 AND mr.message_id > ?2 AND mr.message_id < ?3
+                                AND m.send_at IS NULL {direct}
+                            ORDER BY mr.message_id {order} LIMIT ?4)
+                        UNION
+                        SELECT id FROM (
+                            SELECT id FROM messages m
+                            WHERE m.user_id = ?1 AND m.id > ?2 AND m.id < ?3 AND m.send_at IS NULL {direct}
+                            ORDER BY m.id {order} LIMIT ?4))
+                    ORDER BY id {order} LIMIT ?4
+                ", order = order, direct = direct), user_id)
+            }
+        };
+
+        let mut messages = self.select_messages_where(&format!("m.id IN ({})", page), params![
+            owner_id, after.unwrap_or(0), before.map(i64::from).unwrap_or(i64::MAX), limit + 1
+        ])?;
+        let has_more = messages.len() > limit as usize;
+        if has_more {
+            if forward { messages.pop(); } else { messages.remove(0); }
+        }
+        return Ok(m::MessagePage { messages, has_more });
+    }
+
+    /// Selects messages matching an SQL condition on `m`, oldest first
+    fn select_messages_where<P>(&self, condition: &str, params: P) -> Result<Vec<m::MessageResponse>, Error>
+        where P: IntoIterator, P::Item: rusqlite::ToSql
+    {
+        let mut stmt = self.conn.prepare(&format!(" 
             SELECT 
-                m.ROWID, m.text, m.timestamp, mr.user_id, ur.name, ur.color, us.color, us.name, m.user_id
+                m.id, m.text, m.timestamp, mr.user_id, ur.name, ur.color, us.color, us.name, m.user_id,
+                mr.delivered_at, mr.read_at, m.edited_at, m.deleted_at IS NOT NULL,
+                m.reply_to, up.name, p.text, p.deleted_at IS NOT NULL, m.channel_id, c.name, m.html, m.send_at,
+                m.destruct_after
             FROM messages m
-                JOIN message_recipients mr ON mr.message_id = m.ROWID
-                JOIN users ur ON ur.ROWID = mr.user_id
-                JOIN users us ON us.ROWID = m.user_id
-            WHERE 
-                m.ROWID IN ( 
-                    SELECT message_id 
-                    FROM message_recipients mr2 JOIN users u2 ON mr2.user_id = u2.ROWID
-                    WHERE u2.ROWID = ?1) 
-                OR m.user_id = ?1
-            ORDER BY
-                m.timestamp
-        ")?;
+                JOIN users us ON us.id = m.user_id
+                LEFT JOIN message_recipients mr ON mr.message_id = m.id
+                LEFT JOIN users ur ON ur.id = mr.user_id
+                LEFT JOIN messages p ON p.id = m.reply_to
+                LEFT JOIN users up ON up.id = p.user_id
+                LEFT JOIN channels c ON c.id = m.channel_id
+            WHERE {}
+            ORDER BY m.id, mr.user_id
+        ", condition))?;
 
-        let row_array = stmt.query_map(params![ user_id ], |row| {
+        let row_array = stmt.query_map(params, |row| {
             Ok( MessageRow { 
                 id: row.get(0)?,
                 text: row.get(1)?,
                 timestamp: row.get(2)?,
-                recipient_id: row.get(3)?,
-                recipient_name: row.get(4)?,
-                recipient_color: row.get(5)?,
+                recipient: match row.get::<_, Option<u32>>(3)? {
+                    Some(id) => {
+                        Some(m::MessageRecipient {
+                            id,
+                            name: row.get(4)?,
+                            color: row.get(5)?,
+                            delivered_at: row.get(9)?,
+                            read_at: row.get(10)?
+                        })
+                    }
+                    None => { None }
+                },
                 sender_color: row.get(6)?,
                 sender_name: row.get(7)?,
-                sender_id: row.get(8)?
+                sender_id: row.get(8)?,
+                edited_at: row.get(11)?,
+                deleted: row.get(12)?,
+                reply_to: row.get(13)?,
+                quote: match row.get::<_, Option<String>>(14)? {
+                    Some(sender_name) => {
+                        let text: String = row.get(15)?;
+                        Some(m::Quote { sender_name, text: util::excerpt(&text, QUOTE_LENGTH), deleted: row.get(16)? })
+                    }
+                    None => { None }
+                },
+                channel_id: row.get(17)?,
+                channel_name: row.get(18)?,
+                html: row.get(19)?,
+                send_at: row.get(20)?,
+                destruct_after: row.get(21)?
             })
         })?.collect::<Result<Vec<_>,_>>()?;
-        Ok(message_rows_to_message(row_array.into_iter()))
+        let mut messages = message_rows_to_message(row_array.into_iter());
+        for message in messages.iter_mut() {
+            message.attachments = self.select_attachments(message.id)?;
+            message.mentions = self.select_mentions(message.id)?;
+            message.reactions = self.select_reactions(message.id)?;
+        }
+        return Ok(messages);
     }
 
-    pub fn select_users_all(&self) -> Result<Vec<m::EmbeddedRecipient>, Error> {
-        let mut stmt = self.conn.prepare(" 
-            SELECT ROWID, name, color FROM users ORDER BY name
+    pub fn select_attachments(&self, message_id: u32) -> Result<Vec<m::EmbeddedAttachment>, Error> {
+        let mut stmt = self.conn.prepare_cached("
+            SELECT f.ROWID, f.original_name
+            FROM message_files mf JOIN files f ON f.ROWID = mf.file_id
+            WHERE mf.message_id = ?1 AND f.is_deleted = 0
+            ORDER BY f.ROWID
         ")?;
 
-        return Ok(stmt.query_map(params![], |row| {
-            Ok(m::EmbeddedRecipient {
+        return stmt.query_map(params![ message_id ], |row| {
+            Ok(m::EmbeddedAttachment {
                 id: row.get(0)?,
-                name: row.get(1)?,
-                color: row.get(2)?
+                name: row.get(1)?
             })
-        })?.collect::<Result<Vec<_>,_>>()?);
+        })?.collect::<Result<Vec<_>,_>>();
     }
 
-    pub fn select_message_by_id(&self, rowid: u32) -> Result<m::MessageResponse, Error> {
+    /// Finds a file that was attached to a message the user can see
+    pub fn select_file_for_user(&self, file_id: u32, user_id: u32) -> Result<Option<m::StoredFile>, Error> {
+        let mut stmt = self.conn.prepare(&format!("
+            SELECT f.original_name, f.stored_name
+            FROM files f
+            WHERE f.id = ?2 AND f.is_deleted = 0 AND (
+                f.owner_id = ?1 OR EXISTS (
+                    SELECT 1
+                    FROM message_files mf
+                        JOIN messages m ON m.id = mf.message_id
+                    WHERE mf.file_id = f.id AND {}))
+        ", VISIBLE_TO_USER))?;
+
+        match stmt.query_row(params![ user_id, file_id ], |row| {
+            Ok(m::StoredFile { original_name: row.get(0)?, stored_name: row.get(1)? })
+        }) {
+            Ok(file) => { return Ok(Some(file)); }
+            Err(Error::QueryReturnedNoRows) => { return Ok(None) }
+            Err(n) => { return Err(n) }
+        }
+    }
+
+    /// Selects every user, `online` is left for the caller to fill in
+    pub fn select_users_all(&self) -> Result<Vec<m::User>, Error> {
         let mut stmt = self.conn.prepare(" 
-            SELECT 
-                m.ROWID, m.text, m.timestamp, mr.user_id, ur.name, ur.color, us.name, us.color, m.user_id
-            FROM messages m
-                JOIN message_recipients mr ON mr.message_id = m.ROWID
-                JOIN users ur ON ur.ROWID = mr.user_id
-                JOIN users us ON us.ROWID = m.user_id
-            WHERE 
-                m.ROWID = ?1
+            SELECT ROWID, username, name, color, last_seen_at FROM users ORDER BY name
         ")?;
 
-        let row_array = stmt.query_map(params![ rowid ], |row| {
-            Ok( MessageRow { 
+        return stmt.query_map(params![], |row| {
+            Ok(m::User {
                 id: row.get(0)?,
-                text: row.get(1)?,
-                timestamp: row.get(2)?,
-                recipient_id: row.get(3)?,
-                recipient_name: row.get(4)?,
-                recipient_color: row.get(5)?,
-                sender_name: row.get(6)?,
-                sender_color: row.get(7)?,
-                sender_id: row.get(8)?
+                username: row.get(1)?,
+                name: row.get(2)?,
+                color: row.get(3)?,
+                online: false,
+                last_seen: row.get(4)?
             })
-        })?.collect::<Result<Vec<_>,_>>()?;
-        return message_rows_to_message(row_array.into_iter())
+        })?.collect::<Result<Vec<_>,_>>();
+    }
+
+    /// Users that can be mentioned by lowercase username, all of them if `among` is none
+    fn select_mentionable(&self, among: Option<&[u32]>) -> Result<HashMap<String, u32>, Error> {
+        return Ok(self.select_users_all()?.into_iter()
+            .filter(|u| among.map(|ids| ids.contains(&u.id)).unwrap_or(true))
+            .map(|u| (u.username.to_ascii_lowercase(), u.id))
+            .collect());
+    }
+
+    fn replace_mentions(&self, message_id: u32, mentions: &[u32]) -> Result<(), Error> {
+        self.conn.execute("DELETE FROM message_mentions WHERE message_id = ?1", params![ message_id ])?;
+        let mut insert = self.conn.prepare_cached("INSERT INTO message_mentions (message_id, user_id) VALUES (?1, ?2)")?;
+        for user_id in mentions {
+            insert.execute(params![ message_id, user_id ])?;
+        }
+        return Ok(());
+    }
+
+    pub fn select_mentions(&self, message_id: u32) -> Result<Vec<u32>, Error> {
+        let mut stmt = self.conn.prepare_cached("
+            SELECT user_id FROM message_mentions WHERE message_id = ?1 ORDER BY user_id
+        ")?;
+        return stmt.query_map(params![ message_id ], |row| row.get(0))?.collect::<Result<Vec<_>,_>>();
+    }
+
+    /// Records when the user closed their last connection
+    pub fn set_last_seen(&self, user_id: u32, at: i64) -> Result<(), Error> {
+        self.conn.execute("UPDATE users SET last_seen_at = ?2 WHERE ROWID = ?1", params![ user_id, at ])?;
+        return Ok(());
+    }
+
+    pub fn select_message_by_id(&self, message_id: u32) -> Result<m::MessageResponse, Error> {
+        return self.select_messages_where("m.id = ?1", params![ message_id ])?
             .into_iter()
             .next()
             .ok_or(Error::QueryReturnedNoRows);
     }
 
-    pub fn insert_message(&self, sender_id: u32, req: m::PostMessageRequest) -> Result<m::MessageResponse, Error> {
-        let now: i64 = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Can not count time anymore")
-            .as_secs()
-            .try_into()
-            .expect("Can not count this much time");
+    pub fn insert_message(
+        &self, 
+        sender_id: u32, 
+        req: m::PostMessageRequest, 
+        files: &[m::StoredFile]
+    ) -> Result<m::MessageResponse, InsertMessageError> {
+        let now = now();
+        if req.channel_id.is_some() && !req.recipients.is_empty() {
+            return Err(InsertMessageError::ChannelWithRecipients);
+        }
+        if req.destruct_after == Some(0) {
+            return Err(InsertMessageError::InvalidSelfDestruct);
+        }
+        // a time that has passed sends the message now
+        let send_at = req.send_at.filter(|t| *t > now);
 
-        self.conn.execute(
-            " INSERT INTO messages (text, user_id, timestamp) VALUES (?1, ?2, ?3) ",
-            &[req.text.clone(), sender_id.to_string(), now.to_string()]
+        // rolled back on drop unless committed
+        let tx = self.conn.unchecked_transaction()?;
+        let mut recipients: Vec<u32> = match req.channel_id {
+            Some(channel_id) => {
+                if !self.channel_exists(channel_id)? {
+                    return Err(InsertMessageError::UnknownChannel(channel_id));
+                }
+                let members = self.select_channel_member_ids(channel_id)?;
+                if !members.contains(&sender_id) {
+                    return Err(InsertMessageError::NotMember(channel_id));
+                }
+                // recipient rows keep receipts of the members at the time of sending
+                members.into_iter().filter(|id| *id != sender_id).collect()
+            }
+            None => { req.recipients }
+        };
+        {
+            let mut user_exists = tx.prepare("SELECT 1 FROM users WHERE ROWID = ?1")?;
+            let mut seen = HashSet::new();
+            for recp in recipients.iter() {
+                if !seen.insert(*recp) {
+                    return Err(InsertMessageError::DuplicateRecipient(*recp));
+                }
+                if !user_exists.exists(params![ recp ])? {
+                    return Err(InsertMessageError::UnknownRecipient(*recp));
+                }
+            }
+        }
+        // mentioned users get a direct message, in a channel only members can be mentioned
+        let mentionable = match req.channel_id {
+            Some(_) => { self.select_mentionable(Some(&recipients))? }
+            None => { self.select_mentionable(None)? }
+        };
+        let rendered = markdown::render(&req.text, &mentionable);
+        let mentions: Vec<u32> = rendered.mentions.into_iter().filter(|id| *id != sender_id).collect();
+        for user_id in mentions.iter() {
+            if !recipients.contains(user_id) {
+                recipients.push(*user_id);
+            }
+        }
+        if recipients.is_empty() && req.channel_id.is_none() {
+            return Err(InsertMessageError::NoRecipients);
+        }
+
+        if let Some(parent) = req.reply_to {
+            if !self.is_visible(sender_id, parent)? {
+                return Err(InsertMessageError::UnknownParent(parent));
+            }
+        }
+
+        tx.execute(
+            "
+                INSERT INTO messages (text, html, user_id, timestamp, reply_to, channel_id, send_at, destruct_after)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+            ",
+            params![ req.text, rendered.html, sender_id, now, req.reply_to, req.channel_id, send_at, req.destruct_after ]
         )?;
 
-        let rowid = self.conn.last_insert_rowid();
-        // TODO: Wrap in transaction 
-        for recp in req.recipients.iter() {
-            self.conn.execute(
+        let rowid = tx.last_insert_rowid();
+        self.replace_mentions(rowid.try_into().unwrap(), &mentions)?;
+        for recp in recipients.iter() {
+            tx.execute(
                 "INSERT INTO message_recipients (user_id, message_id) VALUES (?1, ?2)",
                 params![ recp, rowid ]
             )?;
         }
-        return self.select_message_by_id(rowid.try_into().unwrap());
+        for file in files.iter() {
+            tx.execute(
+                "INSERT INTO files (original_name, stored_name, owner_id, is_deleted) VALUES (?1, ?2, ?3, 0)",
+                params![ file.original_name, file.stored_name, sender_id ]
+            )?;
+            let file_id = tx.last_insert_rowid();
+            tx.execute(
+                "INSERT INTO message_files (file_id, message_id) VALUES (?1, ?2)",
+                params![ file_id, rowid ]
+            )?;
+        }
+        tx.commit()?;
+
+        return Ok(self.select_message_by_id(rowid.try_into().unwrap())?);
+    }
+
+    /// Checks that the user can see the message
+    fn is_visible(&self, user_id: u32, message_id: u32) -> Result<bool, Error> {
+        return self.conn.prepare_cached(&format!("
+            SELECT 1 FROM messages m WHERE m.id = ?2 AND {}
+        ", VISIBLE_TO_USER))?.exists(params![ user_id, message_id ]);
+    }
+
+    /// Selects a message the user can see
+    pub fn select_message_for_user(&self, user_id: u32, message_id: u32) -> Result<Option<m::MessageResponse>, Error> {
+        let condition = format!("m.id = ?2 AND {}", VISIBLE_TO_USER);
+        return Ok(self.select_messages_where(&condition, params![ user_id, message_id ])?.into_iter().next());
+    }
+
+    /// Selects the whole thread the message belongs to: its first message and
+    /// all replies to it, recursively, that the user can see. Oldest first,
+    /// `None` if the user can not see the message itself.
+    pub fn select_thread(&self, user_id: u32, message_id: u32) -> Result<Option<Vec<m::MessageResponse>>, Error> {
+        if !self.is_visible(user_id, message_id)? {
+            return Ok(None);
+        }
+        let thread = "
+            WITH RECURSIVE
+                ancestors (id, reply_to) AS (
+                    SELECT id, reply_to FROM messages WHERE id = ?2
+                    UNION
+                    SELECT p.id, p.reply_to FROM messages p JOIN ancestors a ON p.id = a.reply_to),
+                thread (id) AS (
+                    SELECT id FROM ancestors WHERE reply_to IS NULL
+                    UNION
+                    SELECT r.id FROM messages r JOIN thread t ON r.reply_to = t.id)
+            SELECT id FROM thread
+        ";
+        let condition = format!("m.id IN ({}) AND {}", thread, VISIBLE_TO_USER);
+        return Ok(Some(self.select_messages_where(&condition, params![ user_id, message_id ])?));
+    }
+
+    /// Replaces text of a message sent by the user. The previous text is kept as a revision.
+    pub fn edit_message(&self, user_id: u32, message_id: u32, text: &str) -> Result<m::MessageResponse, EditMessageError> {
+        let tx = self.conn.unchecked_transaction()?;
+        self.check_editable(user_id, message_id)?;
+        self.insert_revision(message_id)?;
+        // only those who got the message can be mentioned
+        let recipients = self.conn.prepare("SELECT user_id FROM message_recipients WHERE message_id = ?1")?
+            .query_map(params![ message_id ], |row| row.get(0))?
+            .collect::<Result<Vec<u32>,_>>()?;
+        let rendered = markdown::render(text, &self.select_mentionable(Some(&recipients))?);
+        tx.execute("
+            UPDATE messages SET text = ?2, html = ?3, edited_at = ?4 WHERE id = ?1
+        ", params![ message_id, text, rendered.html, now() ])?;
+        self.replace_mentions(message_id, &rendered.mentions)?;
+        tx.commit()?;
+        return Ok(self.select_message_by_id(message_id)?);
+    }
+
+    /// Retracts a message sent by the user, leaving a tombstone. The text is
+    /// kept as a revision, attached files stop being available.
+    pub fn delete_message(&self, user_id: u32, message_id: u32) -> Result<m::MessageResponse, EditMessageError> {
+        let tx = self.conn.unchecked_transaction()?;
+        self.check_editable(user_id, message_id)?;
+        self.insert_revision(message_id)?;
+        tx.execute("
+            UPDATE messages SET text = '', html = '', deleted_at = ?2 WHERE id = ?1
+        ", params![ message_id, now() ])?;
+        self.replace_mentions(message_id, &[])?;
+        tx.execute("DELETE FROM message_reactions WHERE message_id = ?1", params![ message_id ])?;
+        tx.execute("
+            UPDATE files SET is_deleted = 1
+            WHERE id IN (SELECT file_id FROM message_files WHERE message_id = ?1)
+        ", params![ message_id ])?;
+        tx.commit()?;
+        return Ok(self.select_message_by_id(message_id)?);
+    }
+
+    /// Messages the user scheduled that were not sent yet, the next one first
+    pub fn select_scheduled_messages(&self, user_id: u32) -> Result<Vec<m::MessageResponse>, Error> {
+        let mut messages = self.select_messages_where("m.user_id = ?1 AND m.send_at IS NOT NULL", params![ user_id ])?;
+        messages.sort_by_key(|m| (m.send_at, m.id));
+        return Ok(messages);
+    }
+
+    /// Moves a scheduled message of the user to another time, a passed one sends it right away
+    pub fn reschedule_message(&self, user_id: u32, message_id: u32, send_at: i64) -> Result<m::MessageResponse, EditMessageError> {
+        let changed = self.conn.execute("
+            UPDATE messages SET send_at = ?3 WHERE id = ?1 AND user_id = ?2 AND send_at IS NOT NULL
+        ", params![ message_id, user_id, send_at ])?;
+        if changed == 0 {
+            return Err(EditMessageError::NotFound);
+        }
+        return Ok(self.select_message_by_id(message_id)?);
+    }
+
+    /// Deletes a scheduled message of the user as if it was never written.
+    /// Returns stored names of its files to remove from disk.
+    pub fn cancel_message(&self, user_id: u32, message_id: u32) -> Result<Vec<String>, EditMessageError> {
+        let tx = self.conn.unchecked_transaction()?;
+        let found = tx.prepare("SELECT 1 FROM messages WHERE id = ?1 AND user_id = ?2 AND send_at IS NOT NULL")?
+            .exists(params![ message_id, user_id ])?;
+        if !found {
+            return Err(EditMessageError::NotFound);
+        }
+        let files = tx.prepare("
+            SELECT f.id, f.stored_name FROM message_files mf JOIN files f ON f.id = mf.file_id WHERE mf.message_id = ?1
+        ")?.query_map(params![ message_id ], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(u32, String)>,_>>()?;
+        tx.execute_batch(&format!("
+            DELETE FROM message_recipients WHERE message_id = {message};
+            DELETE FROM message_mentions WHERE message_id = {message};
+            DELETE FROM message_files WHERE message_id = {message};
+        ", message = message_id))?;
+        for (file_id, _) in files.iter() {
+            tx.execute("DELETE FROM files WHERE id = ?1", params![ file_id ])?;
+        }
+        tx.execute("DELETE FROM messages WHERE id = ?1", params![ message_id ])?;
+        tx.commit()?;
+        return Ok(files.into_iter().map(|(_, stored_name)| stored_name).collect());
+    }
+
+    /// Sends scheduled messages that are due. Each gets a new id, so that it comes
+    /// after the messages sent while it waited, in history and when replaying.
+    pub fn send_due_messages(&self) -> Result<Vec<m::MessageResponse>, Error> {
+        let now = now();
+        let due = self.conn.prepare("SELECT id FROM messages WHERE send_at <= ?1 ORDER BY send_at, id")?
+            .query_map(params![ now ], |row| row.get(0))?
+            .collect::<Result<Vec<u32>,_>>()?;
+        let mut sent = Vec::new();
+        for pending_id in due {
+            let tx = self.conn.unchecked_transaction()?;
+            tx.execute("
+                INSERT INTO messages (text, html, user_id, timestamp, reply_to, channel_id, destruct_after)
+                    SELECT text, html, user_id, ?2, reply_to, channel_id, destruct_after FROM messages WHERE id = ?1
+            ", params![ pending_id, now ])?;
+            let message_id = tx.last_insert_rowid();
+            // nobody could see the message yet, so it has no reactions, replies or revisions
+            for table in ["message_recipients", "message_files", "message_mentions"].iter() {
+                tx.execute(&format!("UPDATE {} SET message_id = ?2 WHERE message_id = ?1", table),
+                    params![ pending_id, message_id ])?;
+            }
+            tx.execute("DELETE FROM messages WHERE id = ?1", params![ pending_id ])?;
+            tx.commit()?;
+            sent.push(self.select_message_by_id(message_id.try_into().unwrap())?);
+        }
+        return Ok(sent);
+    }
+
+    /// Time the next scheduled message is due at
+    pub fn next_send_at(&self) -> Result<Option<i64>, Error> {
+        return self.conn.query_row("SELECT min(send_at) FROM messages", params![], |row| row.get(0));
+    }
+
+    /// Selects up to `limit` ids of sent messages whose self-destruct timer ran
+    /// out or which are past the retention policy of their conversation. Channels
+    /// can have their own policy. Direct messages are only limited by the global
+    /// `days`: they have no conversation with fixed members to count the last ones in.
+    pub fn select_expired_messages(&self, retention: Retention, limit: u32) -> Result<Vec<u32>, Error> {
+        let now = now();
+        let select = |sql: &str, params: &[&dyn rusqlite::ToSql]| -> Result<Vec<u32>, Error> {
+            return self.conn.prepare_cached(sql)?.query_map(params, |row| row.get(0))?.collect();
+        };
+        let mut expired = select("
+            SELECT id FROM messages
+            WHERE destruct_after IS NOT NULL AND timestamp + destruct_after <= ?1 AND send_at IS NULL
+            LIMIT ?2
+        ", &[&now, &limit])?;
+        if retention.days > 0 {
+            expired.extend(select("
+                SELECT m.id FROM messages m LEFT JOIN channels c ON c.id = m.channel_id
+                WHERE m.timestamp < ?1 AND m.send_at IS NULL AND c.retention_days IS NULL
+                LIMIT ?2
+            ", &[&(now - days_to_seconds(retention.days)), &limit])?);
+        }
+        for channel in self.select_channel_retentions()? {
+            if let Some(days) = channel.days.filter(|days| *days > 0) {
+                expired.extend(select("
+                    SELECT id FROM messages WHERE channel_id = ?1 AND timestamp < ?2 AND send_at IS NULL LIMIT ?3
+                ", &[&channel.id, &(now - days_to_seconds(days)), &limit])?);
+            }
+            let keep_last = channel.keep_last.unwrap_or(retention.keep_last);
+            if keep_last > 0 {
+                // older than the last message that is kept, nothing if there are fewer
+                expired.extend(select("
+                    SELECT id FROM messages WHERE channel_id = ?1 AND send_at IS NULL AND id < (
+                        SELECT id FROM messages WHERE channel_id = ?1 AND send_at IS NULL
+                        ORDER BY id DESC LIMIT 1 OFFSET ?2)
+                    LIMIT ?3
+                ", &[&channel.id, &(keep_last - 1), &limit])?);
+            }
+        }
+        expired.sort_unstable();
+        expired.dedup();
+        expired.truncate(limit as usize);
+        return Ok(expired);
+    }
+
+    /// Deletes messages for good with their recipients, revisions, mentions,
+    /// reactions and files, in one transaction. Replies stop quoting them.
+    /// Returns the deleted messages and stored names of the deleted files,
+    /// which are left on disk.
+    pub fn purge_messages(&self, message_ids: &[u32]) -> Result<(Vec<PurgedMessage>, Vec<String>), Error> {
+        if message_ids.is_empty() {
+            return Ok((Vec::new(), Vec::new()));
+        }
+        let ids = message_ids.iter().map(u32::to_string).join(",");
+        let tx = self.conn.unchecked_transaction()?;
+        let mut purged = tx.prepare(&format!("SELECT id, channel_id, user_id FROM messages WHERE id IN ({})", ids))?
+            .query_map(params![], |row| Ok(PurgedMessage { id: row.get(0)?, channel_id: row.get(1)?, user_ids: vec![row.get(2)?] }))?
+            .collect::<Result<Vec<_>,_>>()?;
+        {
+            let mut recipients = tx.prepare("SELECT user_id FROM message_recipients WHERE message_id = ?1")?;
+            for message in purged.iter_mut() {
+                for user_id in recipients.query_map(params![ message.id ], |row| row.get(0))? {
+                    message.user_ids.push(user_id?);
+                }
+            }
+        }
+        let files = tx.prepare(&format!("
+            SELECT f.id, f.stored_name FROM message_files mf JOIN files f ON f.id = mf.file_id WHERE mf.message_id IN ({})
+        ", ids))?.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(u32, String)>,_>>()?;
+        tx.execute_batch(&format!("
+            UPDATE messages SET reply_to = NULL WHERE reply_to IN ({ids});
+            DELETE FROM message_revisions WHERE message_id IN ({ids});
+            DELETE FROM message_recipients WHERE message_id IN ({ids});
+            DELETE FROM message_mentions WHERE message_id IN ({ids});
+            DELETE FROM message_reactions WHERE message_id IN ({ids});
+            DELETE FROM message_files WHERE message_id IN ({ids});
+            DELETE FROM messages WHERE id IN ({ids});
+        ", ids = ids))?;
+        for (file_id, _) in files.iter() {
+            tx.execute("DELETE FROM files WHERE id = ?1", params![ file_id ])?;
+        }
+        tx.commit()?;
+        return Ok((purged, files.into_iter().map(|(_, stored_name)| stored_name).collect()));
+    }
+
+    /// Time the next self-destruct timer runs out at
+    pub fn next_destruct_at(&self) -> Result<Option<i64>, Error> {
+        return self.conn.query_row("
+            SELECT min(timestamp + destruct_after) FROM messages WHERE destruct_after IS NOT NULL AND send_at IS NULL
+        ", params![], |row| row.get(0));
+    }
+
+    /// Returns pages freed by deleted rows to the file system
+    pub fn incremental_vacuum(&self) -> Result<(), Error> {
+        return self.conn.execute_batch("PRAGMA incremental_vacuum;");
+    }
+
+    /// Selects retention policies of every channel, by name
+    pub fn select_channel_retentions(&self) -> Result<Vec<m::ChannelRetention>, Error> {
+        let mut stmt = self.conn.prepare_cached("
+            SELECT id, name, retention_days, retention_keep_last FROM channels ORDER BY lower(name)
+        ")?;
+        return stmt.query_map(params![], |row| {
+            Ok(m::ChannelRetention { id: row.get(0)?, name: row.get(1)?, days: row.get(2)?, keep_last: row.get(3)? })
+        })?.collect();
+    }
+
+    /// Sets the retention policy of a channel, `None` makes it follow the global one
+    pub fn set_channel_retention(&self, channel_id: u32, days: Option<u32>, keep_last: Option<u32>) -> Result<(), Error> {
+        self.conn.execute("
+            UPDATE channels SET retention_days = ?2, retention_keep_last = ?3 WHERE id = ?1
+        ", params![ channel_id, days, keep_last ])?;
+        return Ok(());
+    }
+
+    /// Adds a reaction of the user to a message they can see. Adding it again changes nothing.
+    pub fn add_reaction(&self, user_id: u32, message_id: u32, emoji: &str) -> Result<m::MessageResponse, EditMessageError> {
+        self.check_reactable(user_id, message_id)?;
+        self.conn.execute("
+            INSERT OR IGNORE INTO message_reactions (message_id, user_id, emoji, created_at) VALUES (?1, ?2, ?3, ?4)
+        ", params![ message_id, user_id, emoji, now() ])?;
+        return Ok(self.select_message_by_id(message_id)?);
+    }
+
+    pub fn remove_reaction(&self, user_id: u32, message_id: u32, emoji: &str) -> Result<m::MessageResponse, EditMessageError> {
+        self.check_reactable(user_id, message_id)?;
+        self.conn.execute("
+            DELETE FROM message_reactions WHERE message_id = ?1 AND user_id = ?2 AND emoji = ?3
+        ", params![ message_id, user_id, emoji ])?;
+        return Ok(self.select_message_by_id(message_id)?);
+    }
+
+    /// Anyone who can see a message can react to it until it is deleted
+    fn check_reactable(&self, user_id: u32, message_id: u32) -> Result<(), EditMessageError> {
+        if !self.is_visible(user_id, message_id)? {
+            return Err(EditMessageError::NotFound);
+        }
+        let deleted: bool = self.conn.query_row("
+            SELECT deleted_at IS NOT NULL FROM messages WHERE id = ?1
+        ", params![ message_id ], |row| row.get(0))?;
+        if deleted {
+            return Err(EditMessageError::Deleted);
+        }
+        return Ok(());
+    }
+
+    pub fn select_reactions(&self, message_id: u32) -> Result<Vec<m::Reaction>, Error> {
+        let mut stmt = self.conn.prepare_cached("
+            SELECT emoji, user_id FROM message_reactions WHERE message_id = ?1 ORDER BY created_at, ROWID
+        ")?;
+        let rows = stmt.query_map(params![ message_id ], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let mut reactions: Vec<m::Reaction> = Vec::new();
+        for row in rows {
+            let (emoji, user_id): (String, u32) = row?;
+            match reactions.iter_mut().find(|r| r.emoji == emoji) {
+                Some(reaction) => { reaction.user_ids.push(user_id); }
+                None => { reactions.push(m::Reaction { emoji, user_ids: vec![user_id] }); }
+            }
+        }
+        return Ok(reactions);
+    }
+
+    fn check_editable(&self, user_id: u32, message_id: u32) -> Result<(), EditMessageError> {
+        let found = self.conn.query_row("
+            SELECT m.user_id, m.deleted_at IS NOT NULL FROM messages m WHERE m.id = ?1 AND m.send_at IS NULL
+        ", params![ message_id ], |row| Ok((row.get(0)?, row.get(1)?)));
+        let (sender_id, deleted): (u32, bool) = match found {
+            Ok(found) => { found }
+            Err(Error::QueryReturnedNoRows) => { return Err(EditMessageError::NotFound); }
+            Err(n) => { return Err(n.into()); }
+        };
+        if sender_id != user_id {
+            // other messages are not known to the user at all
+            return Err(if self.is_visible(user_id, message_id)? { EditMessageError::NotSender } else { EditMessageError::NotFound });
+        }
+        if deleted {
+            return Err(EditMessageError::Deleted);
+        }
+        return Ok(());
+    }
+
+    fn insert_revision(&self, message_id: u32) -> Result<(), Error> {
+        self.conn.execute("
+            INSERT INTO message_revisions (message_id, text, edited_at, replaced_at)
+            SELECT id, text, edited_at, ?2 FROM messages WHERE id = ?1
+        ", params![ message_id, now() ])?;
+        return Ok(());
+    }
+
+    /// Finds messages the user can see containing all words of the query,
+    /// best matches first. Returns nothing for a query without words.
+    pub fn search_messages(&self, user_id: u32, query: &str, offset: u32, limit: u32) -> Result<m::SearchPage, Error> {
+        let query = util::fts_query(query);
+        if query.is_empty() {
+            return Ok(m::SearchPage { results: Vec::new(), has_more: false });
+        }
+        let mut stmt = self.conn.prepare(&format!("
+            SELECT f.rowid, snippet(messages_fts, 0, char(1), char(2), '…', ?5)
+            FROM messages_fts f JOIN messages m ON m.id = f.rowid
+            WHERE messages_fts MATCH ?2 AND {}
+            ORDER BY f.rank, m.id DESC
+            LIMIT ?3 OFFSET ?4
+        ", VISIBLE_TO_USER))?;
+        let mut found = stmt.query_map(params![ user_id, query, limit + 1, offset, SNIPPET_WORDS ], |row| {
+            Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?))
+        })?.collect::<Result<Vec<_>,_>>()?;
+        let has_more = found.len() > limit as usize;
+        found.truncate(limit as usize);
+
+        // ids are numbers, so they are safe to put into the query
+        let ids = found.iter().map(|(id, _)| id.to_string()).join(",");
+        let mut messages = self.select_messages_where(&format!("m.id IN ({})", ids), params![])?;
+        let results = found.into_iter()
+            .filter_map(|(id, snippet)| {
+                let index = messages.iter().position(|m| m.id == id)?;
+                Some(m::SearchResult { message: messages.swap_remove(index), snippet: util::snippet_parts(&snippet) })
+            })
+            .collect();
+        return Ok(m::SearchPage { results, has_more });
+    }
+
+    /// Creates a channel with the user as its only member
+    pub fn create_channel(&self, user_id: u32, name: &str) -> Result<m::ChannelResponse, ChannelError> {
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > MAX_CHANNEL_NAME_LENGTH {
+            return Err(ChannelError::InvalidName);
+        }
+        let tx = self.conn.unchecked_transaction()?;
+        let taken = tx.prepare("SELECT 1 FROM channels WHERE lower(name) = lower(?1)")?
+            .exists(params![ name ])?;
+        if taken {
+            return Err(ChannelError::NameTaken);
+        }
+        let now = now();
+        tx.execute("
+            INSERT INTO channels (name, created_by, created_at) VALUES (?1, ?2, ?3)
+        ", params![ name, user_id, now ])?;
+        let channel_id = tx.last_insert_rowid().try_into().unwrap();
+        tx.execute("
+            INSERT INTO channel_members (channel_id, user_id, joined_at) VALUES (?1, ?2, ?3)
+        ", params![ channel_id, user_id, now ])?;
+        tx.commit()?;
+        return self.select_channel(user_id, channel_id)?.ok_or(ChannelError::NotFound);
+    }
+
+    /// Selects every channel, by name
+    pub fn select_channels(&self, user_id: u32) -> Result<Vec<m::ChannelResponse>, Error> {
+        return self.select_channels_where("1", params![ user_id ]);
+    }
+
+    pub fn select_channel(&self, user_id: u32, channel_id: u32) -> Result<Option<m::ChannelResponse>, Error> {
+        return Ok(self.select_channels_where("c.id = ?2", params![ user_id, channel_id ])?.into_iter().next());
+    }
+
+    /// Selects channels matching an SQL condition on `c`, `?1` is the user
+    fn select_channels_where<P>(&self, condition: &str, params: P) -> Result<Vec<m::ChannelResponse>, Error>
+        where P: IntoIterator, P::Item: rusqlite::ToSql
+    {
+        let mut stmt = self.conn.prepare(&format!("
+            SELECT
+                c.id, c.name,
+                (SELECT count(*) FROM channel_members WHERE channel_id = c.id),
+                EXISTS (SELECT 1 FROM channel_members WHERE channel_id = c.id AND user_id = ?1)
+            FROM channels c
+            WHERE {}
+            ORDER BY lower(c.name)
+        ", condition))?;
+
+        return stmt.query_map(params, |row| {
+            Ok(m::ChannelResponse {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                member_count: row.get(2)?,
+                joined: row.get(3)?
+            })
+        })?.collect::<Result<Vec<_>,_>>();
+    }
+
+    /// Adds the user to a channel, joining twice changes nothing
+    pub fn join_channel(&self, user_id: u32, channel_id: u32) -> Result<(), ChannelError> {
+        if !self.channel_exists(channel_id)? {
+            return Err(ChannelError::NotFound);
+        }
+        self.conn.execute("
+            INSERT OR IGNORE INTO channel_members (channel_id, user_id, joined_at) VALUES (?1, ?2, ?3)
+        ", params![ channel_id, user_id, now() ])?;
+        return Ok(());
+    }
+
+    /// Removes the user from a channel. Its messages stop being visible to
+    /// them, except the ones they sent or received while being a member.
+    pub fn leave_channel(&self, user_id: u32, channel_id: u32) -> Result<(), ChannelError> {
+        if !self.channel_exists(channel_id)? {
+            return Err(ChannelError::NotFound);
+        }
+        self.conn.execute("
+            DELETE FROM channel_members WHERE channel_id = ?1 AND user_id = ?2
+        ", params![ channel_id, user_id ])?;
+        return Ok(());
+    }
+
+    fn channel_exists(&self, channel_id: u32) -> Result<bool, Error> {
+        return self.conn.prepare_cached("SELECT 1 FROM channels WHERE id = ?1")?
+            .exists(params![ channel_id ]);
+    }
+
+    pub fn select_channel_member_ids(&self, channel_id: u32) -> Result<Vec<u32>, Error> {
+        let mut stmt = self.conn.prepare_cached("
+            SELECT user_id FROM channel_members WHERE channel_id = ?1 ORDER BY user_id
+        ")?;
+        return stmt.query_map(params![ channel_id ], |row| row.get(0))?.collect();
+    }
+
+    /// Records that messages reached the user. Returns receipts of messages
+    /// that were not delivered before; messages the user did not receive are skipped.
+    pub fn mark_delivered(&self, user_id: u32, message_ids: &[u32]) -> Result<Vec<m::Receipt>, Error> {
+        return self.update_receipts(user_id, message_ids, "
+            UPDATE message_recipients SET delivered_at = ?3
+            WHERE user_id = ?1 AND message_id = ?2 AND delivered_at IS NULL
+                AND message_id IN (SELECT id FROM messages WHERE send_at IS NULL)
+        ");
+    }
+
+    /// Records that the user read messages, which also delivers them. Returns
+    /// receipts of messages that were not read before.
+    pub fn mark_read(&self, user_id: u32, message_ids: &[u32]) -> Result<Vec<m::Receipt>, Error> {
+        return self.update_receipts(user_id, message_ids, "
+            UPDATE message_recipients SET read_at = ?3, delivered_at = coalesce(delivered_at, ?3)
+            WHERE user_id = ?1 AND message_id = ?2 AND read_at IS NULL
+                AND message_id IN (SELECT id FROM messages WHERE send_at IS NULL)
+        ");
+    }
 
+    /// Runs `update` with user id, message id and current time for each message
+    fn update_receipts(&self, user_id: u32, message_ids: &[u32], update: &str) -> Result<Vec<m::Receipt>, Error> {
+        let now = now();
+        let tx = self.conn.unchecked_transaction()?;
+        let mut receipts = Vec::new();
+        {
+            let mut update = tx.prepare(update)?;
+            let mut select = tx.prepare("
+                SELECT m.user_id, mr.delivered_at, mr.read_at
+                FROM message_recipients mr JOIN messages m ON m.id = mr.message_id
+                WHERE mr.user_id = ?1 AND mr.message_id = ?2
+            ")?;
+            for message_id in message_ids {
+                if update.execute(params![ user_id, message_id, now ])? == 0 {
+                    continue;
+                }
+                receipts.push(select.query_row(params![ user_id, message_id ], |row| {
+                    Ok(m::Receipt {
+                        message_id: *message_id,
+                        sender_id: row.get(0)?,
+                        user_id,
+                        delivered_at: row.get(1)?,
+                        read_at: row.get(2)?
+                    })
+                })?);
+            }
+        }
+        tx.commit()?;
+        return Ok(receipts);
     }
 }
 
+fn days_to_seconds(days: u32) -> i64 {
+    return i64::from(days) * 24 * 60 * 60;
+}
+
+/// Current time as stored in the database
+fn now() -> i64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Can not count time anymore")
+        .as_secs()
+        .try_into()
+        .expect("Can not count this much time");
+}
+
 fn message_rows_to_message(row_array: impl Iterator<Item=MessageRow>) -> Vec<m::MessageResponse> {
-    return row_array.group_by(|m| {(
-            m.id, 
-            m.timestamp, 
-            m.sender_id, 
-            m.sender_name.clone(), 
-            m.sender_color.clone(), 
-            m.text.clone()) })
+    // one row per recipient
+    return row_array.group_by(|m| m.id)
         .into_iter()
-        .map(|(key, g)| { m::MessageResponse {
-            id: key.0,
-            timestamp: key.1,
-            sender_id: key.2,
-            sender_name: key.3,
-            sender_color: key.4,
-            text: key.5,
-            recipients: g.map(|m| m::EmbeddedRecipient { 
-                id: m.recipient_id, name: m.recipient_name, color: m.recipient_color
-            }).collect()
-        }})
+        .map(|(_, group)| {
+            let rows: Vec<MessageRow> = group.collect();
+            let first = &rows[0];
+            let mut message = m::MessageResponse {
+                id: first.id,
+                timestamp: first.timestamp,
+                sender_id: first.sender_id,
+                sender_name: first.sender_name.clone(),
+                sender_color: first.sender_color.clone(),
+                text: first.text.clone(),
+                html: first.html.clone(),
+                edited_at: first.edited_at,
+                deleted: first.deleted,
+                reply_to: first.reply_to,
+                quote: first.quote.clone(),
+                channel_id: first.channel_id,
+                channel_name: first.channel_name.clone(),
+                mentions: Vec::new(),
+                reactions: Vec::new(),
+                send_at: first.send_at,
+                destruct_after: first.destruct_after,
+                // the timer starts when the message is sent
+                expires_at: match first.send_at {
+                    Some(_) => { None }
+                    None => { first.destruct_after.map(|seconds| first.timestamp + i64::from(seconds)) }
+                },
+                recipients: Vec::new(),
+                attachments: Vec::new()
+            };
+            message.recipients = rows.into_iter().filter_map(|m| m.recipient).collect();
+            return message;
+        })
         .collect();
 }
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    fn repo_with_users() -> Repo {
+        let repo = Repo::new(Path::new(":memory:")).unwrap();
+        repo.conn.execute_batch("
+            INSERT INTO users (username, password, color, name) VALUES
+                ('alice', '', 'red', 'Alice A'),
+                ('bob', '', 'blue', 'Bob B'),
+                ('carol', '', 'green', 'Carol C');
+        ").unwrap();
+        return repo;
+    }
+
+    fn count(repo: &Repo, table: &str) -> u32 {
+        return repo.conn
+            .query_row(&format!("SELECT count(*) FROM {}", table), rusqlite::NO_PARAMS, |row| row.get(0))
+            .unwrap();
+    }
+
+    fn message(recipients: Vec<u32>) -> m::PostMessageRequest {
+        return m::PostMessageRequest {
+            recipients, text: "hello".to_owned(), reply_to: None, channel_id: None, send_at: None, destruct_after: None
+        };
+    }
+
+    #[test]
+    fn inserts_message_with_files() {
+        let repo = repo_with_users();
+        let files = [m::StoredFile { original_name: "a.txt".to_owned(), stored_name: "x".to_owned() }];
+        let response = repo.insert_message(1, message(vec![2]), &files).unwrap();
+        assert_eq!(response.text, "hello");
+        assert_eq!(response.recipients.len(), 1);
+        assert_eq!(response.attachments.len(), 1);
+        assert_eq!(count(&repo, "message_recipients"), 1);
+    }
+
+    fn stored(original_name: &str) -> m::StoredFile {
+        return m::StoredFile { original_name: original_name.to_owned(), stored_name: format!("s-{}", original_name) };
+    }
+
+    #[test]
+    fn selects_attachments_of_message() {
+        let repo = repo_with_users();
+        let first = repo.insert_message(1, message(vec![2]), &[stored("a.txt"), stored("b.txt")]).unwrap();
+        let second = repo.insert_message(1, message(vec![2]), &[stored("c.txt")]).unwrap();
+        repo.conn.execute("UPDATE files SET is_deleted = 1 WHERE original_name = 'b.txt'", rusqlite::NO_PARAMS).unwrap();
+
+        let names = |id| repo.select_attachments(id).unwrap().into_iter().map(|a| a.name).collect::<Vec<_>>();
+        assert_eq!(names(first.id), vec!["a.txt"]);
+        assert_eq!(names(second.id), vec!["c.txt"]);
+    }
+
+    #[test]
+    fn files_are_available_to_owner_and_recipients_only() {
+        let repo = repo_with_users();
+        let message = repo.insert_message(1, message(vec![2]), &[stored("a.txt")]).unwrap();
+        let file_id = message.attachments[0].id;
+
+        let owner = repo.select_file_for_user(file_id, 1).unwrap().unwrap();
+        assert_eq!(owner.original_name, "a.txt");
+        assert_eq!(owner.stored_name, "s-a.txt");
+        assert!(repo.select_file_for_user(file_id, 2).unwrap().is_some());
+        assert!(repo.select_file_for_user(file_id, 3).unwrap().is_none());
+        assert!(repo.select_file_for_user(file_id + 1, 1).unwrap().is_none());
+
+        repo.conn.execute("UPDATE files SET is_deleted = 1", rusqlite::NO_PARAMS).unwrap();
+        assert!(repo.select_file_for_user(file_id, 1).unwrap().is_none());
+    }
+
+    fn ids(page: &m::MessagePage) -> Vec<u32> {
+        return page.messages.iter().map(|m| m.id).collect();
+    }
+
+    #[test]
+    fn paginates_history() {
+        let repo = repo_with_users();
+        // 1..=9, every third message does not involve bob
+        for i in 1..=9 {
+            let (sender, recipients) = match i % 3 { 0 => (1, vec![3]), 1 => (1, vec![2, 3]), _ => (2, vec![1]) };
+            repo.insert_message(sender, message(recipients), &[]).unwrap();
+        }
+
+        let latest = repo.select_messages_for_user(2, Conversation::All, None, None, 3).unwrap();
+        assert_eq!(ids(&latest), vec![5, 7, 8]);
+        assert!(latest.has_more);
+        assert_eq!(latest.messages[1].recipients.iter().map(|r| r.id).collect::<Vec<_>>(), vec![2, 3]);
+
+        let older = repo.select_messages_for_user(2, Conversation::All, Some(5), None, 3).unwrap();
+        assert_eq!(ids(&older), vec![1, 2, 4]);
+        assert!(!older.has_more);
+
+        let newer = repo.select_messages_for_user(2, Conversation::All, None, Some(2), 2).unwrap();
+        assert_eq!(ids(&newer), vec![4, 5]);
+        assert!(newer.has_more);
+        let newer = repo.select_messages_for_user(2, Conversation::All, None, Some(5), 3).unwrap();
+        assert_eq!(ids(&newer), vec![7, 8]);
+        assert!(!newer.has_more);
+
+        let between = repo.select_messages_for_user(2, Conversation::All, Some(8), Some(1), 10).unwrap();
+        assert_eq!(ids(&between), vec![2, 4, 5, 7]);
+        assert!(!between.has_more);
+
+        let all = repo.select_messages_for_user(3, Conversation::All, None, None, 100).unwrap();
+        assert_eq!(ids(&all), vec![1, 3, 4, 6, 7, 9]);
+    }
+
+    #[test]
+    fn revokes_sessions_by_bumping_generation() {
+        let repo = repo_with_users();
+        assert_eq!(repo.select_token_generation(1).unwrap(), Some(0));
+        assert_eq!(repo.select_token_generation(9).unwrap(), None);
+
+        repo.revoke_sessions(1).unwrap();
+        assert_eq!(repo.select_token_generation(1).unwrap(), Some(1));
+        assert_eq!(repo.select_token_generation(2).unwrap(), Some(0));
+
+        assert_eq!(repo.revoke_all_sessions().unwrap(), 3);
+        assert_eq!(repo.select_token_generation(1).unwrap(), Some(2));
+        assert_eq!(repo.select_token_generation(2).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn updates_profile_keeping_missing_fields() {
+        let repo = repo_with_users();
+        repo.update_profile(1, Some("Alice Liddell"), None).unwrap();
+        repo.update_profile(1, None, Some("#1e90ff")).unwrap();
+        let profile = repo.select_profile(1).unwrap().unwrap();
+        assert_eq!((profile.name.as_str(), profile.color.as_str()), ("Alice Liddell", "#1e90ff"));
+        assert!(repo.select_profile(42).unwrap().is_none());
+    }
+
+    #[test]
+    fn password_change_revokes_sessions_but_rehash_does_not() {
+        let repo = repo_with_users();
+        repo.set_user_password(1, "rehashed", false).unwrap();
+        assert_eq!(repo.select_token_generation(1).unwrap(), Some(0));
+
+        repo.set_user_password(1, "changed", true).unwrap();
+        assert_eq!(repo.select_token_generation(1).unwrap(), Some(1));
+        assert_eq!(repo.select_user_credentials("alice").unwrap().unwrap().password, "changed");
+    }
+
+    #[test]
+    fn rejects_invalid_recipients_without_writing() {
+        let repo = repo_with_users();
+        let files = [m::StoredFile { original_name: "a.txt".to_owned(), stored_name: "x".to_owned() }];
+
+        assert!(matches!(repo.insert_message(1, message(vec![2, 4]), &files),
+            Err(InsertMessageError::UnknownRecipient(4))));
+        assert!(matches!(repo.insert_message(1, message(vec![2, 1, 2]), &files),
+            Err(InsertMessageError::DuplicateRecipient(2))));
+        assert!(matches!(repo.insert_message(1, message(vec![]), &files),
+            Err(InsertMessageError::NoRecipients)));
+
+        for table in ["messages", "message_recipients", "files", "message_files"].iter() {
+            assert_eq!(count(&repo, table), 0, "{}", table);
+        }
+    }
+
+    #[test]
+    fn records_delivery_and_reading_once() {
+        let repo = repo_with_users();
+        let sent = repo.insert_message(1, message(vec![2, 3]), &[]).unwrap();
+        assert!(sent.recipients.iter().all(|r| r.delivered_at.is_none() && r.read_at.is_none()));
+
+        let delivered = repo.mark_delivered(2, &[sent.id]).unwrap();
+        assert_eq!(delivered.len(), 1);
+        assert_eq!((delivered[0].sender_id, delivered[0].user_id, delivered[0].read_at), (1, 2, None));
+        assert!(repo.mark_delivered(2, &[sent.id]).unwrap().is_empty());
+        // the sender and unknown messages are skipped
+        assert!(repo.mark_delivered(1, &[sent.id, sent.id + 1]).unwrap().is_empty());
+
+        let read = repo.mark_read(3, &[sent.id]).unwrap();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].read_at, Some(read[0].delivered_at));
+        assert!(repo.mark_read(3, &[sent.id]).unwrap().is_empty());
+
+        let message = repo.select_message_by_id(sent.id).unwrap();
+        assert_eq!(message.recipients[0].delivered_at, Some(delivered[0].delivered_at));
+        assert_eq!(message.recipients[0].read_at, None);
+        assert_eq!(message.recipients[1].read_at, read[0].read_at);
+    }
+
+    #[test]
+    fn edits_and_deletes_own_messages_keeping_revisions() {
+        let repo = repo_with_users();
+        let sent = repo.insert_message(1, message(vec![2]), &[stored("a.txt")]).unwrap();
+        assert_eq!((sent.edited_at, sent.deleted), (None, false));
+
+        let edited = repo.edit_message(1, sent.id, "hello again").unwrap();
+        assert_eq!(edited.text, "hello again");
+        assert!(edited.edited_at.is_some());
+        assert_eq!(edited.attachments.len(), 1);
+
+        assert!(matches!(repo.edit_message(2, sent.id, "x"), Err(EditMessageError::NotSender)));
+        assert!(matches!(repo.delete_message(3, sent.id), Err(EditMessageError::NotFound)));
+        assert!(matches!(repo.edit_message(1, sent.id + 1, "x"), Err(EditMessageError::NotFound)));
+
+        let deleted = repo.delete_message(1, sent.id).unwrap();
+        assert!(deleted.deleted);
+        assert_eq!(deleted.text, "");
+        assert!(deleted.attachments.is_empty());
+        assert!(repo.select_file_for_user(sent.attachments[0].id, 2).unwrap().is_none());
+        assert!(matches!(repo.edit_message(1, sent.id, "x"), Err(EditMessageError::Deleted)));
+        assert!(matches!(repo.delete_message(1, sent.id), Err(EditMessageError::Deleted)));
+
+        let mut stmt = repo.conn.prepare(
+            "SELECT text, edited_at IS NOT NULL FROM message_revisions WHERE message_id = ?1 ORDER BY id").unwrap();
+        let revisions = stmt.query_map(params![ sent.id ], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+            .collect::<Result<Vec<(String, bool)>, _>>().unwrap();
+        assert_eq!(revisions, vec![("hello".to_owned(), false), ("hello again".to_owned(), true)]);
+    }
+
+    #[test]
+    fn aggregates_reactions_of_those_who_see_the_message() {
+        let repo = repo_with_users();
+        let sent = repo.insert_message(1, message(vec![2]), &[]).unwrap();
+        repo.add_reaction(2, sent.id, "👍").unwrap();
+        repo.add_reaction(1, sent.id, "🎉").unwrap();
+        repo.add_reaction(1, sent.id, "👍").unwrap();
+        let reacted = repo.add_reaction(1, sent.id, "👍").unwrap();
+        assert_eq!(reacted.reactions, vec![
+            m::Reaction { emoji: "👍".to_owned(), user_ids: vec![2, 1] },
+            m::Reaction { emoji: "🎉".to_owned(), user_ids: vec![1] }
+        ]);
+        assert!(matches!(repo.add_reaction(3, sent.id, "👍"), Err(EditMessageError::NotFound)));
+
+        let removed = repo.remove_reaction(1, sent.id, "🎉").unwrap();
+        assert_eq!(removed.reactions.len(), 1);
+        repo.delete_message(1, sent.id).unwrap();
+        assert_eq!(count(&repo, "message_reactions"), 0);
+        assert!(matches!(repo.add_reaction(2, sent.id, "👍"), Err(EditMessageError::Deleted)));
+    }
+
+    #[test]
+    fn hides_scheduled_messages_until_sent_with_a_new_id() {
+        let repo = repo_with_users();
+        let later = |files: &[m::StoredFile]| repo.insert_message(1,
+            m::PostMessageRequest { send_at: Some(now() + 60), ..message(vec![2]) }, files).unwrap();
+        let scheduled = later(&[stored("a.txt")]);
+        assert_eq!(scheduled.send_at, Some(now() + 60));
+        let sent = repo.insert_message(1, message(vec![2]), &[]).unwrap();
+        let ids = |user| repo.select_messages_for_user(user, Conversation::All, None, None, 10).unwrap()
+            .messages.into_iter().map(|m| m.id).collect::<Vec<_>>();
+        assert_eq!((ids(1), ids(2)), (vec![sent.id], vec![sent.id]));
+        assert!(repo.select_message_for_user(2, scheduled.id).unwrap().is_none());
+        assert!(matches!(repo.edit_message(1, scheduled.id, "x"), Err(EditMessageError::NotFound)));
+        assert!(repo.mark_read(2, &[scheduled.id]).unwrap().is_empty());
+        assert_eq!(repo.select_scheduled_messages(1).unwrap().len(), 1);
+        assert!(repo.select_scheduled_messages(2).unwrap().is_empty());
+
+        assert!(repo.send_due_messages().unwrap().is_empty());
+        assert!(matches!(repo.reschedule_message(2, scheduled.id, 0), Err(EditMessageError::NotFound)));
+        repo.reschedule_message(1, scheduled.id, now() - 1).unwrap();
+        let due = repo.send_due_messages().unwrap();
+        assert_eq!(due.len(), 1);
+        assert!(due[0].id > sent.id);
+        assert_eq!((due[0].send_at, due[0].attachments.len(), due[0].recipients[0].id), (None, 1, 2));
+        assert_eq!(ids(2), vec![sent.id, due[0].id]);
+        assert!(repo.select_file_for_user(due[0].attachments[0].id, 2).unwrap().is_some());
+        assert_eq!(repo.next_send_at().unwrap(), None);
+
+        let cancelled = later(&[stored("b.txt")]);
+        assert!(matches!(repo.cancel_message(1, due[0].id), Err(EditMessageError::NotFound)));
+        assert_eq!(repo.cancel_message(1, cancelled.id).unwrap(), vec!["s-b.txt".to_owned()]);
+        assert_eq!((count(&repo, "files"), count(&repo, "message_recipients")), (1, 2));
+    }
+
+    #[test]
+    fn selects_messages_past_timers_and_retention() {
+        let repo = repo_with_users();
+        let general = repo.create_channel(1, "general").unwrap().id;
+        repo.join_channel(2, general).unwrap();
+        let backdate = |id: u32, days: i64| {
+            repo.conn.execute("UPDATE messages SET timestamp = ?2 WHERE id = ?1", params![ id, now() - days * 86400 ])
+                .unwrap();
+        };
+        let old_direct = repo.insert_message(1, message(vec![2]), &[]).unwrap().id;
+        backdate(old_direct, 10);
+        let old_channel = repo.insert_message(1, to_channel(general, "old"), &[]).unwrap().id;
+        backdate(old_channel, 10);
+        let recent = repo.insert_message(2, to_channel(general, "recent"), &[]).unwrap().id;
+        let latest = repo.insert_message(1, to_channel(general, "latest"), &[]).unwrap().id;
+        let destructing = |send_at| repo.insert_message(1,
+            m::PostMessageRequest { destruct_after: Some(60), send_at, ..message(vec![2]) }, &[]).unwrap();
+        let timed = destructing(None);
+        assert_eq!(timed.expires_at, Some(timed.timestamp + 60));
+        let scheduled = destructing(Some(now() + 3600));
+        assert_eq!((scheduled.destruct_after, scheduled.expires_at), (Some(60), None));
+        assert!(matches!(repo.insert_message(1, m::PostMessageRequest { destruct_after: Some(0), ..message(vec![2]) }, &[]),
+            Err(InsertMessageError::InvalidSelfDestruct)));
+
+        let expired = |days, keep_last| repo.select_expired_messages(Retention { days, keep_last }, 10).unwrap();
+        assert!(expired(0, 0).is_empty());
+        assert_eq!(repo.next_destruct_at().unwrap(), Some(timed.timestamp + 60));
+        backdate(timed.id, 1);
+        backdate(scheduled.id, 1);
+        assert_eq!(expired(0, 0), vec![timed.id]);
+        assert_eq!(expired(5, 0), vec![old_direct, old_channel, timed.id]);
+        assert_eq!(expired(0, 1), vec![old_channel, recent, timed.id]);
+        assert_eq!(repo.select_expired_messages(Retention { days: 5, keep_last: 1 }, 2).unwrap(), vec![old_direct, old_channel]);
+
+        // the channel keeps its messages forever, but only the last two
+        repo.set_channel_retention(general, Some(0), Some(2)).unwrap();
+        assert_eq!(expired(5, 0), vec![old_direct, old_channel, timed.id]);
+        repo.set_channel_retention(general, Some(0), None).unwrap();
+        assert_eq!(expired(5, 0), vec![old_direct, timed.id]);
+        assert_eq!(expired(5, 5), vec![old_direct, timed.id]);
+        repo.set_channel_retention(general, None, None).unwrap();
+        let retentions = repo.select_channel_retentions().unwrap();
+        assert_eq!((retentions[0].days, retentions[0].keep_last), (None, None));
+        assert!(!expired(0, 2).contains(&latest));
+    }
+
+    #[test]
+    fn purges_messages_with_everything_attached() {
+        let repo = repo_with_users();
+        let purged = repo.insert_message(1, m::PostMessageRequest { text: "@carol".to_owned(), ..message(vec![2]) },
+            &[stored("a.txt")]).unwrap().id;
+        repo.edit_message(1, purged, "@carol!").unwrap();
+        repo.add_reaction(2, purged, "👍").unwrap();
+        let answer = repo.insert_message(2, reply(purged, vec![1], "ok"), &[]).unwrap().id;
+        let general = repo.create_channel(2, "general").unwrap().id;
+        let in_channel = repo.insert_message(2, to_channel(general, "hi"), &[]).unwrap().id;
+
+        let (messages, stored_names) = repo.purge_messages(&[purged, in_channel]).unwrap();
+        assert_eq!(messages, vec![
+            PurgedMessage { id: purged, channel_id: None, user_ids: vec![1, 2, 3] },
+            PurgedMessage { id: in_channel, channel_id: Some(general), user_ids: vec![2] }
+        ]);
+        assert_eq!(stored_names, vec!["s-a.txt".to_owned()]);
+        assert_eq!(count(&repo, "messages"), 1);
+        let answer = repo.select_message_by_id(answer).unwrap();
+        assert_eq!((answer.reply_to, answer.quote.is_none()), (None, true));
+        assert_eq!((count(&repo, "files"), count(&repo, "message_files"), count(&repo, "message_revisions")), (0, 0, 0));
+        assert_eq!((count(&repo, "message_mentions"), count(&repo, "message_reactions")), (0, 0));
+        assert_eq!(count(&repo, "message_recipients"), 1);
+        assert_eq!(repo.purge_messages(&[]).unwrap(), (vec![], vec![]));
+        repo.incremental_vacuum().unwrap();
+    }
+
+    fn reply(parent: u32, recipients: Vec<u32>, text: &str) -> m::PostMessageRequest {
+        return m::PostMessageRequest {
+            recipients, text: text.to_owned(), reply_to: Some(parent), channel_id: None, send_at: None, destruct_after: None
+        };
+    }
+
+    #[test]
+    fn replies_quote_visible_messages_only() {
+        let repo = repo_with_users();
+        let to_bob = repo.insert_message(1, message(vec![2]), &[]).unwrap();
+        let long = "x".repeat(100);
+        let answer = repo.insert_message(2, reply(to_bob.id, vec![1], &long), &[]).unwrap();
+        assert_eq!(answer.reply_to, Some(to_bob.id));
+        let quote = answer.quote.unwrap();
+        assert_eq!((quote.sender_name.as_str(), quote.text.as_str(), quote.deleted), ("Alice A", "hello", false));
+
+        let quoted = repo.insert_message(1, reply(answer.id, vec![2], "ok"), &[]).unwrap();
+        assert_eq!(quoted.quote.unwrap().text, format!("{}…", "x".repeat(QUOTE_LENGTH)));
+
+        assert!(matches!(repo.insert_message(3, reply(to_bob.id, vec![1], "me too"), &[]),
+            Err(InsertMessageError::UnknownParent(_))));
+        assert!(matches!(repo.insert_message(1, reply(999, vec![2], "?"), &[]),
+            Err(InsertMessageError::UnknownParent(999))));
+
+        repo.delete_message(1, to_bob.id).unwrap();
+        let quote = repo.select_message_by_id(answer.id).unwrap().quote.unwrap();
+        assert_eq!((quote.text.as_str(), quote.deleted), ("", true));
+    }
+
+    #[test]
+    fn selects_visible_part_of_thread() {
+        let repo = repo_with_users();
+        let root = repo.insert_message(1, message(vec![2, 3]), &[]).unwrap().id;
+        let private = repo.insert_message(2, reply(root, vec![1], "just for alice"), &[]).unwrap().id;
+        let public = repo.insert_message(3, reply(root, vec![1, 2], "for all"), &[]).unwrap().id;
+        let nested = repo.insert_message(1, reply(private, vec![2], "nested"), &[]).unwrap().id;
+        let other = repo.insert_message(1, message(vec![2]), &[]).unwrap().id;
+
+        let ids = |user, message| repo.select_thread(user, message).unwrap()
+            .map(|thread| thread.into_iter().map(|m| m.id).collect::<Vec<_>>());
+        assert_eq!(ids(1, nested), Some(vec![root, private, public, nested]));
+        assert_eq!(ids(2, root), Some(vec![root, private, public, nested]));
+        assert_eq!(ids(3, public), Some(vec![root, public]));
+        assert_eq!(ids(3, private), None);
+        assert_eq!(ids(1, other), Some(vec![other]));
+    }
+
+    fn to_channel(channel_id: u32, text: &str) -> m::PostMessageRequest {
+        return m::PostMessageRequest {
+            recipients: vec![], text: text.to_owned(), reply_to: None, channel_id: Some(channel_id), send_at: None,
+            destruct_after: None
+        };
+    }
+
+    #[test]
+    fn manages_channels_and_membership() {
+        let repo = repo_with_users();
+        let general = repo.create_channel(1, " general ").unwrap();
+        assert_eq!((general.name.as_str(), general.member_count, general.joined), ("general", 1, true));
+        assert!(matches!(repo.create_channel(2, "General"), Err(ChannelError::NameTaken)));
+        assert!(matches!(repo.create_channel(2, "  "), Err(ChannelError::InvalidName)));
+        assert!(matches!(repo.join_channel(2, 999), Err(ChannelError::NotFound)));
+
+        repo.join_channel(2, general.id).unwrap();
+        repo.join_channel(2, general.id).unwrap();
+        assert_eq!(repo.select_channel_member_ids(general.id).unwrap(), vec![1, 2]);
+        let channels = repo.select_channels(3).unwrap();
+        assert_eq!(channels.iter().map(|c| (c.member_count, c.joined)).collect::<Vec<_>>(), vec![(2, false)]);
+
+        repo.leave_channel(1, general.id).unwrap();
+        assert_eq!(repo.select_channel_member_ids(general.id).unwrap(), vec![2]);
+        assert!(!repo.select_channel(1, general.id).unwrap().unwrap().joined);
+    }
+
+    #[test]
+    fn sends_to_channel_members() {
+        let repo = repo_with_users();
+        let general = repo.create_channel(1, "general").unwrap().id;
+        let alone = repo.insert_message(1, to_channel(general, "anyone?"), &[]).unwrap();
+        assert_eq!((alone.channel_id, alone.channel_name.as_deref()), (Some(general), Some("general")));
+        assert!(alone.recipients.is_empty());
+
+        assert!(matches!(repo.insert_message(2, to_channel(general, "hi"), &[]),
+            Err(InsertMessageError::NotMember(_))));
+        assert!(matches!(repo.insert_message(1, to_channel(999, "hi"), &[]),
+            Err(InsertMessageError::UnknownChannel(999))));
+        assert!(matches!(repo.insert_message(1, m::PostMessageRequest { channel_id: Some(general), ..message(vec![2]) }, &[]),
+            Err(InsertMessageError::ChannelWithRecipients)));
+
+        repo.join_channel(2, general).unwrap();
+        let hello = repo.insert_message(2, to_channel(general, "hello"), &[]).unwrap();
+        assert_eq!(hello.recipients.iter().map(|r| r.id).collect::<Vec<_>>(), vec![1]);
+        let direct = repo.insert_message(1, message(vec![2]), &[]).unwrap();
+
+        // history from before joining is visible, direct messages are kept apart
+        let ids = |user, conversation| repo.select_messages_for_user(user, conversation, None, None, 10).unwrap()
+            .messages.into_iter().map(|m| m.id).collect::<Vec<_>>();
+        assert_eq!(ids(2, Conversation::Channel(general)), vec![alone.id, hello.id]);
+        assert_eq!(ids(2, Conversation::Direct), vec![direct.id]);
+        assert_eq!(ids(2, Conversation::All), vec![hello.id, direct.id]);
+        assert!(repo.select_message_for_user(2, alone.id).unwrap().is_some());
+        assert!(repo.select_message_for_user(3, alone.id).unwrap().is_none());
+
+        // received messages stay visible after leaving
+        repo.leave_channel(1, general).unwrap();
+        assert!(repo.select_message_for_user(1, hello.id).unwrap().is_some());
+        repo.leave_channel(2, general).unwrap();
+        assert!(repo.select_message_for_user(2, alone.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn mentions_add_direct_recipients_and_channel_members_only() {
+        let repo = repo_with_users();
+        let text = |text: &str, recipients| m::PostMessageRequest { text: text.to_owned(), ..message(recipients) };
+        let direct = repo.insert_message(1, text("@Carol look, @alice @nobody", vec![2]), &[]).unwrap();
+        assert_eq!(direct.recipients.iter().map(|r| r.id).collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(direct.mentions, vec![3]);
+        assert!(direct.html.contains("<span class=\"mention\" data-user-id=\"3\">@Carol</span>"));
+        let only_mention = repo.insert_message(1, text("@bob?", vec![]), &[]).unwrap();
+        assert_eq!((only_mention.recipients.len(), only_mention.mentions.clone()), (1, vec![2]));
+        assert!(matches!(repo.insert_message(1, text("@nobody", vec![]), &[]), Err(InsertMessageError::NoRecipients)));
+
+        let general = repo.create_channel(1, "general").unwrap().id;
+        repo.join_channel(2, general).unwrap();
+        let in_channel = repo.insert_message(1, to_channel(general, "@bob and @carol"), &[]).unwrap();
+        assert_eq!(in_channel.mentions, vec![2]);
+        assert_eq!(in_channel.recipients.len(), 1);
+
+        // edits mention among the recipients, a deleted message mentions nobody
+        assert_eq!(repo.edit_message(1, direct.id, "@bob only").unwrap().mentions, vec![2]);
+        assert!(repo.edit_message(1, only_mention.id, "@carol").unwrap().mentions.is_empty());
+        assert!(repo.delete_message(1, direct.id).unwrap().mentions.is_empty());
+        assert_eq!(count(&repo, "message_mentions"), 1);
+    }
+
+    #[test]
+    fn searches_visible_messages_kept_in_sync() {
+        let repo = repo_with_users();
+        let text = |t: &str| m::PostMessageRequest { text: t.to_owned(), ..message(vec![2]) };
+        let lunch = repo.insert_message(1, text("Lunch at noon? The usual place"), &[]).unwrap().id;
+        let both = repo.insert_message(1, text("lunch lunch, really hungry"), &[]).unwrap().id;
+        let private = repo.insert_message(1, m::PostMessageRequest { text: "lunch with carol".to_owned(), ..message(vec![3]) }, &[]).unwrap().id;
+
+        let ids = |user, query: &str| repo.search_messages(user, query, 0, 10).unwrap()
+            .results.into_iter().map(|r| r.message.id).collect::<Vec<_>>();
+        assert_eq!(ids(2, "LUNCH"), vec![both, lunch]);
+        assert_eq!(ids(1, "lunch"), vec![both, private, lunch]);
+        assert_eq!(ids(3, "lunch"), vec![private]);
+        assert_eq!(ids(2, "lunch noon"), vec![lunch]);
+        assert_eq!(ids(2, "\"noon OR"), Vec::<u32>::new());
+        assert_eq!(ids(2, " "), Vec::<u32>::new());
+
+        let page = repo.search_messages(2, "lunch", 1, 1).unwrap();
+        assert_eq!((page.results[0].message.id, page.has_more), (lunch, false));
+        assert_eq!(page.results[0].snippet[0], m::SnippetPart { text: "Lunch".to_owned(), highlighted: true });
+
+        repo.edit_message(1, lunch, "Dinner at six").unwrap();
+        assert_eq!(ids(2, "lunch"), vec![both]);
+        assert_eq!(ids(2, "dinner"), vec![lunch]);
+        repo.delete_message(1, both).unwrap();
+        assert_eq!(ids(2, "lunch"), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn registers_with_invites_until_used_up_or_expired() {
+        let repo = repo_with_users();
+        let invite = repo.create_invite(2, now() + 60).unwrap();
+        let request = |invite: &str, username: &str| m::RegisterRequest {
+            invite: invite.to_owned(), username: username.to_owned(), name: "Dave".to_owned(), ..Default::default()
+        };
+        assert!(matches!(repo.register_user(&request("wrong", "dave"), "gray", "hash"), Err(RegisterError::InvalidInvite)));
+        assert!(matches!(repo.register_user(&request(&invite.code, "BOB"), "gray", "hash"), Err(RegisterError::UsernameTaken)));
+        let dave = repo.register_user(&request(&invite.code, "dave"), "gray", "hash").unwrap();
+        assert_eq!(repo.select_user_credentials("Dave").unwrap().unwrap().id, dave);
+        repo.register_user(&request(&invite.code, "erin"), "gray", "hash").unwrap();
+        assert!(matches!(repo.register_user(&request(&invite.code, "frank"), "gray", "hash"), Err(RegisterError::InvalidInvite)));
+        assert!(repo.select_valid_invites().unwrap().is_empty());
+
+        let invite = repo.create_invite(5, now() + 60).unwrap();
+        assert_eq!(repo.select_valid_invites().unwrap().len(), 1);
+        assert!(repo.revoke_invite(&invite.code).unwrap());
+        assert!(!repo.revoke_invite(&invite.code).unwrap());
+        assert!(matches!(repo.register_user(&request(&invite.code, "frank"), "gray", "hash"), Err(RegisterError::InvalidInvite)));
+    }
+
+    #[test]
+    fn deletes_user_with_their_messages() {
+        let repo = repo_with_users();
+        let file = m::StoredFile { original_name: "a.txt".to_owned(), stored_name: "stored_a".to_owned() };
+        let from_bob = repo.insert_message(2, message(vec![1, 3]), &[file]).unwrap().id;
+        let answer = repo.insert_message(1, reply(from_bob, vec![2, 3], "thanks"), &[]).unwrap().id;
+        repo.edit_message(2, from_bob, "hello!").unwrap();
+        let to_bob = repo.insert_message(3, m::PostMessageRequest { text: "@bob".to_owned(), ..message(vec![1, 2]) }, &[])
+            .unwrap().id;
+        let channel = repo.create_channel(2, "bobs").unwrap().id;
+        repo.join_channel(1, channel).unwrap();
+        repo.add_reaction(2, to_bob, "👍").unwrap();
+        repo.add_reaction(3, from_bob, "👍").unwrap();
+
+        assert_eq!(repo.delete_user(2).unwrap(), vec!["stored_a".to_owned()]);
+        assert!(repo.select_user_credentials("bob").unwrap().is_none());
+        let answer = repo.select_message_by_id(answer).unwrap();
+        assert_eq!((answer.reply_to, answer.quote.is_none()), (None, true));
+        assert_eq!(repo.select_message_by_id(to_bob).unwrap().recipients.len(), 1);
+        assert!(repo.select_message_for_user(1, from_bob).unwrap().is_none());
+        assert_eq!(repo.select_channel_member_ids(channel).unwrap(), vec![1]);
+        assert_eq!((count(&repo, "files"), count(&repo, "message_revisions")), (0, 0));
+        assert_eq!((count(&repo, "message_mentions"), count(&repo, "message_reactions")), (0, 0));
+        let violations = repo.conn.prepare("PRAGMA foreign_key_check").unwrap()
+            .query_map(rusqlite::NO_PARAMS, |_| Ok(())).unwrap().count();
+        assert_eq!(violations, 0);
+    }
+}
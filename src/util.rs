@@ -1,193 +1,320 @@
 
-#[derive(PartialEq, Debug)]
-pub enum ContainsResult {
-    DoesNotContain,
-    PossiblyContains (usize),
-    Contains (usize)
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+use crate::model;
+
+fn random_alphanumeric(len: usize) -> String {
+    return rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect();
 }
 
-//generate bm_bc for Boyer-Moore algorithm.
-fn generate_bm_bc(subslice: &[u8]) -> [u8; 256] {
-    let len = subslice.len() as u8;
-    let mut bm_bc: [u8; 256] = [len; 256];
+/// Generates a random name to store an uploaded file under
+pub fn generate_stored_name() -> String {
+    return random_alphanumeric(32);
+}
 
-    for i in 0..len - 1 {
-        bm_bc[ subslice[i as usize] as usize ] = len - i - 1;
-    }
-    return bm_bc;
+/// Generates a code for an invite, easy to read out and type
+pub fn generate_invite_code() -> String {
+    return random_alphanumeric(16);
 }
 
+/// Generates a random key for signing tokens
+pub fn generate_secret() -> String {
+    return random_alphanumeric(43);
+}
 
-//find subslice with Boyer-Moore algorithm.
-fn find_subslice(slice: &[u8], subslice: &[u8]) -> Option<usize> {
-    let size_subslice = subslice.len();
-    let size_slice = slice.len();
-    let mut j = 0;
-    let bm_bc = generate_bm_bc(subslice);
-    let mut c;
-   /* Searching */
+/// Strips characters that can not be safely put into a quoted header value.
+/// Header values have to be ASCII, so other characters are replaced.
+pub fn sanitize_file_name(name: &str) -> String {
+    return name.chars()
+        .filter(|c| !c.is_control() && *c != '"' && *c != '\\')
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect();
+}
 
-    while j <= (size_slice - size_subslice) {
-        c = slice[j + size_subslice - 1];
-        if subslice[size_subslice - 1] == c && 
-            subslice[..size_subslice] == slice[j..j+size_subslice] {
-            return Some(j);
+/// Builds `Content-Disposition` for a download. The original name is passed
+/// percent-encoded as described in RFC 5987, with an ASCII fallback for old clients.
+pub fn content_disposition(file_name: &str) -> String {
+    let mut encoded = String::new();
+    for byte in file_name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
         }
-        j += bm_bc[c as usize] as usize;
     }
+    return format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", sanitize_file_name(file_name), encoded);
+}
 
-    return None;
+/// Cuts text to at most `length` characters, marking the cut with an ellipsis
+pub fn excerpt(text: &str, length: usize) -> String {
+    let text = text.trim();
+    match text.char_indices().nth(length) {
+        Some((end, _)) => { return format!("{}…", text[..end].trim_end()); }
+        None => { return text.to_owned(); }
+    }
 }
 
-/// Returns option of position of one subslice in an other
-pub fn contains(slice: &[u8], subslice: &[u8]) -> ContainsResult {
-   
-    let mut i;
+/// Checks a login name. Only ASCII is allowed, so that the database compares
+/// usernames regardless of case the same way everywhere.
+pub fn check_username(username: &str) -> Result<(), &'static str> {
+    if username.is_empty() || username.len() > 32 {
+        return Err("Username must have between 1 and 32 characters");
+    }
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c)) {
+        return Err("Username can only contain latin letters, digits, dots, dashes and underscores");
+    }
+    return Ok(());
+}
 
-    if slice.len() >= subslice.len() {
-        if let Some(value) = find_subslice(slice, subslice) {
-            return ContainsResult::Contains(value);
-        }
-        i = slice.len() + 1 - subslice.len();
+/// Checks a reaction. It has to look like one emoji, which may be a sequence of
+/// code points: pictographs joined with zero width joiners, variation selectors,
+/// skin tones, tags, or a keycap. Exact sequences are not checked.
+pub fn check_emoji(emoji: &str) -> Result<(), &'static str> {
+    if emoji.chars().count() > 16 || !emoji.chars().any(is_pictograph) {
+        return Err("Reaction must be an emoji");
     }
-    else {
-        i = 0;
+    let keycap = emoji.contains('\u{20E3}');
+    let allowed = |c: char| is_pictograph(c) || is_emoji_modifier(c) || (keycap && "0123456789#*".contains(c));
+    if !emoji.chars().all(allowed) {
+        return Err("Reaction must be an emoji");
     }
+    return Ok(());
+}
 
-    let mut streak = 0;
-    while i < slice.len() {
-        if slice[i] == subslice[streak] {
-            streak += 1;
-        } else {
-            i -= streak;
-            streak = 0;
-        }
-        i += 1;
-    }
-    if streak > 0 {
-        return ContainsResult::PossiblyContains (slice.len() - streak);
-    } else {
-        return ContainsResult::DoesNotContain;
-    }
-}
-
-
-
-
-
-pub mod multipart {
-
-    #[derive(Debug)]
-    pub(crate) struct BodyPartInfo {
-        pub content_type: Option<String>,
-        pub field_name: String,
-        pub file_name: Option<String>
-    }
-
-    impl BodyPartInfo {
-        pub fn from_headers(headers: &str) -> Result<Self, tide::Error> {
-            let mut content_type = None;
-            let mut file_name = None;
-            let mut field_name = None;
-
-            for header in headers.split("\r\n") {
-                if header.is_empty() { continue; }
-                let mut header_split = header.split(": ");
-                match header_split.next().ok_or(tide::Error::from_str(400, "Malformed body"))? {
-                    "Content-Disposition" => {
-                        let mut disposition_split = header_split.next()
-                                .ok_or(tide::Error::from_str(400, "Malformed body"))?
-                                .split(";");
-                        let content_disposition = disposition_split.next()
-                            .ok_or(tide::Error::from_str(400, "Malformed body"))?;
-                        if content_disposition.trim() != "form-data" {
-                            return Err(tide::Error::from_str(400, "Unknown content disposition"));
-                        }
-                        for other in disposition_split.into_iter() {
-                            let mut key_value = other.trim().split("=");
-                            let key = key_value.next()
-                                .ok_or(tide::Error::from_str(400, "Malformed body"))?;
-                            let value = key_value.next()
-                                .ok_or(tide::Error::from_str(400, "Malformed body"))?
-                                .trim_matches('"');
-                        
-                            match key { 
-                                "name" => { field_name = Some(value.to_owned()) },
-                                "filename" => { file_name = Some(value.to_owned()) },
-                                _ => {}
-                            }
-
-
-                        }
-                    },
-                    "Content-Type" => {
-                        content_type = header_split.next().map(|v| v.to_owned()) ;
-                    },
-                    _ => {}
-
-                }
-
-            };
-            let field_name = field_name
-                .ok_or(tide::Error::from_str(400, "No fielname provided"))?;
-            return Ok(Self { field_name, content_type, file_name });
-        }
+/// Code points of the emoji blocks, symbols shown as emoji and the keycap mark
+fn is_pictograph(c: char) -> bool {
+    return matches!(c as u32,
+        0x00A9 | 0x00AE | 0x203C | 0x2049 | 0x20E3 | 0x2122 | 0x2139 | 0x2194..=0x21AA | 0x231A..=0x23FF
+        | 0x24C2 | 0x25AA..=0x25FE | 0x2600..=0x27BF | 0x2934 | 0x2935 | 0x2B05..=0x2B55
+        | 0x3030 | 0x303D | 0x3297 | 0x3299 | 0x1F000..=0x1FAFF);
+}
+
+/// Code points that change the emoji before them or join it with the next one
+fn is_emoji_modifier(c: char) -> bool {
+    // zero width joiner, variation selectors, tags of subdivision flags
+    return matches!(c as u32, 0x200D | 0xFE0E | 0xFE0F | 0xE0020..=0xE007F);
+}
+
+pub fn check_password(password: &str) -> Result<(), &'static str> {
+    if password.chars().count() < 8 {
+        return Err("Password must have at least 8 characters");
     }
+    return Ok(());
 }
 
+/// Checks a displayed name. Its capital letters are shown as the acronym, so it needs one.
+pub fn check_display_name(name: &str) -> Result<(), &'static str> {
+    if name.trim().is_empty() || name.chars().count() > 64 {
+        return Err("Name must have between 1 and 64 characters");
+    }
+    if !name.chars().any(char::is_uppercase) {
+        return Err("Name must contain a capital letter");
+    }
+    return Ok(());
+}
 
+/// Named CSS colors, sorted by name
+const CSS_COLORS: [(&str, u32); 148] = [
+    ("aliceblue", 0xf0f8ff), ("antiquewhite", 0xfaebd7), ("aqua", 0x00ffff), ("aquamarine", 0x7fffd4),
+    ("azure", 0xf0ffff), ("beige", 0xf5f5dc), ("bisque", 0xffe4c4), ("black", 0x000000),
+    ("blanchedalmond", 0xffebcd), ("blue", 0x0000ff), ("blueviolet", 0x8a2be2), ("brown", 0xa52a2a),
+    ("burlywood", 0xdeb887), ("cadetblue", 0x5f9ea0), ("chartreuse", 0x7fff00), ("chocolate", 0xd2691e),
+    ("coral", 0xff7f50), ("cornflowerblue", 0x6495ed), ("cornsilk", 0xfff8dc), ("crimson", 0xdc143c),
+    ("cyan", 0x00ffff), ("darkblue", 0x00008b), ("darkcyan", 0x008b8b), ("darkgoldenrod", 0xb8860b),
+    ("darkgray", 0xa9a9a9), ("darkgreen", 0x006400), ("darkgrey", 0xa9a9a9), ("darkkhaki", 0xbdb76b),
+    ("darkmagenta", 0x8b008b), ("darkolivegreen", 0x556b2f), ("darkorange", 0xff8c00),
+    ("darkorchid", 0x9932cc), ("darkred", 0x8b0000), ("darksalmon", 0xe9967a), ("darkseagreen", 0x8fbc8f),
+    ("darkslateblue", 0x483d8b), ("darkslategray", 0x2f4f4f), ("darkslategrey", 0x2f4f4f),
+    ("darkturquoise", 0x00ced1), ("darkviolet", 0x9400d3), ("deeppink", 0xff1493), ("deepskyblue", 0x00bfff),
+    ("dimgray", 0x696969), ("dimgrey", 0x696969), ("dodgerblue", 0x1e90ff), ("firebrick", 0xb22222),
+    ("floralwhite", 0xfffaf0), ("forestgreen", 0x228b22), ("fuchsia", 0xff00ff), ("gainsboro", 0xdcdcdc),
+    ("ghostwhite", 0xf8f8ff), ("gold", 0xffd700), ("goldenrod", 0xdaa520), ("gray", 0x808080),
+    ("green", 0x008000), ("greenyellow", 0xadff2f), ("grey", 0x808080), ("honeydew", 0xf0fff0),
+    ("hotpink", 0xff69b4), ("indianred", 0xcd5c5c), ("indigo", 0x4b0082), ("ivory", 0xfffff0),
+    ("khaki", 0xf0e68c), ("lavender", 0xe6e6fa), ("lavenderblush", 0xfff0f5), ("lawngreen", 0x7cfc00),
+    ("lemonchiffon", 0xfffacd), ("lightblue", 0xadd8e6), ("lightcoral", 0xf08080), ("lightcyan", 0xe0ffff),
+    ("lightgoldenrodyellow", 0xfafad2), ("lightgray", 0xd3d3d3), ("lightgreen", 0x90ee90),
+    ("lightgrey", 0xd3d3d3), ("lightpink", 0xffb6c1), ("lightsalmon", 0xffa07a), ("lightseagreen", 0x20b2aa),
+    ("lightskyblue", 0x87cefa), ("lightslategray", 0x778899), ("lightslategrey", 0x778899),
+    ("lightsteelblue", 0xb0c4de), ("lightyellow", 0xffffe0), ("lime", 0x00ff00), ("limegreen", 0x32cd32),
+    ("linen", 0xfaf0e6), ("magenta", 0xff00ff), ("maroon", 0x800000), ("mediumaquamarine", 0x66cdaa),
+    ("mediumblue", 0x0000cd), ("mediumorchid", 0xba55d3), ("mediumpurple", 0x9370db),
+    ("mediumseagreen", 0x3cb371), ("mediumslateblue", 0x7b68ee), ("mediumspringgreen", 0x00fa9a),
+    ("mediumturquoise", 0x48d1cc), ("mediumvioletred", 0xc71585), ("midnightblue", 0x191970),
+    ("mintcream", 0xf5fffa), ("mistyrose", 0xffe4e1), ("moccasin", 0xffe4b5), ("navajowhite", 0xffdead),
+    ("navy", 0x000080), ("oldlace", 0xfdf5e6), ("olive", 0x808000), ("olivedrab", 0x6b8e23),
+    ("orange", 0xffa500), ("orangered", 0xff4500), ("orchid", 0xda70d6), ("palegoldenrod", 0xeee8aa),
+    ("palegreen", 0x98fb98), ("paleturquoise", 0xafeeee), ("palevioletred", 0xdb7093),
+    ("papayawhip", 0xffefd5), ("peachpuff", 0xffdab9), ("peru", 0xcd853f), ("pink", 0xffc0cb),
+    ("plum", 0xdda0dd), ("powderblue", 0xb0e0e6), ("purple", 0x800080), ("rebeccapurple", 0x663399),
+    ("red", 0xff0000), ("rosybrown", 0xbc8f8f), ("royalblue", 0x4169e1), ("saddlebrown", 0x8b4513),
+    ("salmon", 0xfa8072), ("sandybrown", 0xf4a460), ("seagreen", 0x2e8b57), ("seashell", 0xfff5ee),
+    ("sienna", 0xa0522d), ("silver", 0xc0c0c0), ("skyblue", 0x87ceeb), ("slateblue", 0x6a5acd),
+    ("slategray", 0x708090), ("slategrey", 0x708090), ("snow", 0xfffafa), ("springgreen", 0x00ff7f),
+    ("steelblue", 0x4682b4), ("tan", 0xd2b48c), ("teal", 0x008080), ("thistle", 0xd8bfd8),
+    ("tomato", 0xff6347), ("turquoise", 0x40e0d0), ("violet", 0xee82ee), ("wheat", 0xf5deb3),
+    ("white", 0xffffff), ("whitesmoke", 0xf5f5f5), ("yellow", 0xffff00), ("yellowgreen", 0x9acd32),
+];
+
+/// Lowest contrast ratio of a name to the white page, as WCAG asks of large text
+const MIN_CONTRAST: f64 = 3.0;
+
+/// Parses a CSS color name or hex code into red, green and blue, blended onto white if
+/// it is translucent
+fn parse_color(color: &str) -> Option<[f64; 3]> {
+    if let Some(hex) = color.strip_prefix('#') {
+        if ![3, 4, 6, 8].contains(&hex.len()) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        // every digit of the short forms is doubled
+        let digits: Vec<u8> = hex.bytes().map(|c| (c as char).to_digit(16).unwrap() as u8).collect();
+        let channels: Vec<f64> = if hex.len() <= 4 {
+            digits.iter().map(|d| f64::from(d * 17)).collect()
+        } else {
+            digits.chunks(2).map(|d| f64::from(d[0] * 16 + d[1])).collect()
+        };
+        let alpha = channels.get(3).map(|a| a / 255.0).unwrap_or(1.0);
+        let blend = |c: f64| c * alpha + 255.0 * (1.0 - alpha);
+        return Some([blend(channels[0]), blend(channels[1]), blend(channels[2])]);
+    }
+    let index = CSS_COLORS.binary_search_by_key(&color.to_ascii_lowercase().as_str(), |(name, _)| name).ok()?;
+    let rgb = CSS_COLORS[index].1;
+    return Some([f64::from(rgb >> 16), f64::from((rgb >> 8) & 0xff), f64::from(rgb & 0xff)]);
+}
 
+/// Contrast ratio of a color to white, from 1 to 21
+fn contrast_to_white(rgb: [f64; 3]) -> f64 {
+    let linear = |c: f64| {
+        let c = c / 255.0;
+        if c <= 0.03928 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+    };
+    let luminance = 0.2126 * linear(rgb[0]) + 0.7152 * linear(rgb[1]) + 0.0722 * linear(rgb[2]);
+    return 1.05 / (luminance + 0.05);
+}
 
+/// Checks that a color is a CSS color name or hex code, so it is safe to put into a style,
+/// and that a name in it can be read on the page
+pub fn check_color(color: &str) -> Result<(), &'static str> {
+    let rgb = parse_color(color)
+        .ok_or("Color must be a CSS color name or a hex code like #1e90ff")?;
+    if contrast_to_white(rgb) < MIN_CONTRAST {
+        return Err("Color is too light to read on the white page");
+    }
+    return Ok(());
+}
 
+/// Turns words typed by a user into an FTS5 query matching all of them.
+/// Every word is quoted, so operators and punctuation are searched for literally.
+pub fn fts_query(text: &str) -> String {
+    return text.split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ");
+}
 
+/// Splits a snippet with matches enclosed in `\u{1}` and `\u{2}` into parts
+pub fn snippet_parts(snippet: &str) -> Vec<model::SnippetPart> {
+    let mut parts = Vec::new();
+    for (i, piece) in snippet.split('\u{1}').enumerate() {
+        // every piece but the first starts with a match
+        let (matched, rest) = match piece.split_once('\u{2}') {
+            Some((matched, rest)) if i > 0 => { (matched, rest) }
+            _ => { ("", piece) }
+        };
+        for (text, highlighted) in [(matched, true), (rest, false)].iter() {
+            if !text.is_empty() {
+                parts.push(model::SnippetPart { text: text.to_string(), highlighted: *highlighted });
+            }
+        }
+    }
+    return parts;
+}
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
     #[test]
-    fn contains_works_with_bytes() {
-        assert_eq!(contains(&[1,2,3,4,5], &[1,2,3]), ContainsResult::Contains(0));
-        assert_eq!(contains(&[1,2,3,4,5], &[3,4,5]), ContainsResult::Contains(2));
-        assert_eq!(contains(&[1,2,1,2,3], &[1,2,1]), ContainsResult::Contains(0));
-        assert_eq!(contains(&[1,2,1,2,3], &[1,2,3]), ContainsResult::Contains(2));
-        assert_eq!(contains(&[1,2,3,4,5], &[1,2,5]), ContainsResult::DoesNotContain);
-        assert_eq!(contains(&[1,2,1,2,3], &[1,2,4]), ContainsResult::DoesNotContain);
-        assert_eq!(contains(&[1,2,3,4,5], &[4,5,6]), ContainsResult::PossiblyContains(3));
-
+    fn sanitize_file_name_strips_quotes() {
+        assert_eq!(sanitize_file_name("report.pdf"), "report.pdf");
+        assert_eq!(sanitize_file_name("a\"b.txt\r\nX-Evil: 1"), "ab.txtX-Evil: 1");
     }
 
     #[test]
-    fn contains_works_with_strs() {
-        assert_eq!(contains(b"hello world", b"hello"), ContainsResult::Contains(0));
-        assert_eq!(contains(b"hello there", b"there"), ContainsResult::Contains(6));
-        assert_eq!(contains(b"----hello", b"--hello"), ContainsResult::Contains(2));
-        assert_eq!(contains(b"----hell", b"--hello"), ContainsResult::PossiblyContains(2));
-        assert_eq!(contains(b"--hello there general", b"hello there"), ContainsResult::Contains(2));
-        assert_eq!(contains(b"hello there general", b"general kenobi"), ContainsResult::PossiblyContains(12));
-        assert_eq!(contains(b"hello there kenobi", b"general kenobi"), ContainsResult::DoesNotContain);
+    fn content_disposition_encodes_non_ascii_names() {
+        let value = content_disposition("отчёт 1.pdf");
+        assert_eq!(value, "attachment; filename=\"_____ 1.pdf\"; \
+            filename*=UTF-8''%D0%BE%D1%82%D1%87%D1%91%D1%82%201.pdf");
+        assert!(tide::http::headers::HeaderValue::from_bytes(value.into_bytes()).is_ok());
+        assert_eq!(content_disposition("a\"b.txt"), "attachment; filename=\"ab.txt\"; filename*=UTF-8''a%22b.txt");
     }
 
     #[test]
-    fn contains_works_with_reqs() {
-        assert_eq!(
-            contains(
-                b"------WebKitFormBoundaryGkEAO60J3WyaOnEr\r\nContent-Disposition: form-data; name=\"t", 
-                b"----WebKitFormBoundaryGkEAO60J3WyaOnEr"
-            ), 
-            ContainsResult::Contains(2)
-        );
-        assert_eq!(
-            contains(
-                b"------WebKitFormBoundaryGkEAO60J3Wya", 
-                b"----WebKitFormBoundaryGkEAO60J3WyaOnEr"
-            ), 
-            ContainsResult::PossiblyContains(2)
-        );
+    fn excerpt_cuts_long_text() {
+        assert_eq!(excerpt(" short ", 10), "short");
+        assert_eq!(excerpt("0123456789", 10), "0123456789");
+        assert_eq!(excerpt("привет мир и все", 10), "привет мир…");
+        assert_eq!(excerpt("привет  мир", 7), "привет…");
     }
 
+    #[test]
+    fn fts_query_quotes_words() {
+        assert_eq!(fts_query("  hello   world "), "\"hello\" \"world\"");
+        assert_eq!(fts_query("a\"b OR c*"), "\"a\"\"b\" \"OR\" \"c*\"");
+        assert_eq!(fts_query(" "), "");
+    }
 
-}
-
+    #[test]
+    fn snippet_parts_mark_matches() {
+        let part = |text: &str, highlighted| model::SnippetPart { text: text.to_owned(), highlighted };
+        assert_eq!(snippet_parts("say \u{1}hello\u{2} to \u{1}all\u{2}"),
+            vec![part("say ", false), part("hello", true), part(" to ", false), part("all", true)]);
+        assert_eq!(snippet_parts("\u{1}hi\u{2}"), vec![part("hi", true)]);
+        assert_eq!(snippet_parts("no match"), vec![part("no match", false)]);
+    }
 
+    #[test]
+    fn checks_user_fields() {
+        assert!(check_username("alice_1").is_ok());
+        assert!(check_username("al:ice").is_err());
+        assert!(check_username("al ice").is_err());
+        assert!(check_username("алиса").is_err());
+        assert!(check_password("short").is_err());
+        assert!(check_username("").is_err());
+        assert!(check_display_name("Алиса").is_ok());
+        assert!(check_display_name("alice").is_err());
+        assert!(check_color("darkslategrey").is_ok());
+        assert!(check_color("#1E90ff").is_ok());
+        assert!(check_color("#12345").is_err());
+        assert!(check_color("red; background: url(x)").is_err());
+        assert!(check_color("notacolor").is_err());
+        assert!(check_color("DarkRed").is_ok());
+        assert!(check_color("#00000080").is_ok());
+        assert_eq!(check_color("yellow"), Err("Color is too light to read on the white page"));
+        assert!(check_color("#ff08").is_err());
+        assert!(check_color("#fff").is_err());
+    }
 
+    #[test]
+    fn checks_emoji() {
+        assert!(check_emoji("👍").is_ok());
+        assert!(check_emoji("👩‍👩‍👧").is_ok());
+        assert!(check_emoji("1️⃣").is_ok());
+        assert!(check_emoji("❤️").is_ok());
+        assert!(check_emoji("👍🏽").is_ok());
+        assert!(check_emoji("🇺🇦").is_ok());
+        assert!(check_emoji("").is_err());
+        assert!(check_emoji("lol").is_err());
+        assert!(check_emoji("!!!").is_err());
+        assert!(check_emoji("12").is_err());
+        assert!(check_emoji("<>").is_err());
+        assert!(check_emoji("漢字").is_err());
+        assert!(check_emoji("1👍").is_err());
+        assert!(check_emoji("👍 👍").is_err());
+        assert!(check_emoji(&"👍".repeat(17)).is_err());
+    }
+}
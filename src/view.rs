@@ -1,10 +1,8 @@
-use tera;
+use std::collections::HashMap;
 use serde::Serialize;
 use chrono::TimeZone;
 use crate::model;
-use regex;
 use regex::Regex;
-use chrono;
 use lazy_static::lazy_static;
 
 
@@ -14,11 +12,36 @@ pub struct View {
 
 #[derive(Serialize)]
 struct ViewMessage {
+    id: u32,
     sender: ViewPerson,
     text: String,
+    /// Rendered from Markdown and escaped already
+    html: String,
     time: String,
     time_full: String,
-    recipients: Vec<ViewPerson>
+    recipients: Vec<ViewPerson>,
+    attachments: Vec<model::EmbeddedAttachment>,
+    /// Time of the last edit
+    edited: Option<String>,
+    /// Time the self-destruct timer runs out at
+    expires: Option<String>,
+    deleted: bool,
+    quote: Option<model::Quote>,
+    /// Name of the channel the message was sent to
+    channel: Option<String>,
+    /// The reader is mentioned in the message
+    mentioned: bool,
+    reactions: Vec<ViewReaction>
+}
+
+#[derive(Serialize)]
+struct ViewReaction {
+    emoji: String,
+    count: usize,
+    /// Names of the users who reacted
+    names: String,
+    /// The reader is one of them
+    mine: bool
 }
 
 #[derive(Serialize)]
@@ -26,9 +49,45 @@ struct ViewPerson {
     id: u32,
     name: String,
     acronym: String,
-    color: String
+    color: String,
+    /// `delivered` or `read` for recipients of a message
+    status: &'static str
+}
+
+/// User in the list of recipients
+#[derive(Serialize)]
+struct ViewUser {
+    id: u32,
+    name: String,
+    acronym: String,
+    color: String,
+    online: bool,
+    last_seen: Option<String>
 }
 
+#[derive(Serialize)]
+struct ViewSearchResult {
+    id: u32,
+    sender: ViewPerson,
+    time_full: String,
+    /// Name of the channel, none for direct messages
+    channel: Option<String>,
+    /// Page of the conversation ending with the message
+    link: String,
+    snippet: Vec<model::SnippetPart>
+}
+
+/// Message the user is writing an answer to
+#[derive(Serialize)]
+pub struct Reply {
+    pub message: model::MessageResponse,
+    /// Users the answer goes to unless changed
+    pub recipients: Vec<u32>
+}
+
+/// Offered by the reaction picker of the page, any other emoji can be sent too
+const REACTION_CHOICES: &[&str] = &["👍", "❤️", "😂", "🎉", "😮", "😢", "👀"];
+
 lazy_static!{
     static ref MATCHER : Regex = regex::Regex::new("[[:upper:]]").unwrap();
 }
@@ -37,21 +96,43 @@ fn to_acronym(name: &str) -> String {
     return MATCHER.find_iter(name).fold(String::new(),|a,b| a+b.as_str());
 }
 
+fn receipt_status(recipient: &model::MessageRecipient) -> &'static str {
+    if recipient.read_at.is_some() {
+        return "read";
+    } else if recipient.delivered_at.is_some() {
+        return "delivered";
+    }
+    return "";
+}
+
 impl View {
+    /// Renders a page of history of direct messages or of `channel` for the user.
+    /// `is_latest` tells that there are no newer messages.
+    #[allow(clippy::too_many_arguments)]
     pub fn render_index(
         &self, 
-        msgs: Vec<model::MessageResponse>, 
-        users: Vec<model::EmbeddedRecipient>
+        user_id: u32,
+        page: model::MessagePage, 
+        is_latest: bool,
+        users: Vec<model::User>,
+        reply: Option<Reply>,
+        channels: Vec<model::ChannelResponse>,
+        channel: Option<model::ChannelResponse>
     ) -> tera::Result<String> {
-        let view_messages: Vec<ViewMessage> = msgs.iter()
+        let older_than = page.messages.first().filter(|_| page.has_more).map(|m| m.id);
+        let names: HashMap<u32, &str> = users.iter().map(|u| (u.id, u.name.as_str())).collect();
+        let view_messages: Vec<ViewMessage> = page.messages.iter()
             .map(|m| ViewMessage {
+                id: m.id,
                 sender: ViewPerson { 
                     id: m.sender_id,
                     name: m.sender_name.clone(),
                     acronym: to_acronym(&m.sender_name),
-                    color: m.sender_color.clone()
+                    color: m.sender_color.clone(),
+                    status: ""
                 },
                 text: m.text.clone(),
+                html: m.html.clone(),
                 time: chrono::offset::Local.timestamp(m.timestamp,0).format("%H:%M").to_string(),
                 time_full: chrono::offset::Local.timestamp(m.timestamp,0).format("%Y-%m-%d %H:%M:%S").to_string(),
                 recipients: m.recipients.iter().map(|r| ViewPerson {
@@ -59,21 +140,94 @@ impl View {
                     name: r.name.clone(),
                     acronym: to_acronym(&r.name),
                     color: r.color.clone(),
+                    status: receipt_status(r)
                 }).collect(), 
-                
+                attachments: m.attachments.clone(),
+                edited: m.edited_at.map(|t| chrono::offset::Local.timestamp(t,0).format("%Y-%m-%d %H:%M:%S").to_string()),
+                expires: m.expires_at.map(|t| chrono::offset::Local.timestamp(t,0).format("%Y-%m-%d %H:%M:%S").to_string()),
+                deleted: m.deleted,
+                quote: m.quote.clone(),
+                channel: m.channel_name.clone(),
+                mentioned: m.mentions.contains(&user_id),
+                reactions: m.reactions.iter().map(|r| ViewReaction {
+                    emoji: r.emoji.clone(),
+                    count: r.user_ids.len(),
+                    names: r.user_ids.iter().filter_map(|id| names.get(id)).copied().collect::<Vec<_>>().join(", "),
+                    mine: r.user_ids.contains(&user_id)
+                }).collect()
             })
             .collect();
 
-        let view_users: Vec<ViewPerson> = users.into_iter()
-            .map(|u| ViewPerson {
-                id: u.id, color: u.color, name: u.name.clone(), acronym: to_acronym(&u.name)
+        let view_users: Vec<ViewUser> = users.into_iter()
+            .map(|u| ViewUser {
+                id: u.id, color: u.color, acronym: to_acronym(&u.name), name: u.name, online: u.online,
+                last_seen: u.last_seen.map(|t| chrono::offset::Local.timestamp(t,0).format("%Y-%m-%d %H:%M").to_string())
             })
             .collect();
 
         let mut context = tera::Context::new();
         context.insert("messages", &view_messages);
         context.insert("users", &view_users);
+        context.insert("older_than", &older_than);
+        context.insert("is_latest", &is_latest);
+        context.insert("reply", &reply);
+        context.insert("channels", &channels);
+        context.insert("channel", &channel);
+        context.insert("reaction_choices", REACTION_CHOICES);
 
         return self.tera.render("index.html", &context);
     }
+
+    /// Renders a page of search results starting at `offset`
+    pub fn render_search(&self, query: &str, page: model::SearchPage, offset: u32, page_size: u32) -> tera::Result<String> {
+        let previous_offset = if offset > 0 { Some(offset.saturating_sub(page_size)) } else { None };
+        let next_offset = if page.has_more { Some(offset + page_size) } else { None };
+        let results: Vec<ViewSearchResult> = page.results.into_iter()
+            .map(|r| ViewSearchResult {
+                id: r.message.id,
+                sender: ViewPerson {
+                    id: r.message.sender_id,
+                    acronym: to_acronym(&r.message.sender_name),
+                    name: r.message.sender_name,
+                    color: r.message.sender_color,
+                    status: ""
+                },
+                time_full: chrono::offset::Local.timestamp(r.message.timestamp,0).format("%Y-%m-%d %H:%M").to_string(),
+                link: match r.message.channel_id {
+                    Some(channel_id) => { format!("/?channel={}&before={}", channel_id, r.message.id + 1) }
+                    None => { format!("/?before={}", r.message.id + 1) }
+                },
+                channel: r.message.channel_name,
+                snippet: r.snippet
+            })
+            .collect();
+
+        let mut context = tera::Context::new();
+        context.insert("query", query);
+        context.insert("results", &results);
+        context.insert("previous_offset", &previous_offset);
+        context.insert("next_offset", &next_offset);
+
+        return self.tera.render("search.html", &context);
+    }
+
+    /// Renders the settings form with the profile, or with what was sent if it has an error
+    pub fn render_settings(&self, profile: &model::Profile, error: Option<&str>, saved: bool) -> tera::Result<String> {
+        let mut context = tera::Context::new();
+        context.insert("profile", profile);
+        context.insert("acronym", &to_acronym(&profile.name));
+        context.insert("error", &error);
+        context.insert("saved", &saved);
+
+        return self.tera.render("settings.html", &context);
+    }
+
+    /// Renders the sign-up form filled with what was sent, except the password
+    pub fn render_register(&self, form: &model::RegisterRequest, error: Option<&str>) -> tera::Result<String> {
+        let mut context = tera::Context::new();
+        context.insert("form", form);
+        context.insert("error", &error);
+
+        return self.tera.render("register.html", &context);
+    }
 }
@@ -4,13 +4,15 @@ const cookies = () => document.cookie
 
 
 const form = document.querySelector("form.sender");
-const form_text = document.querySelector("form.sender input[name=text]");
+const form_text = document.querySelector("form.sender [name=text]");
 const form_file = document.querySelector("form.sender input[name=upload-file");
 const statusbar = document.getElementById("form-status-bar");
 const submit = document.querySelector("form.sender input[type=submit]");
 const r_checks = Array.from(document.querySelectorAll("form.sender input[type=checkbox].recipient-checkbox"));
 const comma = document.createTextNode(",");
 const messages = document.querySelector(".messages");
+// null on the page of direct messages
+const channel_id = messages.dataset.channel ? parseInt(messages.dataset.channel) : null;
 const bracket = document.createTextNode("]");
 
 statusbar.style.color = "darkslategrey";
@@ -120,17 +122,166 @@ renderStatusBar();
 // =============================================================================
 // WEB SOCKETS
 
-let socket = new WebSocket("ws://"+window.location.host+"/websocket"); 
-socket.onopen = e => socket.send(cookies().token); 
-socket.onclose = (e) => {
-    alert("Websocket closed");
-    console.error(e);
+// protocol is described in docs/websocket.md
+
+// newest message shown, newer ones are replayed after reconnecting.
+// Pages of older history only show messages arriving live.
+let last_seen = messages.dataset.latest == "true"
+    ? Math.max(0, ...Array.from(document.querySelectorAll(".message[data-id]")).map(m => parseInt(m.dataset.id)))
+    : null;
+let socket = null;
+let user_id = null;
+let reconnect_delay = 1000;
+let session_rejected = false;
+
+const connect = () => {
+    socket = new WebSocket("ws://"+window.location.host+"/websocket");
+    socket.onopen = e => socket.send(JSON.stringify(
+        {type: "hello", version: 1, token: cookies().token, last_seen: last_seen}));
+    socket.onclose = (e) => {
+        console.error(e);
+        if (session_rejected) return;
+        setTimeout(connect, reconnect_delay);
+        reconnect_delay = Math.min(reconnect_delay * 2, 30000);
+    }
+    socket.onmessage = e => {
+        let frame = JSON.parse(e.data);
+        switch (frame.type) {
+            case "hello":
+                reconnect_delay = 1000;
+                user_id = frame.user_id;
+                markRead();
+                break;
+            case "message":
+                if (last_seen === null || frame.message.id > last_seen) {
+                    last_seen = frame.message.id;
+                    // messages of other conversations only mark them in the switcher
+                    if (frame.message.channel_id != channel_id) {
+                        markConversation(frame.message.channel_id);
+                        break;
+                    }
+                    renderMessage(frame.message);
+                    if (frame.message.sender_id != user_id) unread.push(frame.message.id);
+                    markRead();
+                }
+                break;
+            case "message_edited":
+            case "message_deleted":
+            case "message_reacted":
+                replaceMessage(frame.message);
+                break;
+            case "receipt": showReceipt(frame); break;
+            case "typing": showTyping(frame.user_id); break;
+            case "user_updated": updateUser(frame.user); break;
+            case "presence": showPresence(frame.user_id, frame.online); break;
+            case "mention": showMention(frame); break;
+            case "messages_expired": removeMessages(frame.message_ids); break;
+            case "error":
+                // the token is invalid or revoked, reconnecting would not help
+                if (frame.code == 401) session_rejected = true;
+                showError(frame.reason, frame.code);
+                break;
+        }
+    }
+}
+connect();
+
+// messages that were not seen yet, the server skips ones the user did not receive
+let unread = Array.from(document.querySelectorAll(".message[data-id]")).map(m => parseInt(m.dataset.id));
+const markRead = () => {
+    if (unread.length == 0 || document.visibilityState != "visible" || socket.readyState != WebSocket.OPEN) return;
+    while (unread.length > 0) {
+        socket.send(JSON.stringify({type: "mark_read", id: "read", message_ids: unread.splice(0, 200)}));
+    }
+}
+document.addEventListener("visibilitychange", markRead);
+
+const markConversation = (id) => {
+    const link = id === null
+        ? document.querySelector(".channel-link:not([data-id])")
+        : document.querySelector(".channel-link[data-id='" + id + "']");
+    if (link) link.classList.add("unread");
+}
+
+const showReceipt = (receipt) => {
+    const span = document.querySelector(
+        ".message[data-id='" + receipt.message_id + "'] .recipient[data-id='" + receipt.user_id + "']");
+    if (!span) return;
+    const status = receipt.read_at ? "read" : "delivered";
+    span.className = "recipient " + status;
+    span.title = span.title.split(",")[0] + ", " + status;
+}
+
+// tell recipients that a message is being written, at most every few seconds
+let last_typing = 0;
+form_text.addEventListener("input", () => {
+    const recipients = r_checks.filter(c => c.checked).map(c => parseInt(c.dataset.id));
+    if (recipients.length == 0 || socket.readyState != WebSocket.OPEN || Date.now() - last_typing < 3000) return;
+    last_typing = Date.now();
+    socket.send(JSON.stringify({type: "typing", recipients: recipients}));
+});
+
+const typing_bar = document.getElementById("typing-bar");
+let typing_timeouts = {};
+const renderTyping = () => {
+    typing_bar.textContent = Object.keys(typing_timeouts)
+        .map(id => document.querySelector("label[for=usr" + id + "] .acronym").textContent)
+        .join(", ");
+    if (typing_bar.textContent) typing_bar.textContent += " typing...";
+}
+const showTyping = (user_id) => {
+    if (!document.querySelector("label[for=usr" + user_id + "]")) return;
+    clearTimeout(typing_timeouts[user_id]);
+    typing_timeouts[user_id] = setTimeout(() => { delete typing_timeouts[user_id]; renderTyping(); }, 5000);
+    renderTyping();
 }
-socket.onmessage = e => {
-    let data = JSON.parse(e.data);
+
+const showPresence = (user_id, online) => {
+    const dot = document.querySelector("label[for=usr" + user_id + "] .presence");
+    if (!dot) return;
+    dot.classList.toggle("online", online);
+    dot.title = online ? "online" : "last seen " + new Date().toLocaleString();
+}
+
+// the message is already shown when it is in the open conversation
+const showMention = (mention) => {
+    const msg = document.querySelector(".message[data-id='" + mention.message_id + "']");
+    if (msg) msg.classList.add("mentioned");
+    else markConversation(mention.channel_id === undefined ? null : mention.channel_id);
+}
+
+// a user changed their name or color, the list of recipients shows the new ones
+const updateUser = (user) => {
+    const label = document.querySelector("label[for=usr" + user.id + "]");
+    if (!label) return;
+    const acronym = label.querySelector(".acronym");
+    acronym.textContent = user.name.match(/[A-ZА-Я]/g).reduce((a,b) => a+b);
+    acronym.style.color = user.color;
+    label.querySelector(".name").textContent = user.name;
+    const mark = r_arr_marks["usr" + user.id];
+    if (mark) {
+        mark.textContent = acronym.textContent;
+        mark.style.color = user.color;
+    }
+}
+
+const buildMessage = data => {
     let text_part = document.createElement("div");
-    text_part.className = "text";
-    text_part.textContent = data.text;
+    if (data.deleted) {
+        text_part.className = "text tombstone";
+        text_part.textContent = "message deleted";
+    } else {
+        text_part.className = "text";
+        // rendered and escaped by the server
+        text_part.innerHTML = data.html;
+        if (data.edited_at) {
+            let edited_span = document.createElement("span");
+            edited_span.className = "edited";
+            edited_span.title = new Date(data.edited_at*1000).toLocaleString();
+            edited_span.textContent = " (edited)";
+            text_part.appendChild(edited_span);
+        }
+    }
 
     let head_part = document.createElement("div");
     head_part.className = "head";
@@ -141,18 +292,25 @@ socket.onmessage = e => {
     sender_span.style.color = data.sender_color;
 
     head_part.appendChild(sender_span);
-    head_part.appendChild(document.createTextNode(" -> [ "));
-
-    data.recipients.forEach(each => {
-        let r_span = document.createElement("span");
-        r_span.title = each.name;
-        r_span.textContent = each.name.match(/[A-ZА-Я]/g).reduce((a,b) => a+b);
-        r_span.style.color = each.color;
-        head_part.appendChild(r_span);
-        head_part.appendChild(document.createTextNode(" "));
-    });
+    if (data.channel_name !== null) {
+        head_part.appendChild(document.createTextNode(" -> #" + data.channel_name + " "));
+    } else {
+        head_part.appendChild(document.createTextNode(" -> [ "));
+
+        data.recipients.forEach(each => {
+            let r_span = document.createElement("span");
+            const status = each.read_at ? "read" : each.delivered_at ? "delivered" : "";
+            r_span.className = "recipient " + status;
+            r_span.dataset.id = each.id;
+            r_span.title = status ? each.name + ", " + status : each.name;
+            r_span.textContent = each.name.match(/[A-ZА-Я]/g).reduce((a,b) => a+b);
+            r_span.style.color = each.color;
+            head_part.appendChild(r_span);
+            head_part.appendChild(document.createTextNode(" "));
+        });
 
-    head_part.appendChild(document.createTextNode("]"));
+        head_part.appendChild(document.createTextNode("]"));
+    }
 
     let time = new Date(data.timestamp*1000);
     let time_span = document.createElement("span");
@@ -166,18 +324,163 @@ socket.onmessage = e => {
         + ":" + (""+time.getMinutes()).padStart(2,'0');
     time_span.className = "time";
     head_part.appendChild(time_span);
-    
+
+    if (data.expires_at) {
+        let expires_span = document.createElement("span");
+        expires_span.className = "expires";
+        expires_span.title = "self-destructs at " + new Date(data.expires_at*1000).toLocaleString();
+        expires_span.textContent = "\u231B";
+        head_part.appendChild(document.createTextNode(" "));
+        head_part.appendChild(expires_span);
+    }
+
+    if (!data.deleted) {
+        let reply_a = document.createElement("a");
+        reply_a.href = "/?reply_to=" + data.id;
+        reply_a.className = "reply-link";
+        reply_a.textContent = "reply";
+        head_part.appendChild(document.createTextNode(" "));
+        head_part.appendChild(reply_a);
+    }
 
     let msg_part = document.createElement("div");
     msg_part.className = "message";
+    if (data.mentions.includes(user_id)) msg_part.classList.add("mentioned");
+    msg_part.dataset.id = data.id;
     msg_part.appendChild(head_part);
+
+    if (data.quote) {
+        let quote_part = document.createElement("div");
+        if (data.quote.deleted) {
+            quote_part.className = "quote tombstone";
+            quote_part.textContent = "message deleted";
+        } else {
+            quote_part.className = "quote";
+            quote_part.textContent = data.quote.sender_name + ": " + data.quote.text;
+        }
+        msg_part.appendChild(quote_part);
+    }
     msg_part.appendChild(text_part);
-    messages.appendChild(msg_part);
+
+    if (data.attachments.length > 0) {
+        let attachments_part = document.createElement("div");
+        attachments_part.className = "attachments";
+        data.attachments.forEach(each => {
+            let a = document.createElement("a");
+            a.href = "/files/" + each.id;
+            a.className = "attachment";
+            a.textContent = "F: " + each.name;
+            attachments_part.appendChild(a);
+        });
+        msg_part.appendChild(attachments_part);
+    }
+    if (!data.deleted) msg_part.appendChild(buildReactions(data));
+    return msg_part;
+};
+
+const renderMessage = data => messages.appendChild(buildMessage(data));
+
+// edited and deleted messages are shown again in place
+const replaceMessage = data => {
+    const old = document.querySelector(".message[data-id='" + data.id + "']");
+    if (!old) return;
+    if (old.contains(reaction_picker)) hidePicker();
+    old.replaceWith(buildMessage(data));
+};
+
+// messages deleted by a self-destruct timer or retention disappear
+const removeMessages = ids => {
+    for (const id of ids) {
+        const old = document.querySelector(".message[data-id='" + id + "']");
+        if (!old) continue;
+        if (old.contains(reaction_picker)) hidePicker();
+        old.remove();
+    }
+    unread = unread.filter(id => !ids.includes(id));
+};
+
+// =============================================================================
+// REACTIONS
+
+const reaction_picker = document.getElementById("reaction-picker");
+
+const userName = id => {
+    const label = document.querySelector("label[for=usr" + id + "] .name");
+    return label ? label.textContent : null;
+}
+
+const buildReactions = data => {
+    let part = document.createElement("div");
+    part.className = "reactions";
+    data.reactions.forEach(each => {
+        let button = document.createElement("button");
+        button.type = "button";
+        button.className = each.user_ids.includes(user_id) ? "reaction mine" : "reaction";
+        button.dataset.emoji = each.emoji;
+        button.title = each.user_ids.map(userName).filter(n => n).join(", ");
+        button.textContent = each.emoji + " " + each.user_ids.length;
+        part.appendChild(button);
+    });
+    let add = document.createElement("button");
+    add.type = "button";
+    add.className = "reaction-add";
+    add.title = "add reaction";
+    add.textContent = "+";
+    part.appendChild(add);
+    return part;
+}
+
+// the message is shown again with the reactions when the server announces it
+const react = (message_id, emoji, remove) => {
+    const url = "/messages/" + message_id + "/reactions";
+    const request = remove
+        ? fetch(url + "?emoji=" + encodeURIComponent(emoji), {method: "DELETE", credentials: "same-origin"})
+        : fetch(url, {
+            method: "POST",
+            credentials: "same-origin",
+            headers: {"Content-Type": "application/json"},
+            body: JSON.stringify({emoji: emoji})
+        });
+    request
+        .then(r => { if (!r.ok) r.text().then(t => showError(t, r.status)); })
+        .catch(e => showError(e, 0));
 }
 
+// the picker is moved under the message it is opened for
+const hidePicker = () => {
+    reaction_picker.hidden = true;
+    messages.after(reaction_picker);
+}
+
+messages.addEventListener("click", e => {
+    const message = e.target.closest(".message");
+    if (!message) return;
+    const message_id = parseInt(message.dataset.id);
+    if (e.target.classList.contains("reaction")) {
+        react(message_id, e.target.dataset.emoji, e.target.classList.contains("mine"));
+    } else if (e.target.classList.contains("reaction-add")) {
+        const open = !reaction_picker.hidden && message.contains(reaction_picker);
+        hidePicker();
+        if (open) return;
+        e.target.after(reaction_picker);
+        reaction_picker.hidden = false;
+    } else if (e.target.parentElement === reaction_picker) {
+        hidePicker();
+        react(message_id, e.target.dataset.emoji, false);
+    }
+});
+
 // =============================================================================
 // AJAX POST FORM
 
+// Enter sends, Shift+Enter starts a new line
+form_text.addEventListener("keydown", e => {
+    if (e.key == "Enter" && !e.shiftKey && !e.isComposing) {
+        e.preventDefault();
+        form.requestSubmit();
+    }
+});
+
 form.addEventListener("submit", async e => {
     e.preventDefault();
     const formData = new FormData(form);
@@ -190,6 +493,12 @@ form.addEventListener("submit", async e => {
         if (s.status == 200) {
             form_text.value = '';
             form_file.value = '';
+            // the answer is sent, next messages are not replies
+            const reply_bar = document.getElementById("reply-bar");
+            if (reply_bar) {
+                reply_bar.remove();
+                history.replaceState(null, "", "/");
+            }
 
         } else showError(s, "FORM_POST_THEN"); 
     }).catch(e => {
@@ -0,0 +1,728 @@
+//! Runs the server and talks to it over the websocket protocol described in
+//! `docs/websocket.md`.
+
+#![allow(clippy::needless_return)]
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use async_std::future::timeout;
+use async_std::task::block_on;
+use async_std::net::TcpStream as AsyncTcpStream;
+use async_tungstenite::tungstenite::Message;
+use async_tungstenite::{client_async, WebSocketStream};
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Password of every user of the test server
+const PASSWORD: &str = "correct horse";
+
+/// Server process with its own database, killed when dropped.
+/// Has users alice, bob and carol with ids 1, 2 and 3.
+struct Server {
+    process: Child,
+    address: String,
+    _dir: tempfile::TempDir
+}
+
+/// Runs the server binary with the settings of a test server in `dir`
+fn command(dir: &tempfile::TempDir) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_localpost-server"));
+    command.current_dir(env!("CARGO_MANIFEST_DIR"))
+        .arg("--config").arg(dir.path().join("localpost.toml"))
+        .arg("--database").arg(dir.path().join("messages.db"))
+        .arg("--files-dir").arg(dir.path().join("files"))
+        .env("LOCALPOST_SECRET", "integration-test-secret")
+        .env("LOCALPOST_ARGON2_MEMORY_KIB", "1024")
+        .env("LOCALPOST_ARGON2_ITERATIONS", "1")
+        .stderr(Stdio::null());
+    return command;
+}
+
+fn spawn(dir: &tempfile::TempDir, address: &str) -> Child {
+    return command(dir)
+        .arg("--listen").arg(address)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+}
+
+impl Server {
+    fn start() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let address = format!("127.0.0.1:{}", port);
+        let process = spawn(&dir, &address);
+        let server = Server { process, address, _dir: dir };
+        server.wait_until_listening();
+
+        let invite = server.create_invite(3);
+        for (username, name) in [("alice", "Alice"), ("bob", "Bob"), ("carol", "Carol")].iter() {
+            let (status, body) = server.request("POST", "/register", "",
+                json!({"invite": invite, "username": username, "name": name, "password": PASSWORD}));
+            assert_eq!(status, 201, "{}", body);
+        }
+        return server;
+    }
+
+    fn wait_until_listening(&self) {
+        let started = Instant::now();
+        while TcpStream::connect(&self.address).is_err() {
+            assert!(started.elapsed() < TIMEOUT, "Server did not start");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Stops the server and starts it again with the same database after `downtime`
+    fn restart_after(&mut self, downtime: Duration) {
+        self.process.kill().unwrap();
+        self.process.wait().unwrap();
+        std::thread::sleep(downtime);
+        self.process = spawn(&self._dir, &self.address);
+        self.wait_until_listening();
+    }
+
+    /// Creates an invite with the administrative command, returns its code
+    fn create_invite(&self, uses: u32) -> String {
+        let output = command(&self._dir)
+            .args(["invite", "create", "--uses", &uses.to_string()])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        return stdout.lines()
+            .find_map(|line| line.strip_prefix("Invite code: "))
+            .unwrap_or_else(|| panic!("No invite code in output: {}", stdout))
+            .to_owned();
+    }
+
+    /// Sends a request with a JSON body, returns status and body of the response
+    fn request(&self, method: &str, path: &str, token: &str, body: Value) -> (u16, String) {
+        let body = body.to_string();
+        let mut stream = TcpStream::connect(&self.address).unwrap();
+        write!(stream, "{} {} HTTP/1.1\r\nHost: {}\r\nAuthorization: Bearer {}\r\nConnection: close\r\n\
+            Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            method, path, self.address, token, body.len(), body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response.split(' ').nth(1).unwrap().parse().unwrap();
+        let body = response.split_once("\r\n\r\n").map(|(_, body)| body).unwrap_or("").to_owned();
+        return (status, body);
+    }
+
+    /// Logs in with the password and returns the token from the cookie
+    fn token(&self, username: &str) -> String {
+        let mut stream = TcpStream::connect(&self.address).unwrap();
+        write!(stream, "GET / HTTP/1.1\r\nHost: {}\r\nAuthorization: Basic {}\r\nConnection: close\r\n\r\n",
+            self.address, base64::encode(format!("{}:{}", username, PASSWORD))).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let response = String::from_utf8_lossy(&response);
+        let cookie = response.lines()
+            .find_map(|line| line.strip_prefix("set-cookie: token=").or_else(|| line.strip_prefix("Set-Cookie: token=")))
+            .unwrap_or_else(|| panic!("No token in response: {}", response));
+        return cookie.split(';').next().unwrap().to_owned();
+    }
+
+    async fn open(&self) -> Client {
+        let stream = AsyncTcpStream::from(TcpStream::connect(&self.address).unwrap());
+        let (socket, _) = client_async(format!("ws://{}/websocket", self.address), stream).await.unwrap();
+        return Client { socket };
+    }
+
+    /// Opens a websocket and completes the handshake
+    async fn connect(&self, username: &str) -> Client {
+        return self.reconnect(username, None).await;
+    }
+
+    /// Opens a websocket for a client that has messages up to `last_seen`
+    async fn reconnect(&self, username: &str, last_seen: Option<u64>) -> Client {
+        let mut client = self.open().await;
+        client.send(json!({"type": "hello", "version": 1, "token": self.token(username), "last_seen": last_seen})).await;
+        let hello = client.recv().await;
+        assert_eq!(hello["type"], "hello", "{}", hello);
+        return client;
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+struct Client {
+    socket: WebSocketStream<AsyncTcpStream>
+}
+
+impl Client {
+    async fn send(&mut self, frame: Value) {
+        self.socket.send(Message::Text(frame.to_string())).await.unwrap();
+    }
+
+    /// Next frame, `None` if the connection is closed
+    async fn try_recv(&mut self) -> Option<Value> {
+        loop {
+            let message = timeout(TIMEOUT, self.socket.next()).await.expect("No frame received in time");
+            match message {
+                Some(Ok(Message::Text(text))) => { return Some(serde_json::from_str(&text).unwrap()); }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => { return None; }
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+
+    async fn recv(&mut self) -> Value {
+        return self.try_recv().await.expect("Connection was closed");
+    }
+
+    /// Skips presence events of other users connecting
+    async fn recv_skipping_presence(&mut self) -> Value {
+        loop {
+            let frame = self.recv().await;
+            if frame["type"] != "presence" {
+                return frame;
+            }
+        }
+    }
+
+    /// Sends a message and returns its id
+    async fn send_message(&mut self, recipients: &[u32], text: &str) -> u64 {
+        self.send(json!({"type": "send_message", "id": "m", "recipients": recipients, "text": text})).await;
+        return self.recv_ack().await;
+    }
+
+    /// Skips events until an `ack` of `send_message`, returns id of the sent message
+    async fn recv_ack(&mut self) -> u64 {
+        loop {
+            let frame = self.recv().await;
+            if frame["type"] == "ack" {
+                return frame["message_id"].as_u64().unwrap();
+            }
+        }
+    }
+
+    /// Returns id of the next message event
+    async fn recv_message_id(&mut self) -> u64 {
+        let frame = self.recv_skipping_presence().await;
+        assert_eq!(frame["type"], "message", "{}", frame);
+        return frame["message"]["id"].as_u64().unwrap();
+    }
+
+    /// Skips events until a receipt
+    async fn recv_receipt(&mut self) -> Value {
+        loop {
+            let frame = self.recv().await;
+            if frame["type"] == "receipt" {
+                return frame;
+            }
+        }
+    }
+
+    /// Checks that nothing but presence events arrives for a moment
+    async fn assert_silent(&mut self) {
+        self.send(json!({"type": "ping", "id": "silence"})).await;
+        assert_eq!(self.recv_skipping_presence().await, json!({"type": "ack", "id": "silence"}));
+    }
+}
+
+
+#[test]
+fn rejects_invalid_handshakes() {
+    let server = Server::start();
+    block_on(async {
+        let mut client = server.open().await;
+        client.send(json!({"type": "hello", "version": 99, "token": server.token("alice")})).await;
+        let error = client.recv().await;
+        assert_eq!((error["type"].as_str(), error["code"].as_u64()), (Some("error"), Some(400)));
+        assert_eq!(client.try_recv().await, None);
+
+        let mut client = server.open().await;
+        client.send(json!({"type": "hello", "version": 1, "token": "forged.token"})).await;
+        assert_eq!(client.recv().await["code"], 401);
+        assert_eq!(client.try_recv().await, None);
+
+        let mut client = server.open().await;
+        client.socket.send(Message::Text(server.token("alice"))).await.unwrap();
+        assert_eq!(client.recv().await["code"], 400);
+        assert_eq!(client.try_recv().await, None);
+    });
+}
+
+#[test]
+fn sends_messages_and_acknowledges_them() {
+    let server = Server::start();
+    block_on(async {
+        let mut alice = server.connect("alice").await;
+        let mut bob = server.connect("bob").await;
+        let mut carol = server.connect("carol").await;
+
+        alice.send(json!({"type": "send_message", "id": "m1", "recipients": [2], "text": "Hi Bob"})).await;
+        let ack = alice.recv_skipping_presence().await;
+        assert_eq!(ack["type"], "ack");
+        assert_eq!(ack["id"], "m1");
+        let message_id = ack["message_id"].as_u64().unwrap();
+
+        let own = alice.recv_skipping_presence().await;
+        assert_eq!(own["type"], "message");
+        assert_eq!(own["message"]["id"].as_u64(), Some(message_id));
+        let received = bob.recv_skipping_presence().await;
+        assert_eq!(received, own);
+        assert_eq!(received["message"]["text"], "Hi Bob");
+        assert_eq!(received["message"]["sender_id"], 1);
+        carol.assert_silent().await;
+    });
+}
+
+#[test]
+fn reports_errors_and_keeps_connection() {
+    let server = Server::start();
+    block_on(async {
+        let mut alice = server.connect("alice").await;
+
+        alice.send(json!({"type": "send_message", "id": "m1", "recipients": [9], "text": "Hi"})).await;
+        assert_eq!(alice.recv_skipping_presence().await,
+            json!({"type": "error", "id": "m1", "code": 422, "reason": "Recipient 9 does not exist"}));
+
+        alice.send(json!({"type": "send_message", "id": "m2", "recipients": [], "text": "Hi"})).await;
+        assert_eq!(alice.recv_skipping_presence().await["code"], 422);
+
+        alice.send(json!({"type": "shout", "id": "x"})).await;
+        let error = alice.recv_skipping_presence().await;
+        assert_eq!((error["type"].as_str(), error["code"].as_u64()), (Some("error"), Some(400)));
+
+        alice.socket.send(Message::Binary(vec![1, 2, 3])).await.unwrap();
+        assert_eq!(alice.recv_skipping_presence().await["code"], 400);
+
+        alice.send(json!({"type": "hello", "version": 1, "token": server.token("alice")})).await;
+        assert_eq!(alice.recv_skipping_presence().await["code"], 400);
+
+        alice.send(json!({"type": "ping", "id": "p1"})).await;
+        assert_eq!(alice.recv_skipping_presence().await, json!({"type": "ack", "id": "p1"}));
+    });
+}
+
+#[test]
+fn relays_typing_to_recipients() {
+    let server = Server::start();
+    block_on(async {
+        let mut alice = server.connect("alice").await;
+        let mut bob = server.connect("bob").await;
+        let mut carol = server.connect("carol").await;
+
+        alice.send(json!({"type": "typing", "recipients": [1, 2]})).await;
+        assert_eq!(bob.recv_skipping_presence().await, json!({"type": "typing", "user_id": 1}));
+        alice.assert_silent().await;
+        carol.assert_silent().await;
+    });
+}
+
+#[test]
+fn announces_presence() {
+    let server = Server::start();
+    block_on(async {
+        let mut alice = server.connect("alice").await;
+        assert_eq!(alice.recv().await, json!({"type": "presence", "user_id": 1, "online": true}));
+
+        let bob_tab = server.connect("bob").await;
+        assert_eq!(alice.recv().await, json!({"type": "presence", "user_id": 2, "online": true}));
+        let bob_phone = server.connect("bob").await;
+        drop(bob_tab);
+        alice.assert_silent().await;
+
+        let (_, body) = server.request("GET", "/users", &server.token("carol"), Value::Null);
+        let users: Vec<Value> = serde_json::from_str::<Value>(&body).unwrap()["users"].as_array().unwrap().clone();
+        assert_eq!(users.iter().map(|u| (u["id"].as_u64().unwrap(), u["online"].as_bool().unwrap())).collect::<Vec<_>>(),
+            vec![(1, true), (2, true), (3, false)]);
+
+        // a reloaded page reconnects right away
+        drop(bob_phone);
+        let bob_reloaded = server.connect("bob").await;
+        alice.assert_silent().await;
+        drop(bob_reloaded);
+        assert_eq!(alice.recv().await, json!({"type": "presence", "user_id": 2, "online": false}));
+        let (_, body) = server.request("GET", "/users", &server.token("carol"), Value::Null);
+        assert!(serde_json::from_str::<Value>(&body).unwrap()["users"][1]["last_seen"].is_number());
+    });
+}
+
+#[test]
+fn replays_missed_messages_on_reconnect() {
+    let server = Server::start();
+    block_on(async {
+        let mut bob = server.connect("bob").await;
+        let mut alice = server.connect("alice").await;
+        let seen = bob.send_message(&[1], "seen").await;
+        assert_eq!(alice.recv_message_id().await, seen);
+        drop(alice);
+
+        let mut missed = Vec::new();
+        for text in ["missed 1", "missed 2", "missed 3"].iter() {
+            missed.push(bob.send_message(&[1], text).await);
+        }
+        let not_for_alice = bob.send_message(&[3], "not for alice").await;
+
+        let mut alice = server.reconnect("alice", Some(seen)).await;
+        for id in missed {
+            assert_eq!(alice.recv_message_id().await, id);
+        }
+        let live = bob.send_message(&[1], "live").await;
+        assert_eq!(alice.recv_message_id().await, live);
+        assert!(not_for_alice < live);
+
+        let mut fresh = server.connect("alice").await;
+        fresh.assert_silent().await;
+    });
+}
+
+#[test]
+fn hands_over_from_replay_to_live_messages_without_gaps_or_duplicates() {
+    // more than fits into a page, so messages are read several times while replaying
+    const MISSED: u64 = 250;
+    const LIVE: u64 = 40;
+    let server = Server::start();
+    block_on(async {
+        let mut bob = server.connect("bob").await;
+        // too long to fit into socket buffers, so the replay stalls until alice reads
+        let long_text = "x".repeat(50_000);
+        for _ in 0..MISSED {
+            bob.send_message(&[1], &long_text).await;
+        }
+
+        let mut alice = server.reconnect("alice", Some(0)).await;
+        for _ in 0..LIVE {
+            bob.send_message(&[1], "live").await;
+        }
+
+        let mut received = Vec::new();
+        while received.len() < (MISSED + LIVE) as usize {
+            received.push(alice.recv_message_id().await);
+        }
+        alice.assert_silent().await;
+        assert_eq!(received, (1..=MISSED + LIVE).collect::<Vec<_>>());
+    });
+}
+
+#[test]
+fn reports_delivery_and_reading_to_sender() {
+    let server = Server::start();
+    block_on(async {
+        let mut alice = server.connect("alice").await;
+        let mut bob = server.connect("bob").await;
+
+        let first = alice.send_message(&[2, 3], "first").await;
+        assert_eq!(bob.recv_message_id().await, first);
+        let receipt = alice.recv_receipt().await;
+        assert_eq!((receipt["message_id"].as_u64(), receipt["user_id"].as_u64()), (Some(first), Some(2)));
+        assert!(receipt["delivered_at"].is_i64());
+        assert!(receipt["read_at"].is_null());
+
+        bob.send(json!({"type": "mark_read", "id": "r1", "message_ids": [first]})).await;
+        assert_eq!(bob.recv_skipping_presence().await, json!({"type": "ack", "id": "r1"}));
+        let receipt = alice.recv_receipt().await;
+        assert_eq!(receipt["user_id"], 2);
+        assert!(receipt["read_at"].is_i64());
+
+        // carol is offline, fetching history delivers
+        let carol = server.token("carol");
+        let (status, _) = server.request("GET", "/messages", &carol, Value::Null);
+        assert_eq!(status, 200);
+        assert_eq!(alice.recv_receipt().await["user_id"], 3);
+        let (status, _) = server.request("POST", "/messages/read", &carol, json!({"message_ids": [first]}));
+        assert_eq!(status, 204);
+        let receipt = alice.recv_receipt().await;
+        assert_eq!(receipt["user_id"], 3);
+        assert!(receipt["read_at"].is_i64());
+
+        let (_, body) = server.request("GET", "/messages", &server.token("alice"), Value::Null);
+        let page: Value = serde_json::from_str(&body).unwrap();
+        let recipients = &page["messages"][0]["recipients"];
+        assert!(recipients[0]["read_at"].is_i64());
+        assert!(recipients[1]["read_at"].is_i64());
+        alice.assert_silent().await;
+    });
+}
+
+#[test]
+fn broadcasts_edits_and_deletions() {
+    let server = Server::start();
+    block_on(async {
+        let mut alice = server.connect("alice").await;
+        let mut bob = server.connect("bob").await;
+        let mut carol = server.connect("carol").await;
+        let id = alice.send_message(&[2], "helo").await;
+        assert_eq!(bob.recv_message_id().await, id);
+
+        let alice_token = server.token("alice");
+        let (status, _) = server.request("PATCH", &format!("/messages/{}", id), &server.token("bob"), json!({"text": "x"}));
+        assert_eq!(status, 403);
+        let (status, _) = server.request("PATCH", &format!("/messages/{}", id), &alice_token, json!({"text": "**hello**"}));
+        assert_eq!(status, 200);
+        let edited = bob.recv_skipping_presence().await;
+        assert_eq!(edited["type"], "message_edited");
+        assert_eq!(edited["message"]["text"], "**hello**");
+        assert_eq!(edited["message"]["html"], "<p><strong>hello</strong></p>");
+        assert!(edited["message"]["edited_at"].is_i64());
+
+        let (status, body) = server.request("DELETE", &format!("/messages/{}", id), &alice_token, Value::Null);
+        assert_eq!(status, 200, "{}", body);
+        let deleted = bob.recv_skipping_presence().await;
+        assert_eq!(deleted["type"], "message_deleted");
+        assert_eq!(deleted["message"]["deleted"], true);
+        assert_eq!(deleted["message"]["text"], "");
+        assert_eq!(deleted["message"]["html"], "");
+
+        let (status, _) = server.request("DELETE", &format!("/messages/{}", id), &alice_token, Value::Null);
+        assert_eq!(status, 410);
+        carol.assert_silent().await;
+    });
+}
+
+#[test]
+fn replies_quote_parent_and_form_threads() {
+    let server = Server::start();
+    block_on(async {
+        let mut alice = server.connect("alice").await;
+        let mut bob = server.connect("bob").await;
+        let parent = alice.send_message(&[2], "what time is it?").await;
+        let secret = alice.send_message(&[3], "for carol").await;
+        assert_eq!(bob.recv_message_id().await, parent);
+
+        bob.send(json!({"type": "send_message", "id": "r", "recipients": [3], "text": "x", "reply_to": secret})).await;
+        let error = bob.recv_skipping_presence().await;
+        assert_eq!(error, json!({"type": "error", "id": "r", "code": 422,
+            "reason": format!("Message {} does not exist", secret)}));
+
+        bob.send(json!({"type": "send_message", "id": "r", "recipients": [1], "text": "noon", "reply_to": parent})).await;
+        let reply = bob.recv_ack().await;
+        // alice still has the echoes of her own messages
+        let mut event = alice.recv_skipping_presence().await;
+        while event["message"]["id"] != reply {
+            event = alice.recv_skipping_presence().await;
+        }
+        assert_eq!(event["message"]["reply_to"], parent);
+        assert_eq!(event["message"]["quote"], json!({"sender_name": "Alice", "text": "what time is it?", "deleted": false}));
+
+        let (status, body) = server.request("GET", &format!("/messages/{}/thread", reply), &server.token("bob"), Value::Null);
+        assert_eq!(status, 200, "{}", body);
+        let thread: Value = serde_json::from_str(&body).unwrap();
+        let ids: Vec<&Value> = thread["messages"].as_array().unwrap().iter().map(|m| &m["id"]).collect();
+        assert_eq!(ids, vec![&json!(parent), &json!(reply)]);
+        let (status, _) = server.request("GET", &format!("/messages/{}/thread", parent), &server.token("carol"), Value::Null);
+        assert_eq!(status, 404);
+    });
+}
+
+#[test]
+fn sends_channel_messages_to_current_members() {
+    let server = Server::start();
+    block_on(async {
+        let (alice_token, bob_token) = (server.token("alice"), server.token("bob"));
+        let (status, body) = server.request("POST", "/channels", &alice_token, json!({"name": "general"}));
+        assert_eq!(status, 201, "{}", body);
+        let channel = serde_json::from_str::<Value>(&body).unwrap()["id"].as_u64().unwrap();
+        let (status, _) = server.request("POST", &format!("/channels/{}/join", channel), &bob_token, Value::Null);
+        assert_eq!(status, 204);
+
+        let mut alice = server.connect("alice").await;
+        let mut bob = server.connect("bob").await;
+        let mut carol = server.connect("carol").await;
+        alice.send(json!({"type": "send_message", "id": "c", "channel_id": channel, "text": "standup"})).await;
+        let id = alice.recv_ack().await;
+        let event = bob.recv_skipping_presence().await;
+        assert_eq!(event["message"]["id"], id);
+        assert_eq!(event["message"]["channel_name"], "general");
+        carol.assert_silent().await;
+
+        let (status, _) = server.request("GET", &format!("/messages?channel={}", channel), &server.token("carol"), Value::Null);
+        assert_eq!(status, 403);
+        let (_, body) = server.request("GET", &format!("/messages?channel={}", channel), &bob_token, Value::Null);
+        assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["messages"][0]["id"], id);
+        let (_, body) = server.request("GET", "/messages", &bob_token, Value::Null);
+        assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["messages"], json!([]));
+
+        let (status, _) = server.request("POST", &format!("/channels/{}/leave", channel), &bob_token, Value::Null);
+        assert_eq!(status, 204);
+        alice.send(json!({"type": "send_message", "id": "c", "channel_id": channel, "text": "bob left"})).await;
+        alice.recv_ack().await;
+        bob.assert_silent().await;
+        bob.send(json!({"type": "send_message", "id": "b", "channel_id": channel, "text": "wait"})).await;
+        assert_eq!(bob.recv_skipping_presence().await["code"], 403);
+    });
+}
+
+#[test]
+fn registers_with_invites() {
+    let server = Server::start();
+    let register = |invite: &str, username: &str, password: &str| {
+        return server.request("POST", "/register", "",
+            json!({"invite": invite, "username": username, "name": "Dave", "password": password})).0;
+    };
+    assert_eq!(register("nonsense", "dave", PASSWORD), 403);
+
+    let invite = server.create_invite(1);
+    assert_eq!(register(&invite, "ALICE", PASSWORD), 409);
+    assert_eq!(register(&invite, "dave", "short"), 422);
+    assert_eq!(register(&invite, "da ve", PASSWORD), 422);
+    let (status, body) = server.request("POST", "/register", "",
+        json!({"invite": invite, "username": "dave", "name": "Dave", "color": "#ff0000", "password": PASSWORD}));
+    assert_eq!(status, 201, "{}", body);
+    assert_eq!(serde_json::from_str::<Value>(&body).unwrap(), json!({"id": 4, "username": "dave"}));
+    assert_eq!(register(&invite, "erin", PASSWORD), 403);
+
+    let (status, body) = server.request("GET", "/messages", &server.token("dave"), Value::Null);
+    assert_eq!(status, 200, "{}", body);
+}
+
+#[test]
+fn updates_profile_and_tells_everyone() {
+    let server = Server::start();
+    block_on(async {
+        let token = server.token("alice");
+        assert_eq!(server.request("PATCH", "/me", &token, json!({"color": "yellow"})).0, 422);
+        assert_eq!(server.request("PATCH", "/me", &token, json!({"name": "alice"})).0, 422);
+
+        let mut bob = server.connect("bob").await;
+        let (status, body) = server.request("PATCH", "/me", &token, json!({"name": "Alice Liddell", "color": "#1e90ff"}));
+        assert_eq!(status, 200, "{}", body);
+        let user = json!({"id": 1, "name": "Alice Liddell", "color": "#1e90ff"});
+        assert_eq!(bob.recv_skipping_presence().await, json!({"type": "user_updated", "user": user}));
+        let (_, body) = server.request("GET", "/me", &token, Value::Null);
+        assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["username"], "alice");
+
+        assert_eq!(server.request("PATCH", "/me", &token, json!({"password": "new password"})).0, 403);
+        assert_eq!(server.request("PATCH", "/me", &token,
+            json!({"password": "new password", "current_password": "wrong"})).0, 403);
+        assert_eq!(server.request("PATCH", "/me", &token,
+            json!({"password": "new password", "current_password": PASSWORD})).0, 200);
+        assert_eq!(server.request("GET", "/me", &token, Value::Null).0, 401);
+    });
+}
+
+#[test]
+fn notifies_mentioned_users() {
+    let server = Server::start();
+    block_on(async {
+        let mut alice = server.connect("alice").await;
+        let mut bob = server.connect("bob").await;
+        let mut carol = server.connect("carol").await;
+
+        let id = alice.send_message(&[2], "@carol have a look").await;
+        let event = carol.recv_skipping_presence().await;
+        assert_eq!(event["message"]["id"], id);
+        assert_eq!(event["message"]["mentions"], json!([3]));
+        assert_eq!(carol.recv_skipping_presence().await, json!({"type": "mention", "message_id": id, "sender_id": 1}));
+        assert_eq!(bob.recv_message_id().await, id);
+        bob.assert_silent().await;
+
+        let (status, _) = server.request("POST", "/messages", &server.token("bob"), json!({"recipients": [], "text": "@nobody"}));
+        assert_eq!(status, 422);
+    });
+}
+
+#[test]
+fn broadcasts_reactions() {
+    let server = Server::start();
+    block_on(async {
+        let mut alice = server.connect("alice").await;
+        let mut bob = server.connect("bob").await;
+        let id = alice.send_message(&[2], "lunch?").await;
+        assert_eq!(alice.recv_message_id().await, id);
+        assert_eq!(bob.recv_message_id().await, id);
+        assert_eq!(alice.recv_receipt().await["user_id"], 2);
+
+        let path = format!("/messages/{}/reactions", id);
+        let bob_token = server.token("bob");
+        assert_eq!(server.request("POST", &path, &bob_token, json!({"emoji": "yes"})).0, 422);
+        assert_eq!(server.request("POST", &path, &server.token("carol"), json!({"emoji": "👍"})).0, 404);
+        let (status, body) = server.request("POST", &path, &bob_token, json!({"emoji": "👍"}));
+        assert_eq!(status, 200, "{}", body);
+        let event = alice.recv_skipping_presence().await;
+        assert_eq!(event["type"], "message_reacted");
+        assert_eq!(event["message"]["reactions"], json!([{"emoji": "👍", "user_ids": [2]}]));
+        assert_eq!(bob.recv_skipping_presence().await["type"], "message_reacted");
+
+        let (status, _) = server.request("DELETE", &format!("{}?emoji=%F0%9F%91%8D", path), &bob_token, Value::Null);
+        assert_eq!(status, 200);
+        assert_eq!(alice.recv_skipping_presence().await["message"]["reactions"], json!([]));
+    });
+}
+
+#[test]
+fn sends_scheduled_messages_when_due_even_after_restart() {
+    let mut server = Server::start();
+    let (alice_token, bob_token) = (server.token("alice"), server.token("bob"));
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    let schedule = |server: &Server, text: &str, send_at: u64| {
+        let (status, body) = server.request("POST", "/messages", &alice_token,
+            json!({"recipients": [2], "text": text, "send_at": send_at}));
+        assert_eq!(status, 201, "{}", body);
+        let message = serde_json::from_str::<Value>(&body).unwrap();
+        assert_eq!(message["send_at"], send_at);
+        return message["id"].as_u64().unwrap();
+    };
+    let messages = |server: &Server, path: &str, token: &str| {
+        let (status, body) = server.request("GET", path, token, Value::Null);
+        assert_eq!(status, 200, "{}", body);
+        return serde_json::from_str::<Value>(&body).unwrap()["messages"].as_array().unwrap().clone();
+    };
+
+    let later = schedule(&server, "later", now + 3600);
+    let cancelled = schedule(&server, "cancelled", now + 3600);
+    assert!(messages(&server, "/messages", &bob_token).is_empty());
+    assert_eq!(messages(&server, "/messages/scheduled", &alice_token).len(), 2);
+    let cancel = format!("/messages/scheduled/{}", cancelled);
+    assert_eq!(server.request("DELETE", &cancel, &bob_token, Value::Null).0, 404);
+    assert_eq!(server.request("DELETE", &cancel, &alice_token, Value::Null).0, 204);
+    assert_eq!(server.request("DELETE", &cancel, &alice_token, Value::Null).0, 404);
+
+    block_on(async {
+        let mut bob = server.connect("bob").await;
+        let soon = schedule(&server, "soon", now + 1);
+        let event = bob.recv_skipping_presence().await;
+        assert_eq!(event["message"]["text"], "soon");
+        assert!(event["message"]["id"].as_u64().unwrap() > soon);
+    });
+
+    // due while the server is down
+    let (status, _) = server.request("PATCH", &format!("/messages/scheduled/{}", later), &alice_token,
+        json!({"send_at": now + 2}));
+    assert_eq!(status, 200);
+    server.restart_after(Duration::from_secs(2));
+    let started = Instant::now();
+    while !messages(&server, "/messages/scheduled", &alice_token).is_empty() {
+        assert!(started.elapsed() < TIMEOUT, "Overdue message was not sent");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let received = messages(&server, "/messages", &bob_token);
+    let texts: Vec<&str> = received.iter().map(|m| m["text"].as_str().unwrap()).collect();
+    assert_eq!(texts, vec!["soon", "later"]);
+    assert_eq!(received[1]["send_at"], Value::Null);
+}
+
+#[test]
+fn purges_messages_when_their_timer_runs_out() {
+    let server = Server::start();
+    let alice_token = server.token("alice");
+    block_on(async {
+        let mut bob = server.connect("bob").await;
+        let (status, _) = server.request("POST", "/messages", &alice_token,
+            json!({"recipients": [2], "text": "burn after reading", "destruct_after": 0}));
+        assert_eq!(status, 422);
+        let (status, body) = server.request("POST", "/messages", &alice_token,
+            json!({"recipients": [2], "text": "burn after reading", "destruct_after": 1}));
+        assert_eq!(status, 201, "{}", body);
+        let id = serde_json::from_str::<Value>(&body).unwrap()["id"].as_u64().unwrap();
+
+        let event = bob.recv_skipping_presence().await;
+        assert_eq!(event["message"]["id"], id);
+        assert!(event["message"]["expires_at"].is_u64());
+        assert_eq!(bob.recv_skipping_presence().await, json!({"type": "messages_expired", "message_ids": [id]}));
+        let (status, _) = server.request("GET", &format!("/messages/{}/thread", id), &server.token("bob"), Value::Null);
+        assert_eq!(status, 404);
+    });
+}